@@ -1,21 +1,24 @@
-use crate::calibration_data::Coefficients;
+use crate::calibration_data::{CalibrationData, CalibrationTrial, Coefficients, TrialSampling};
 use crate::errors::AppError;
+use crate::hardware::HardwareManager;
+use crate::history::CoefficientSource;
 use crate::state::AppData;
+use serde::Serialize;
 use std::sync::Mutex;
 use std::time::Duration;
 use tauri::State;
 
 pub struct Backend {}
 impl Backend {
-    pub async fn get_coefficients(state: State<'_, Mutex<AppData>>) -> Result<String, AppError> {
-        let scale = {
-            let mut state = state.lock().unwrap();
-            state.take_scale()?
-        };
+    pub async fn get_coefficients(
+        state: State<'_, Mutex<AppData>>,
+        hardware: State<'_, HardwareManager>,
+    ) -> Result<String, AppError> {
+        let phidget_id = { state.lock().unwrap().get_phidget_id()? };
         let client = reqwest::Client::new();
         let url = format!(
             "https://us-west1-calibration-backend.cloudfunctions.net/test-function/{}",
-            scale.get_phidget_id()
+            phidget_id
         );
         let response = client
             .get(url)
@@ -26,13 +29,13 @@ impl Backend {
             .text()
             .await
             .map_err(AppError::Reqwest)?;
-        {
-            let mut state = state.lock().unwrap();
-            state.return_scale(scale)?;
-            state.update_coefficients(
-                serde_json::from_str::<Coefficients>(&response).map_err(AppError::Serde)?,
-            )?;
-        }
+        let coefficients =
+            serde_json::from_str::<Coefficients>(&response).map_err(AppError::Serde)?;
+        hardware.apply_coefficients(&coefficients).await?;
+        state
+            .lock()
+            .unwrap()
+            .update_coefficients(coefficients, CoefficientSource::Cloud)?;
 
         Ok(response)
     }
@@ -42,11 +45,12 @@ impl Backend {
                 .lock()
                 .unwrap()
                 .get_calibration_data()
-                .ok_or(AppError::NoScale)?
+                .ok_or(AppError::NoSession)?
         };
         let client = reqwest::Client::new();
         let url = "https://us-west1-calibration-backend.cloudfunctions.net/test-function";
-        let payload = serde_json::to_string(&calibration_data).map_err(AppError::Serde)?;
+        let payload = serde_json::to_string(&CalibrationPayload::new(&calibration_data))
+            .map_err(AppError::Serde)?;
         client
             .post(url)
             .header(reqwest::header::CONTENT_TYPE, "application/json")
@@ -60,3 +64,70 @@ impl Backend {
             .map_err(AppError::Reqwest)
     }
 }
+
+// Notes stay local; the per-cell sampling statistics go along so the backend can weight
+// noisy trials less, and are left out for trials typed in by hand
+#[derive(Serialize)]
+struct CalibrationPayload<'a> {
+    trials: Vec<TrialPayload<'a>>,
+    phidget_id: i32,
+}
+impl<'a> CalibrationPayload<'a> {
+    fn new(calibration_data: &'a CalibrationData) -> Self {
+        Self {
+            trials: calibration_data
+                .get_trials()
+                .iter()
+                .map(TrialPayload::new)
+                .collect(),
+            phidget_id: calibration_data.get_phidget_id(),
+        }
+    }
+}
+#[derive(Serialize)]
+struct TrialPayload<'a> {
+    readings: &'a [f64],
+    weight: f64,
+    timestamp: Duration,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sampling: Option<&'a TrialSampling>,
+}
+impl<'a> TrialPayload<'a> {
+    fn new(trial: &'a CalibrationTrial) -> Self {
+        Self {
+            readings: trial.get_readings(),
+            weight: trial.get_weight(),
+            timestamp: trial.get_timestamp(),
+            sampling: trial.get_sampling(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use node_diagnostics::data::Data;
+
+    #[test]
+    fn sends_sampling_statistics_with_sampled_trials() {
+        let data = [1., 2., 3., 4.].map(|reading: f64| {
+            let mut data = Data::new(3);
+            for (sample, noise) in [-0.1, 0., 0.1].into_iter().enumerate() {
+                data.push(Duration::from_millis(sample as u64 * 100), reading + noise);
+            }
+            data
+        });
+        let sampling = TrialSampling::from_data(&data, Duration::from_millis(100)).unwrap();
+        let mut calibration_data = CalibrationData::new(716709);
+        calibration_data.add_trial(CalibrationTrial::from_sampling(sampling, 10.));
+        calibration_data.add_trial(CalibrationTrial::from_array([1., 2., 3., 4.], 10.));
+        let payload = serde_json::to_value(CalibrationPayload::new(&calibration_data)).unwrap();
+
+        let sampled = &payload["trials"][0]["sampling"];
+        assert_eq!(sampled["samples"], 3);
+        assert_eq!(sampled["cells"].as_array().unwrap().len(), 4);
+        assert!(sampled["cells"][2]["std_dev"].as_f64().unwrap() > 0.);
+        assert!(payload["trials"][1].get("sampling").is_none());
+        assert!(payload["trials"][0].get("note").is_none());
+    }
+}
@@ -0,0 +1,238 @@
+use crate::errors::AppError;
+use crate::scale::Scale;
+use node_diagnostics::data::Data;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+const DEFAULT_LENGTH: Duration = Duration::from_secs(60);
+// A failing scale is retried at this period, doubling up to the maximum
+const RETRY_PERIOD: Duration = Duration::from_millis(500);
+const MAX_RETRY_PERIOD: Duration = Duration::from_secs(8);
+
+#[derive(Debug, Clone, Copy)]
+struct BufferedReading {
+    time: Duration,
+    cells: [f64; 4],
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+pub enum BufferWindow {
+    // The most recent stretch of readings
+    Last(Duration),
+    // Everything after a timestamp previously returned by the buffer
+    Since(Duration),
+    All,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BufferStatus {
+    length: Duration,
+    readings: usize,
+    oldest: Option<Duration>,
+    newest: Option<Duration>,
+}
+
+// The last `length` of raw per-cell readings, timestamped from when the buffer was made
+pub struct ReadingBuffer {
+    readings: VecDeque<BufferedReading>,
+    length: Duration,
+    epoch: Instant,
+}
+impl Default for ReadingBuffer {
+    fn default() -> Self {
+        Self {
+            readings: VecDeque::new(),
+            length: DEFAULT_LENGTH,
+            epoch: Instant::now(),
+        }
+    }
+}
+impl ReadingBuffer {
+    pub fn push(&mut self, cells: [f64; 4]) {
+        self.insert(self.epoch.elapsed(), cells);
+    }
+    fn insert(&mut self, time: Duration, cells: [f64; 4]) {
+        self.readings.push_back(BufferedReading { time, cells });
+        self.prune(time);
+    }
+    pub fn clear(&mut self) {
+        self.readings.clear();
+    }
+    pub fn set_length(&mut self, length: Duration) {
+        self.length = length;
+        self.prune(self.epoch.elapsed());
+    }
+    fn prune(&mut self, now: Duration) {
+        let oldest = now.saturating_sub(self.length);
+        while self
+            .readings
+            .front()
+            .is_some_and(|reading| reading.time < oldest)
+        {
+            self.readings.pop_front();
+        }
+    }
+    pub fn get_status(&self) -> BufferStatus {
+        BufferStatus {
+            length: self.length,
+            readings: self.readings.len(),
+            oldest: self.readings.front().map(|reading| reading.time),
+            newest: self.readings.back().map(|reading| reading.time),
+        }
+    }
+    // Keeps every `decimation`th reading of the window
+    pub fn query(
+        &self,
+        window: BufferWindow,
+        decimation: Option<usize>,
+    ) -> Result<[Data; 4], AppError> {
+        let decimation = decimation.unwrap_or(1);
+        if decimation == 0 {
+            return Err(AppError::Other("Decimation must be at least 1!".into()));
+        }
+        let since = match window {
+            BufferWindow::Last(duration) => self.epoch.elapsed().saturating_sub(duration),
+            BufferWindow::Since(time) => time,
+            BufferWindow::All => Duration::ZERO,
+        };
+        let start = self
+            .readings
+            .partition_point(|reading| reading.time < since);
+        let mut data: [Data; 4] =
+            std::array::from_fn(|_| Data::new((self.readings.len() - start) / decimation + 1));
+        for reading in self.readings.range(start..).step_by(decimation) {
+            for (datum, cell) in data.iter_mut().zip(reading.cells) {
+                datum.push(reading.time, cell);
+            }
+        }
+        Ok(data)
+    }
+}
+
+// Reads the scale at its data interval between jobs. A failing scale is retried with a
+// growing delay, so acquisition resumes once the scale is back.
+pub struct Acquisition {
+    period: Duration,
+    next: tokio::time::Instant,
+    failures: u32,
+}
+impl Acquisition {
+    pub fn new(period: Duration) -> Self {
+        Self {
+            period,
+            next: tokio::time::Instant::now(),
+            failures: 0,
+        }
+    }
+    pub fn set_period(&mut self, period: Duration) {
+        self.period = period;
+    }
+    pub fn get_deadline(&self) -> tokio::time::Instant {
+        self.next
+    }
+    pub fn get_period(&self) -> Duration {
+        self.period
+    }
+    pub fn sample(&mut self, scale: &mut dyn Scale) -> Result<[f64; 4], AppError> {
+        let now = tokio::time::Instant::now();
+        // Readings missed while a job held the hardware are left as a gap
+        self.next = (self.next + self.period).max(now);
+        let result = scale.get_raw_readings();
+        match result.as_ref() {
+            Ok(_) if self.failures > 0 => {
+                log::info!(
+                    "Background acquisition resumed after {} failed reads",
+                    self.failures
+                );
+                self.failures = 0;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                if self.failures == 0 {
+                    log::warn!("Background acquisition failed, retrying: {e}");
+                }
+                let retry = RETRY_PERIOD.saturating_mul(1 << self.failures.min(8));
+                self.next = now + retry.min(MAX_RETRY_PERIOD);
+                self.failures += 1;
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scale::testing::TestScale;
+
+    fn secs(time: u64) -> Duration {
+        Duration::from_secs(time)
+    }
+
+    fn buffer(times: &[u64]) -> ReadingBuffer {
+        let mut buffer = ReadingBuffer::default();
+        for (index, time) in times.iter().enumerate() {
+            buffer.insert(secs(*time), [index as f64; 4]);
+        }
+        buffer
+    }
+
+    #[test]
+    fn query_since_includes_the_timestamp() {
+        let data = buffer(&[1, 2, 3, 4])
+            .query(BufferWindow::Since(secs(2)), None)
+            .unwrap();
+        assert_eq!(data[0].times, [secs(2), secs(3), secs(4)]);
+        assert_eq!(data[3].readings, [1., 2., 3.]);
+    }
+
+    #[test]
+    fn query_decimates() {
+        let buffer = buffer(&[1, 2, 3, 4, 5]);
+        let data = buffer.query(BufferWindow::All, Some(2)).unwrap();
+        assert_eq!(data[0].times, [secs(1), secs(3), secs(5)]);
+        assert!(matches!(
+            buffer.query(BufferWindow::All, Some(0)),
+            Err(AppError::Other(_))
+        ));
+    }
+
+    #[test]
+    fn old_readings_are_pruned() {
+        let buffer = buffer(&[0, 30, 60, 90]);
+        let data = buffer.query(BufferWindow::All, None).unwrap();
+        assert_eq!(data[0].times, [secs(30), secs(60), secs(90)]);
+        let status = buffer.get_status();
+        assert_eq!(status.oldest, Some(secs(30)));
+        assert_eq!(status.newest, Some(secs(90)));
+    }
+
+    #[test]
+    fn shorter_length_prunes_immediately() {
+        let mut buffer = ReadingBuffer::default();
+        buffer.push([1.; 4]);
+        std::thread::sleep(Duration::from_millis(5));
+        buffer.set_length(Duration::ZERO);
+        assert_eq!(buffer.get_status().readings, 0);
+    }
+
+    #[test]
+    fn acquisition_retries_a_failing_scale() {
+        let mut scale = TestScale::new([1.; 4]).failing_after(0);
+        let mut acquisition = Acquisition::new(Duration::from_millis(10));
+        assert!(acquisition.sample(&mut scale).is_err());
+        let first_retry = acquisition.get_deadline();
+        assert!(first_retry >= tokio::time::Instant::now() + RETRY_PERIOD / 2);
+        // The delay grows while the scale keeps failing
+        assert!(acquisition.sample(&mut scale).is_err());
+        assert!(acquisition.get_deadline() > first_retry);
+
+        scale.reconnect();
+        assert_eq!(acquisition.sample(&mut scale).unwrap(), [1.; 4]);
+        // A later failure starts over from the shortest delay
+        let mut scale = TestScale::new([1.; 4]).failing_after(0);
+        assert!(acquisition.sample(&mut scale).is_err());
+        assert!(acquisition.get_deadline() <= tokio::time::Instant::now() + RETRY_PERIOD);
+    }
+}
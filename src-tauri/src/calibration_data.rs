@@ -1,14 +1,23 @@
+use crate::data::LoadCellDataRequest;
 use crate::errors::AppError;
-use crate::state::AppData;
-use serde::Deserialize;
-use std::sync::Mutex;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::hardware::{CancelToken, HardwareManager};
+use crate::progress::ProgressReporter;
+use crate::scale::Scale;
+use crate::stability::StabilityCriteria;
+use crate::statistics;
+use node_diagnostics::data::Data;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-#[derive(Debug, Clone, serde::Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct CalibrationTrial {
     readings: Vec<f64>,
     weight: f64,
     timestamp: Duration,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sampling: Option<TrialSampling>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    note: Option<String>,
 }
 impl CalibrationTrial {
     pub fn from_array(data: [f64; 4], weight: f64) -> Self {
@@ -16,32 +25,179 @@ impl CalibrationTrial {
             readings: data.to_vec(),
             weight,
             timestamp: SystemTime::now().duration_since(UNIX_EPOCH).unwrap(),
+            sampling: None,
+            note: None,
         }
     }
+    pub fn from_sampling(sampling: TrialSampling, weight: f64) -> Self {
+        let mut trial = Self::from_array(sampling.medians(), weight);
+        trial.sampling.replace(sampling);
+        trial
+    }
     pub fn new(
-        state: tauri::State<'_, Mutex<AppData>>,
+        scale: &mut dyn Scale,
+        stability: Option<StabilityCriteria>,
         samples: usize,
         weight: f64,
         sample_period: Duration,
+        cancel: &CancelToken,
+        progress: &mut ProgressReporter,
     ) -> Result<Self, AppError> {
         if samples == 0 {
             return Err(AppError::ZeroSamples);
         }
-        let scale = {
-            let mut state = state.lock().unwrap();
-            state.take_scale()?
-        };
-        // TODO: include sample rate!
-        let readings = scale
-            .get_load_cell_medians(samples, sample_period)
-            .map_err(AppError::Libra)?;
-        {
-            state.lock().unwrap().return_scale(scale)?;
+        let start = Instant::now();
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            progress.set_attempt(attempt);
+            let sampling = TrialSampling::new(scale, samples, sample_period, cancel, progress)?;
+            let Some(stability) = stability.as_ref() else {
+                return Ok(Self::from_sampling(sampling, weight));
+            };
+            match stability.check(&sampling) {
+                Ok(()) => return Ok(Self::from_sampling(sampling, weight)),
+                Err(e) if stability.should_retry(start.elapsed()) => {
+                    log::warn!("{e}, retrying trial");
+                }
+                Err(e) => return Err(e),
+            }
         }
-        Ok(Self::from_array(readings, weight))
     }
+    pub async fn capture(
+        hardware: &HardwareManager,
+        stability: Option<StabilityCriteria>,
+        samples: usize,
+        weight: f64,
+        sample_period: Duration,
+    ) -> Result<Self, AppError> {
+        hardware
+            .run_sync("calibration_trial", move |hardware| {
+                let cancel = hardware.get_cancel_token();
+                let mut progress = hardware.get_progress_reporter();
+                Self::new(
+                    hardware.get_scale()?,
+                    stability,
+                    samples,
+                    weight,
+                    sample_period,
+                    &cancel,
+                    &mut progress,
+                )
+            })
+            .await
+    }
+    pub fn get_readings(&self) -> &[f64] {
+        &self.readings
+    }
+    pub fn get_weight(&self) -> f64 {
+        self.weight
+    }
+    pub fn get_timestamp(&self) -> Duration {
+        self.timestamp
+    }
+    pub fn get_sampling(&self) -> Option<&TrialSampling> {
+        self.sampling.as_ref()
+    }
+    pub fn get_note(&self) -> Option<&str> {
+        self.note.as_deref()
+    }
+    pub fn set_note(&mut self, note: Option<String>) {
+        self.note = note;
+    }
+}
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CellStatistics {
+    median: f64,
+    mean: f64,
+    std_dev: f64,
+    min: f64,
+    max: f64,
+    mad: f64,
+    #[serde(default)]
+    drift: f64,
+}
+impl CellStatistics {
+    // None without any readings
+    pub fn from_data(data: &Data) -> Option<Self> {
+        let readings = &data.readings;
+        let times: Vec<f64> = data.times.iter().map(Duration::as_secs_f64).collect();
+        Some(Self {
+            median: statistics::median(readings)?,
+            mean: statistics::mean(readings)?,
+            std_dev: statistics::std_dev(readings),
+            min: statistics::min(readings)?,
+            max: statistics::max(readings)?,
+            mad: statistics::median_absolute_deviation(readings)?,
+            drift: statistics::slope(&times, readings),
+        })
+    }
+    pub fn get_median(&self) -> f64 {
+        self.median
+    }
+    pub fn get_std_dev(&self) -> f64 {
+        self.std_dev
+    }
+    pub fn get_drift(&self) -> f64 {
+        self.drift
+    }
+}
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TrialSampling {
+    sample_period: Duration,
+    samples: usize,
+    cells: [CellStatistics; 4],
 }
-#[derive(Debug, Clone, serde::Serialize)]
+impl TrialSampling {
+    pub fn new(
+        scale: &mut dyn Scale,
+        samples: usize,
+        sample_period: Duration,
+        cancel: &CancelToken,
+        progress: &mut ProgressReporter,
+    ) -> Result<Self, AppError> {
+        let data =
+            LoadCellDataRequest::new(samples, sample_period).conduct(scale, cancel, progress)?;
+        // A partial sampling would skew the trial, so it is thrown away
+        if cancel.is_cancelled() {
+            return Err(AppError::Cancelled);
+        }
+        Self::from_data(&data, sample_period)
+    }
+    pub fn from_data(data: &[Data; 4], sample_period: Duration) -> Result<Self, AppError> {
+        let samples = data[0].readings.len();
+        let cells = data.each_ref().map(CellStatistics::from_data);
+        if cells.iter().any(Option::is_none) {
+            return Err(AppError::ZeroSamples);
+        }
+        Ok(Self {
+            sample_period,
+            samples,
+            cells: cells.map(Option::unwrap),
+        })
+    }
+    pub fn get_cells(&self) -> &[CellStatistics; 4] {
+        &self.cells
+    }
+    pub fn medians(&self) -> [f64; 4] {
+        std::array::from_fn(|cell| self.cells[cell].get_median())
+    }
+    // Approximate variance of the summed medians, used to down-weight noisy trials
+    pub fn noise_variance(&self) -> f64 {
+        self.cells
+            .iter()
+            .map(|cell| cell.get_std_dev().powi(2))
+            .sum::<f64>()
+            / self.samples as f64
+    }
+}
+#[derive(Debug, Clone, Serialize)]
+pub struct TrialEntry {
+    index: usize,
+    #[serde(flatten)]
+    trial: CalibrationTrial,
+}
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct CalibrationData {
     trials: Vec<CalibrationTrial>,
     phidget_id: i32,
@@ -56,14 +212,146 @@ impl CalibrationData {
     pub fn add_trial(&mut self, trial: CalibrationTrial) {
         self.trials.push(trial);
     }
+    pub fn get_phidget_id(&self) -> i32 {
+        self.phidget_id
+    }
+    pub fn get_trials(&self) -> &[CalibrationTrial] {
+        &self.trials
+    }
+    pub fn get_trial_entries(&self) -> Vec<TrialEntry> {
+        self.trials
+            .iter()
+            .cloned()
+            .enumerate()
+            .map(|(index, trial)| TrialEntry { index, trial })
+            .collect()
+    }
+    pub fn find_trial(&self, timestamp: Duration) -> Option<usize> {
+        self.trials
+            .iter()
+            .position(|trial| trial.get_timestamp() == timestamp)
+    }
+    pub fn get_trial(&self, index: usize) -> Result<&CalibrationTrial, AppError> {
+        self.check_index(index)?;
+        Ok(&self.trials[index])
+    }
+    pub fn remove_trial(&mut self, index: usize) -> Result<CalibrationTrial, AppError> {
+        self.check_index(index)?;
+        Ok(self.trials.remove(index))
+    }
+    pub fn replace_trial(
+        &mut self,
+        index: usize,
+        trial: CalibrationTrial,
+    ) -> Result<CalibrationTrial, AppError> {
+        self.check_index(index)?;
+        Ok(std::mem::replace(&mut self.trials[index], trial))
+    }
+    pub fn get_mut_trial(&mut self, index: usize) -> Result<&mut CalibrationTrial, AppError> {
+        self.check_index(index)?;
+        Ok(&mut self.trials[index])
+    }
+    // Trials read back from a session file are not guaranteed one reading per load cell
+    pub fn check_readings(&self) -> Result<(), AppError> {
+        match self
+            .trials
+            .iter()
+            .position(|trial| trial.get_readings().len() != 4)
+        {
+            Some(index) => Err(AppError::TrialReadings {
+                index,
+                readings: self.trials[index].get_readings().len(),
+            }),
+            None => Ok(()),
+        }
+    }
+    fn check_index(&self, index: usize) -> Result<(), AppError> {
+        if index < self.trials.len() {
+            Ok(())
+        } else {
+            Err(AppError::TrialIndex {
+                index,
+                trials: self.trials.len(),
+            })
+        }
+    }
 }
 
-#[derive(Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Coefficients {
     coefficients: [f64; 4],
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    offset: Option<f64>,
 }
 impl Coefficients {
+    pub fn new(coefficients: [f64; 4], offset: Option<f64>) -> Self {
+        Self {
+            coefficients,
+            offset,
+        }
+    }
     pub fn get_coefficients(&self) -> [f64; 4] {
         self.coefficients
     }
+    pub fn get_offset(&self) -> Option<f64> {
+        self.offset
+    }
+    // Weight the scale reports with these coefficients applied, which never includes the offset
+    pub fn predict(&self, readings: &[f64]) -> f64 {
+        self.coefficients
+            .iter()
+            .zip(readings)
+            .map(|(coefficient, reading)| coefficient * reading)
+            .sum::<f64>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scale::testing::TestScale;
+
+    fn trial(scale: &mut TestScale, samples: usize) -> Result<CalibrationTrial, AppError> {
+        CalibrationTrial::new(
+            scale,
+            None,
+            samples,
+            100.,
+            Duration::from_millis(1),
+            &CancelToken::default(),
+            &mut ProgressReporter::silent(),
+        )
+    }
+
+    #[test]
+    fn trial_needs_samples() {
+        let mut scale = TestScale::new([1.; 4]);
+        assert!(matches!(trial(&mut scale, 0), Err(AppError::ZeroSamples)));
+    }
+
+    #[test]
+    fn trial_returns_scale_errors() {
+        let mut scale = TestScale::new([1.; 4]).failing_after(5);
+        assert!(matches!(trial(&mut scale, 10), Err(AppError::Other(_))));
+        scale.reconnect();
+        let trial = trial(&mut scale, 10).unwrap();
+        assert_eq!(trial.get_readings(), &[1.; 4]);
+    }
+
+    #[test]
+    fn cancelled_trial_is_discarded() {
+        let mut scale = TestScale::new([1.; 4]);
+        let cancel = CancelToken::default();
+        cancel.cancel();
+        let result = CalibrationTrial::new(
+            &mut scale,
+            None,
+            10,
+            100.,
+            Duration::from_millis(1),
+            &cancel,
+            &mut ProgressReporter::silent(),
+        );
+        assert!(matches!(result, Err(AppError::Cancelled)));
+    }
 }
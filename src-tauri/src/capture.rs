@@ -0,0 +1,137 @@
+use crate::errors::AppError;
+use serde::Serialize;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tauri::{AppHandle, Manager};
+
+// Raw captures ride on background acquisition rather than reading the bridge themselves,
+// so they are not a continuous record at the bridge's data interval:
+// - readings are taken at the acquisition period, and none while a job holds the hardware,
+//   those stretches are listed as gaps in the info file
+// - each reading is the second sample of a two sample trial, the first one after opening
+//   the trial is dropped, so readings are at least two data intervals apart
+const ACQUISITION_NOTE: &str = "Readings come from background acquisition at sample_period, \
+    with none while a hardware job runs (see gaps). Each reading is the second sample of a \
+    two sample trial, the first is dropped, so readings are at least two bridge data \
+    intervals apart.";
+
+// Stretch of the capture without readings, times are from the start of the capture
+#[derive(Debug, Clone, Serialize)]
+pub struct CaptureGap {
+    start: Duration,
+    end: Duration,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CaptureInfo {
+    id: String,
+    path: PathBuf,
+    phidget_id: i32,
+    // Period background acquisition samples at. Readings are only taken between jobs, so
+    // the CSV times are the ones to rely on.
+    sample_period: Duration,
+    // As applied to the scale since it connected, none if they were never updated
+    coefficients: Option<[f64; 4]>,
+    started: Duration,
+    stopped: Option<Duration>,
+    readings: usize,
+    // Longer than twice the sample period, mostly while a job held the hardware
+    gaps: Vec<CaptureGap>,
+    // Written into the file so a capture read on its own explains its spacing
+    note: &'static str,
+}
+impl CaptureInfo {
+    pub fn get_id(&self) -> &str {
+        &self.id
+    }
+}
+
+// Unfiltered per-cell readings written to a CSV as they arrive, with the capture's
+// metadata kept next to it in a JSON file
+pub struct RawCapture {
+    info: CaptureInfo,
+    writer: BufWriter<File>,
+    start: Instant,
+    last: Duration,
+}
+impl RawCapture {
+    pub fn new(
+        app: &AppHandle,
+        phidget_id: i32,
+        sample_period: Duration,
+        coefficients: Option<[f64; 4]>,
+    ) -> Result<Self, AppError> {
+        let dir = app
+            .path()
+            .app_data_dir()
+            .map_err(AppError::Tauri)?
+            .join("captures");
+        fs::create_dir_all(&dir).map_err(AppError::Io)?;
+        let started = now();
+        let id = format!("{phidget_id}-{}", started.as_millis());
+        let path = dir.join(format!("{id}.csv"));
+        let mut writer = BufWriter::new(File::create(&path).map_err(AppError::Io)?);
+        writeln!(writer, "time,cell_0,cell_1,cell_2,cell_3").map_err(AppError::Io)?;
+        let capture = Self {
+            info: CaptureInfo {
+                id,
+                path,
+                phidget_id,
+                sample_period,
+                coefficients,
+                started,
+                stopped: None,
+                readings: 0,
+                gaps: Vec::new(),
+                note: ACQUISITION_NOTE,
+            },
+            writer,
+            start: Instant::now(),
+            last: Duration::ZERO,
+        };
+        capture.write_info()?;
+        Ok(capture)
+    }
+    pub fn get_info(&self) -> &CaptureInfo {
+        &self.info
+    }
+    pub fn record(&mut self, cells: [f64; 4]) -> Result<(), AppError> {
+        let time = self.start.elapsed();
+        self.check_gap(time);
+        let [cell_0, cell_1, cell_2, cell_3] = cells;
+        writeln!(
+            self.writer,
+            "{},{cell_0},{cell_1},{cell_2},{cell_3}",
+            time.as_secs_f64()
+        )
+        .map_err(AppError::Io)?;
+        self.info.readings += 1;
+        Ok(())
+    }
+    fn check_gap(&mut self, time: Duration) {
+        if time - self.last > self.info.sample_period * 2 {
+            self.info.gaps.push(CaptureGap {
+                start: self.last,
+                end: time,
+            });
+        }
+        self.last = time;
+    }
+    pub fn finish(mut self) -> Result<CaptureInfo, AppError> {
+        self.writer.flush().map_err(AppError::Io)?;
+        self.check_gap(self.start.elapsed());
+        self.info.stopped.replace(now());
+        self.write_info()?;
+        Ok(self.info)
+    }
+    fn write_info(&self) -> Result<(), AppError> {
+        let contents = serde_json::to_string_pretty(&self.info).map_err(AppError::Serde)?;
+        fs::write(self.info.path.with_extension("json"), contents).map_err(AppError::Io)
+    }
+}
+
+fn now() -> Duration {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap()
+}
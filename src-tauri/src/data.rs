@@ -1,10 +1,38 @@
+use crate::dispenser::{self, DispenseResult, DispenseSettings};
 use crate::errors::AppError;
+use crate::hardware::CancelToken;
+use crate::motor::Motor;
+use crate::progress::ProgressReporter;
+use crate::recording::{RecordedEvent, Recorder};
+use crate::scale::Scale;
+use crate::statistics;
 use libra::scale::ConnectedScale;
 use node_diagnostics::data::Data;
 use node_diagnostics::filter::Filter;
-use node_diagnostics::trial::{WeightTrial as NodeTrial, WeightTrialType, LoadCellTrial};
+use node_diagnostics::trial::{LoadCellTrial, WeightTrial as NodeTrial, WeightTrialType};
 use serde::{Deserialize, Serialize};
-use std::time::Duration;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+// Rolling window for median trials on scales without the node-diagnostics trials
+const MEDIAN_WINDOW: usize = 5;
+// Node-diagnostics trials run in chunks about this long, so a cancel or a progress update
+// never waits for the whole trial
+const CHUNK_PERIOD: Duration = Duration::from_millis(500);
+
+fn chunk_samples(sample_period: Duration, remaining: usize) -> usize {
+    let samples = CHUNK_PERIOD.as_secs_f64() / sample_period.as_secs_f64();
+    (samples as usize).clamp(1, remaining.max(1))
+}
+// Node trials return a bad first sample, so every chunk takes one extra and drops it
+fn drop_first_sample(data: &mut Data) -> Result<(), AppError> {
+    if data.readings.len() < 2 {
+        return Err(AppError::Other("Node trial returned no samples!".into()));
+    }
+    data.readings.remove(0);
+    data.times.remove(0);
+    Ok(())
+}
 
 #[derive(Deserialize, Serialize)]
 pub enum TestType {
@@ -19,56 +47,314 @@ pub struct DataRequest {
     samples: usize,
     sample_period: Duration,
     cutoff_frequency: Option<f64>,
+    // Only used by dispense trials
+    #[serde(default)]
+    dispense_settings: Option<DispenseSettings>,
 }
 impl DataRequest {
-    pub fn conduct(self, scale: &mut ConnectedScale) -> Result<Data, AppError> {
-        let trial = self.to_node_trial()?;
-        // TODO: this should be done elsewhere
-        // scale
-        //     .set_data_intervals(self.sample_period)
-        //     .map_err(AppError::Libra)?;
-        let mut data = trial.conduct(scale).map_err(AppError::NodeDiagnostics)?;
-        // TODO: figure out how to remove this in node-diagnostics
-        if !data.readings.is_empty() {
-            data.readings.remove(0);
-            data.times.remove(0);
+    pub fn is_dispense(&self) -> bool {
+        matches!(self.trial, TestType::Dispense)
+    }
+    // Dispense trials need the motor, so they are run here instead of through conduct
+    pub async fn dispense(
+        self,
+        motor: &Motor,
+        scale: &mut dyn Scale,
+        cancel: &CancelToken,
+        progress: &mut ProgressReporter,
+    ) -> Result<TrialData, AppError> {
+        let settings = self.dispense_settings.ok_or(AppError::Other(
+            "Missing dispense settings for dispense trial!".into(),
+        ))?;
+        let (data, result) = dispenser::dispense(motor, scale, &settings, cancel, progress).await?;
+        Ok(TrialData::from_dispense(data, result))
+    }
+    // Samples until done or cancelled, a cancelled request returns what it has so far
+    pub fn conduct(
+        self,
+        scale: &mut dyn Scale,
+        cancel: &CancelToken,
+        progress: &mut ProgressReporter,
+    ) -> Result<Data, AppError> {
+        let filter = match self.trial {
+            TestType::Filtered => Some(self.filter(scale.get_sample_period(self.sample_period))?),
+            TestType::Dispense => return Err(AppError::NotImplemented),
+            _ => None,
+        };
+        let recorder = scale.get_recorder();
+        match scale.as_phidget() {
+            Some(scale) => self.conduct_phidget(scale, filter, recorder, cancel, progress),
+            None => self.conduct_sampled(scale, filter, recorder, cancel, progress),
+        }
+    }
+    // Raw and median trials run as node trials of their own type. A filtered trial runs raw
+    // node trials through the same filter here, since a filter restarted for every chunk
+    // would settle again at each one. Weights are recorded before they are filtered.
+    fn conduct_phidget(
+        &self,
+        scale: &mut ConnectedScale,
+        mut filter: Option<Filter>,
+        recorder: Option<Recorder>,
+        cancel: &CancelToken,
+        progress: &mut ProgressReporter,
+    ) -> Result<Data, AppError> {
+        let mut data = Data::new(self.samples);
+        let start = Instant::now();
+        while data.readings.len() < self.samples && !cancel.is_cancelled() {
+            let samples = chunk_samples(self.sample_period, self.samples - data.readings.len());
+            let trial = match self.trial {
+                TestType::Median => WeightTrialType::Median,
+                _ => WeightTrialType::Raw,
+            };
+            let offset = start.elapsed();
+            let mut chunk = NodeTrial::new(trial, samples + 1, self.sample_period)
+                .conduct(scale)
+                .map_err(AppError::NodeDiagnostics)?;
+            drop_first_sample(&mut chunk)?;
+            for (time, weight) in chunk.times.into_iter().zip(chunk.readings) {
+                if let Some(recorder) = recorder.as_ref() {
+                    recorder.record_at(start + offset + time, RecordedEvent::Weight(weight));
+                }
+                let reading = filter
+                    .as_mut()
+                    .map_or(weight, |filter| filter.apply(weight));
+                data.push(offset + time, reading);
+            }
+            let last = data.readings.last().copied();
+            progress.report(data.readings.len(), self.samples, last, || {
+                Scale::get_raw_readings(scale).ok()
+            });
         }
         Ok(data)
     }
-    fn to_node_trial(&self) -> Result<NodeTrial, AppError> {
-        let trial = match self.trial {
-            TestType::Raw => WeightTrialType::Raw,
-            TestType::Median => WeightTrialType::Median,
-            TestType::Filtered => {
-                let sample_rate = 1. / self.sample_period.as_secs_f64();
-                let filter = Filter::new(
-                    sample_rate,
-                    self.cutoff_frequency.ok_or(AppError::Other(
-                        "Missing cutoff frequency for filtered trial!".into(),
-                    ))?,
-                );
-                WeightTrialType::Filtered(filter)
+    fn conduct_sampled(
+        &self,
+        scale: &mut dyn Scale,
+        mut filter: Option<Filter>,
+        recorder: Option<Recorder>,
+        cancel: &CancelToken,
+        progress: &mut ProgressReporter,
+    ) -> Result<Data, AppError> {
+        let mut window = VecDeque::with_capacity(MEDIAN_WINDOW);
+        let mut data = Data::new(self.samples);
+        let start = Instant::now();
+        for sample in 1..=self.samples {
+            if cancel.is_cancelled() {
+                break;
             }
-            _ => return Err(AppError::NotImplemented),
-        };
-        Ok(NodeTrial::new(trial, self.samples, self.sample_period))
+            let weight = match scale.get_weight() {
+                Ok(weight) => weight,
+                // A replayed recording ends the request early, like a cancel
+                Err(AppError::ReplayFinished) => break,
+                Err(e) => return Err(e),
+            };
+            let time = scale.timestamp(start);
+            if let Some(recorder) = recorder.as_ref() {
+                recorder.record_at(start + time, RecordedEvent::Weight(weight));
+            }
+            let reading = match (&self.trial, filter.as_mut()) {
+                (TestType::Filtered, Some(filter)) => filter.apply(weight),
+                (TestType::Median, _) => {
+                    if window.len() == MEDIAN_WINDOW {
+                        window.pop_front();
+                    }
+                    window.push_back(weight);
+                    statistics::median(window.make_contiguous()).unwrap_or(weight)
+                }
+                _ => weight,
+            };
+            data.push(time, reading);
+            progress.report(sample, self.samples, Some(reading), || {
+                scale.get_raw_readings().ok()
+            });
+            scale.wait(self.sample_period);
+        }
+        Ok(data)
+    }
+    fn filter(&self, sample_period: Duration) -> Result<Filter, AppError> {
+        let sample_rate = 1. / sample_period.as_secs_f64();
+        Ok(Filter::new(
+            sample_rate,
+            self.cutoff_frequency.ok_or(AppError::Other(
+                "Missing cutoff frequency for filtered trial!".into(),
+            ))?,
+        ))
+    }
+}
+// The same shape as Data, with the result added for dispense trials
+#[derive(Serialize)]
+pub struct TrialData {
+    #[serde(flatten)]
+    data: Data,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dispense: Option<DispenseResult>,
+}
+impl TrialData {
+    pub fn from_dispense(data: Data, result: DispenseResult) -> Self {
+        Self {
+            data,
+            dispense: Some(result),
+        }
+    }
+}
+impl From<Data> for TrialData {
+    fn from(data: Data) -> Self {
+        Self {
+            data,
+            dispense: None,
+        }
     }
 }
+
 #[derive(Deserialize, Serialize)]
 pub struct LoadCellDataRequest {
     samples: usize,
     sample_period: Duration
 }
 impl LoadCellDataRequest {
-    pub fn conduct(self, scale: &mut ConnectedScale) -> Result<[Data; 4], AppError> {
-        let trial = LoadCellTrial::new(self.samples, self.sample_period);
-        let mut data = trial.conduct(scale).map_err(AppError::NodeDiagnostics)?;
-        if !data[0].readings.is_empty() {
-            for datum in &mut data {
-                datum.readings.remove(0);
-                datum.times.remove(0);
+    pub fn new(samples: usize, sample_period: Duration) -> Self {
+        Self {
+            samples,
+            sample_period,
+        }
+    }
+    pub fn conduct(
+        self,
+        scale: &mut dyn Scale,
+        cancel: &CancelToken,
+        progress: &mut ProgressReporter,
+    ) -> Result<[Data; 4], AppError> {
+        let recorder = scale.get_recorder();
+        match scale.as_phidget() {
+            Some(scale) => self.conduct_phidget(scale, recorder, cancel, progress),
+            None => self.conduct_sampled(scale, recorder, cancel, progress),
+        }
+    }
+    fn conduct_phidget(
+        &self,
+        scale: &mut ConnectedScale,
+        recorder: Option<Recorder>,
+        cancel: &CancelToken,
+        progress: &mut ProgressReporter,
+    ) -> Result<[Data; 4], AppError> {
+        let mut data: [Data; 4] = std::array::from_fn(|_| Data::new(self.samples));
+        let start = Instant::now();
+        while data[0].readings.len() < self.samples && !cancel.is_cancelled() {
+            let samples = chunk_samples(self.sample_period, self.samples - data[0].readings.len());
+            let offset = start.elapsed();
+            let chunk = LoadCellTrial::new(samples + 1, self.sample_period)
+                .conduct(scale)
+                .map_err(AppError::NodeDiagnostics)?;
+            let recorded = data[0].readings.len();
+            for (datum, mut cell) in data.iter_mut().zip(chunk) {
+                drop_first_sample(&mut cell)?;
+                for (time, reading) in cell.times.into_iter().zip(cell.readings) {
+                    datum.push(offset + time, reading);
+                }
+            }
+            if let Some(recorder) = recorder.as_ref() {
+                for sample in recorded..data[0].readings.len() {
+                    let readings = data.each_ref().map(|datum| datum.readings[sample]);
+                    let time = start + data[0].times[sample];
+                    recorder.record_at(time, RecordedEvent::Readings(readings));
+                }
+            }
+            let last = data
+                .each_ref()
+                .map(|datum| datum.readings.last().copied().unwrap_or_default());
+            progress.report(data[0].readings.len(), self.samples, None, || Some(last));
+        }
+        Ok(data)
+    }
+    fn conduct_sampled(
+        &self,
+        scale: &mut dyn Scale,
+        recorder: Option<Recorder>,
+        cancel: &CancelToken,
+        progress: &mut ProgressReporter,
+    ) -> Result<[Data; 4], AppError> {
+        let mut data: [Data; 4] = std::array::from_fn(|_| Data::new(self.samples));
+        let start = Instant::now();
+        for sample in 1..=self.samples {
+            if cancel.is_cancelled() {
+                break;
+            }
+            let readings = scale.get_raw_readings()?;
+            let time = scale.timestamp(start);
+            if let Some(recorder) = recorder.as_ref() {
+                recorder.record_at(start + time, RecordedEvent::Readings(readings));
             }
+            for (datum, reading) in data.iter_mut().zip(readings) {
+                datum.push(time, reading);
+            }
+            progress.report(sample, self.samples, None, || Some(readings));
+            scale.wait(self.sample_period);
         }
         Ok(data)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scale::testing::TestScale;
+
+    fn request(trial: TestType, samples: usize) -> DataRequest {
+        DataRequest {
+            trial,
+            samples,
+            sample_period: Duration::from_millis(1),
+            cutoff_frequency: None,
+            dispense_settings: None,
+        }
+    }
+
+    #[test]
+    fn scale_errors_end_the_request() {
+        let mut scale = TestScale::new([1.; 4]).failing_after(3);
+        let result = request(TestType::Raw, 10).conduct(
+            &mut scale,
+            &CancelToken::default(),
+            &mut ProgressReporter::silent(),
+        );
+        assert!(matches!(result, Err(AppError::Other(_))));
+        // The scale is only borrowed, so it reads again once it is back
+        scale.reconnect();
+        assert_eq!(scale.get_weight().unwrap(), 4.);
+    }
+
+    #[test]
+    fn load_cell_request_returns_scale_errors() {
+        let mut scale = TestScale::new([1.; 4]).failing_after(3);
+        let result = LoadCellDataRequest::new(10, Duration::from_millis(1)).conduct(
+            &mut scale,
+            &CancelToken::default(),
+            &mut ProgressReporter::silent(),
+        );
+        assert!(matches!(result, Err(AppError::Other(_))));
+        scale.reconnect();
+        assert_eq!(scale.get_raw_readings().unwrap(), [1.; 4]);
+    }
+
+    #[test]
+    fn filtered_request_needs_a_cutoff() {
+        let mut scale = TestScale::new([1.; 4]);
+        let result = request(TestType::Filtered, 10).conduct(
+            &mut scale,
+            &CancelToken::default(),
+            &mut ProgressReporter::silent(),
+        );
+        assert!(matches!(result, Err(AppError::Other(_))));
+    }
+
+    #[test]
+    fn dispense_request_is_not_conducted() {
+        let mut scale = TestScale::new([1.; 4]);
+        let result = request(TestType::Dispense, 10).conduct(
+            &mut scale,
+            &CancelToken::default(),
+            &mut ProgressReporter::silent(),
+        );
+        assert!(matches!(result, Err(AppError::NotImplemented)));
+    }
+}
@@ -1,124 +1,463 @@
-// use crate::errors::AppError;
-// use async_clear_core::{motor::ClearCoreMotor};
-// use libra::scale::ConnectedScale;
-// use node_diagnostics::{data::Data, filter::Filter};
-// use std::time::Duration;
-// use serde::Deserialize;
-
-// pub struct Dispenser {}
-// 
-// impl Dispenser {
-//     pub async fn dispense(
-//         motor: &ClearCoreMotor,
-//         scale: ConnectedScale,
-//         settings: DispenseSettings,
-//     ) -> Result<(Data, ConnectedScale), AppError> {
-//         Err(AppError::NotImplemented)
-//     }
-    //     motor
-    //         .set_velocity(settings.max_velocity)
-    //         .await
-    //         .map_err(AppError::Anyhow)?;
-    //     tokio::time::sleep(Duration::from_secs(2)).await;
-    // 
-    //     let mut data = Data::new(10000);
-    //     let sample_rate = 1. / settings.sample_period.as_secs_f64();
-    //     let mut filter = Filter::new(sample_rate, settings.cutoff_frequency);
-    //     let starting_weight = scale
-    //         .get_median_weight(100, settings.sample_period)
-    //         .map_err(AppError::Libra)?
-    //         .get();
-    //     // TODO: figure out what to do with this...
-    //     filter.apply(starting_weight);
-    //     
-    //     let mut interval = tokio::time::interval(settings.sample_period);
-    //     interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
-    //     motor.relative_move(1000.).await.map_err(AppError::Anyhow)?;
-    //     let mut last_speed_update = tokio::time::Instant::now();
-    //     
-    //     // let starting_weight = scale
-    //     //     .get_median_weight(10, settings.sample_period)
-    //     //     .map_err(AppError::Libra)?
-    //     //     .get();
-    //     // // TODO: figure out what to do with this...
-    //     // filter.apply(starting_weight);
-    //     
-    //     let start_time = tokio::time::Instant::now();
-    //     let mut checks_made = 0;
-    //     let result = loop {
-    //         interval.tick().await;
-    //         let curr_weight = filter.apply(scale.get_weight().map_err(AppError::Libra)?.get());
-    //         let now = tokio::time::Instant::now();
-    //         data.push(now - start_time, curr_weight);
-    //         
-    //         if (tokio::time::Instant::now() - last_speed_update > Duration::from_millis(25)) && (now-start_time > settings.start_buffer) {
-    //             let err = (curr_weight - starting_weight + settings.weight)/settings.weight;
-    //             let new_speed  = err*settings.max_velocity;
-    //             if new_speed > settings.max_velocity {
-    //                 motor.set_velocity(settings.max_velocity).await.map_err(AppError::Anyhow)?;
-    //             } else if new_speed > settings.min_velocity {
-    //                 motor.set_velocity(new_speed).await.map_err(AppError::Anyhow)?;
-    //             }  else {
-    //                 motor.set_velocity(settings.min_velocity).await.map_err(AppError::Anyhow)?;
-    //             }
-    //             last_speed_update = tokio::time::Instant::now();
-    //             motor.relative_move(1000.).await.map_err(AppError::Anyhow)?;
-    //         }
-    //         
-    //         if curr_weight <= starting_weight - (settings.weight - settings.check_offset) && (now-start_time > settings.start_buffer) {
-    //             checks_made += 1;
-    //             motor.abrupt_stop().await.map_err(AppError::Anyhow)?;
-    //             tokio::time::sleep(Duration::from_millis(50)).await;
-    //             let med_weight = scale
-    //                 .get_median_weight(100, settings.sample_period)
-    //                 .map_err(AppError::Libra)?
-    //                 .get();
-    //             data.push(tokio::time::Instant::now() - start_time, med_weight);
-    //             if (med_weight <= starting_weight - settings.weight) | (checks_made >= 3) {
-    //                 break Ok((data, scale))
-    //             } else {
-    //                 filter = Filter::new(sample_rate, settings.cutoff_frequency);
-    //                 filter.apply(med_weight);
-    //                 motor.relative_move(1000.).await.map_err(AppError::Anyhow)?;
-    //                 // continue
-    //             }
-    //         }
-    //         if tokio::time::Instant::now() - start_time > settings.timeout {
-    //             motor.abrupt_stop().await.map_err(AppError::Anyhow)?;
-    //             break Err(AppError::DispenseTimeout((data, scale)))
-    //         }
-    //         checks_made = 0;
-    //     };
-    //     motor.relative_move(-settings.retract).await.map_err(AppError::Anyhow)?;
-    //     tokio::time::sleep(Duration::from_millis(25)).await;
-    //     motor.wait_for_move(Duration::from_millis(10)).await.map_err(AppError::Anyhow)?;
-    //     result
-    // }
-// }
-// #[derive(Deserialize, Debug)]
-// pub struct DispenseSettings {
-//     sample_period: Duration,
-//     cutoff_frequency: f64,
-//     check_offset: f64,
-//     weight: f64,
-//     max_velocity: f64,
-//     min_velocity: f64,
-//     retract: f64,
-//     timeout: Duration,
-//     start_buffer: Duration
-// }
-// impl Default for DispenseSettings {
-//     fn default() -> Self {
-//         Self {
-//             sample_period: Duration::from_millis(80),
-//             cutoff_frequency: 2.0,
-//             check_offset: 5.,
-//             weight: 50.,
-//             max_velocity: 0.5,
-//             min_velocity: 0.1,
-//             retract: 0.1,
-//             timeout: Duration::from_secs(30),
-//             start_buffer: Duration::from_millis(500)
-//         }
-//     }
-// }
+use crate::errors::AppError;
+use crate::hardware::CancelToken;
+use crate::motor::Motor;
+use crate::progress::ProgressReporter;
+use crate::recording::RecordedEvent;
+use crate::scale::Scale;
+use crate::strategy::{DispenseState, DispenseStrategy, MIN_UPDATE_PERIOD};
+use node_diagnostics::data::Data;
+use node_diagnostics::filter::Filter;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+// Distance for each forward move, far enough that the motor never reaches it between
+// speed updates
+const FEED_DISTANCE: f64 = 1000.;
+const SPEED_UPDATE_PERIOD: Duration = Duration::from_millis(25);
+const MAX_CHECKS: usize = 3;
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct DispenseSettings {
+    sample_period: Duration,
+    cutoff_frequency: f64,
+    check_offset: f64,
+    weight: f64,
+    max_velocity: f64,
+    min_velocity: f64,
+    retract: f64,
+    timeout: Duration,
+    start_buffer: Duration,
+    check_samples: usize,
+    #[serde(default)]
+    strategy: DispenseStrategy,
+}
+
+impl DispenseSettings {
+    // Checked before the motor moves, a bad setting would otherwise panic mid-dispense
+    fn validate(&self) -> Result<(), AppError> {
+        // The error is reported relative to the target
+        if self.weight <= 0. {
+            return Err(AppError::Other("Dispense weight must be positive!".into()));
+        }
+        if !self.min_velocity.is_finite() || !self.max_velocity.is_finite() {
+            return Err(AppError::Other(
+                "Dispense velocities must be finite!".into(),
+            ));
+        }
+        if self.min_velocity <= 0. || self.min_velocity > self.max_velocity {
+            return Err(AppError::Other(
+                "Minimum velocity must be positive and at most the maximum velocity!".into(),
+            ));
+        }
+        if self.check_samples == 0 {
+            return Err(AppError::Other(
+                "Dispense checks need at least one sample!".into(),
+            ));
+        }
+        if self.sample_period.is_zero() {
+            return Err(AppError::Other("Sample period must be positive!".into()));
+        }
+        if self.cutoff_frequency <= 0. || self.cutoff_frequency.is_nan() {
+            return Err(AppError::Other("Cutoff frequency must be positive!".into()));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum DispenseOutcome {
+    Success,
+    // Still short of the target after the last check
+    Underweight,
+    Timeout,
+    Cancelled,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DispenseResult {
+    outcome: DispenseOutcome,
+    target: f64,
+    dispensed: f64,
+    // Dispensed minus target, positive when over
+    error: f64,
+    error_percent: f64,
+    duration: Duration,
+    checks: usize,
+    // Estimated from the commands sent on the ClearCore
+    final_position: Option<f64>,
+}
+impl DispenseResult {
+    pub fn get_outcome(&self) -> DispenseOutcome {
+        self.outcome
+    }
+    pub fn get_dispensed(&self) -> f64 {
+        self.dispensed
+    }
+    pub fn get_error(&self) -> f64 {
+        self.error
+    }
+    pub fn get_duration(&self) -> Duration {
+        self.duration
+    }
+}
+
+// Feeds at the speed the settings' strategy asks for, then a median check once the target
+// is within check_offset. The scale weighs the hopper, so weight decreases while dispensing.
+// Progress is reported as the percentage of the target dispensed so far.
+pub async fn dispense(
+    motor: &Motor,
+    scale: &mut dyn Scale,
+    settings: &DispenseSettings,
+    cancel: &CancelToken,
+    progress: &mut ProgressReporter,
+) -> Result<(Data, DispenseResult), AppError> {
+    settings.validate()?;
+    let result = feed(motor, scale, settings, cancel, progress).await;
+    // A failed read or move must not leave the auger running towards FEED_DISTANCE
+    if result.is_err() {
+        motor.abrupt_stop().await;
+    }
+    result
+}
+async fn feed(
+    motor: &Motor,
+    scale: &mut dyn Scale,
+    settings: &DispenseSettings,
+    cancel: &CancelToken,
+    progress: &mut ProgressReporter,
+) -> Result<(Data, DispenseResult), AppError> {
+    motor.set_velocity(settings.max_velocity).await;
+    tokio::time::sleep(Duration::from_secs(2)).await;
+
+    let mut data = Data::new(10000);
+    let sample_period = scale.get_sample_period(settings.sample_period);
+    let sample_rate = 1. / sample_period.as_secs_f64();
+    let mut filter = Filter::new(sample_rate, settings.cutoff_frequency);
+    // The scale records the samples of each median itself
+    let recorder = scale.get_recorder();
+    let record = |weight| {
+        if let Some(recorder) = recorder.as_ref() {
+            recorder.record(RecordedEvent::Weight(weight));
+        }
+    };
+    let mut report = |dispensed: f64| {
+        let percent = (100. * dispensed / settings.weight).clamp(0., 100.);
+        progress.report(percent as usize, 100, Some(dispensed), || None);
+    };
+    let starting_weight =
+        scale.get_median_weight(settings.check_samples, settings.sample_period)?;
+    filter.apply(starting_weight);
+
+    let mut controller = settings.strategy.controller();
+    let mut interval = tokio::time::interval(settings.sample_period);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    motor.relative_move(FEED_DISTANCE).await?;
+    let start_time = tokio::time::Instant::now();
+    let mut last_speed_update = start_time;
+    let mut last_update_weight = starting_weight;
+    let mut checks_made = 0;
+    let mut last_weight = starting_weight;
+    let (outcome, final_weight) = loop {
+        interval.tick().await;
+        if cancel.is_cancelled() {
+            motor.abrupt_stop().await;
+            break (DispenseOutcome::Cancelled, last_weight);
+        }
+        let weight = scale.get_weight()?;
+        record(weight);
+        let curr_weight = filter.apply(weight);
+        let now = tokio::time::Instant::now();
+        data.push(now - start_time, curr_weight);
+        report(starting_weight - curr_weight);
+        last_weight = curr_weight;
+        let started = now - start_time > settings.start_buffer;
+
+        if started && now - last_speed_update > SPEED_UPDATE_PERIOD {
+            let since_update = now - last_speed_update;
+            let state = DispenseState {
+                target: settings.weight,
+                dispensed: starting_weight - curr_weight,
+                flow_rate: (last_update_weight - curr_weight)
+                    / since_update.max(MIN_UPDATE_PERIOD).as_secs_f64(),
+                since_update,
+                min_velocity: settings.min_velocity,
+                max_velocity: settings.max_velocity,
+            };
+            let speed = controller
+                .velocity(&state)
+                .clamp(settings.min_velocity, settings.max_velocity);
+            motor.set_velocity(speed).await;
+            motor.relative_move(FEED_DISTANCE).await?;
+            last_speed_update = now;
+            last_update_weight = curr_weight;
+        }
+
+        if started && curr_weight <= starting_weight - (settings.weight - settings.check_offset) {
+            checks_made += 1;
+            motor.abrupt_stop().await;
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let median_weight =
+                scale.get_median_weight(settings.check_samples, settings.sample_period)?;
+            data.push(start_time.elapsed(), median_weight);
+            report(starting_weight - median_weight);
+            if median_weight <= starting_weight - settings.weight {
+                break (DispenseOutcome::Success, median_weight);
+            }
+            if checks_made >= MAX_CHECKS {
+                break (DispenseOutcome::Underweight, median_weight);
+            }
+            filter = Filter::new(sample_rate, settings.cutoff_frequency);
+            filter.apply(median_weight);
+            controller.reset();
+            last_speed_update = tokio::time::Instant::now();
+            last_update_weight = median_weight;
+            motor.relative_move(FEED_DISTANCE).await?;
+        }
+
+        if start_time.elapsed() > settings.timeout {
+            motor.abrupt_stop().await;
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let median_weight =
+                scale.get_median_weight(settings.check_samples, settings.sample_period)?;
+            data.push(start_time.elapsed(), median_weight);
+            break (DispenseOutcome::Timeout, median_weight);
+        }
+    };
+    let duration = start_time.elapsed();
+    motor.relative_move(-settings.retract).await?;
+    tokio::time::sleep(Duration::from_millis(25)).await;
+    motor.wait_for_move(Duration::from_millis(10)).await?;
+
+    let dispensed = starting_weight - final_weight;
+    let error = dispensed - settings.weight;
+    let result = DispenseResult {
+        outcome,
+        target: settings.weight,
+        dispensed,
+        error,
+        error_percent: 100. * error / settings.weight,
+        duration,
+        checks: checks_made,
+        final_position: motor.get_position(),
+    };
+    match outcome {
+        DispenseOutcome::Success => log::info!("Dispense finished: {result:?}"),
+        DispenseOutcome::Underweight => log::warn!("Dispense underweight: {result:?}"),
+        DispenseOutcome::Timeout => log::warn!("Dispense timed out: {result:?}"),
+        DispenseOutcome::Cancelled => log::warn!("Dispense cancelled: {result:?}"),
+    }
+    Ok((data, result))
+}
+
+#[cfg(test)]
+pub mod testing {
+    use super::{DispenseOutcome, DispenseResult};
+    use std::time::Duration;
+
+    pub fn dispense_result(
+        outcome: DispenseOutcome,
+        target: f64,
+        dispensed: f64,
+        duration: Duration,
+    ) -> DispenseResult {
+        DispenseResult {
+            outcome,
+            target,
+            dispensed,
+            error: dispensed - target,
+            error_percent: 100. * (dispensed - target) / target,
+            duration,
+            checks: 1,
+            final_position: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::motor::{MotorDriver, SimulatedMotor, SimulatedMotorConfig};
+    use crate::scale::testing::TestScale;
+
+    fn settings() -> DispenseSettings {
+        DispenseSettings {
+            sample_period: Duration::from_millis(5),
+            cutoff_frequency: 2.,
+            check_offset: 1.,
+            weight: 10.,
+            max_velocity: 1.,
+            min_velocity: 0.1,
+            retract: 0.1,
+            timeout: Duration::from_secs(5),
+            start_buffer: Duration::ZERO,
+            check_samples: 3,
+            strategy: DispenseStrategy::default(),
+        }
+    }
+
+    fn block_on<T>(future: impl std::future::Future<Output = T>) -> T {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    #[test]
+    fn scale_error_stops_the_motor() {
+        let simulated = SimulatedMotor::new(SimulatedMotorConfig::default());
+        simulated.enable().unwrap();
+        let motor = Motor::new(MotorDriver::Simulated(simulated.clone()), None);
+        // The weight never drops, so the dispense is still feeding when the scale goes
+        let mut scale = TestScale::new([100.; 4]).failing_after(10);
+        let result = block_on(dispense(
+            &motor,
+            &mut scale,
+            &settings(),
+            &CancelToken::default(),
+            &mut ProgressReporter::silent(),
+        ));
+        assert!(matches!(result, Err(AppError::Other(_))));
+        assert!(!simulated.is_moving());
+        scale.reconnect();
+        assert_eq!(scale.get_weight().unwrap(), 400.);
+    }
+
+    #[test]
+    fn motor_error_keeps_the_scale() {
+        // Never enabled, so the first move fails
+        let simulated = SimulatedMotor::new(SimulatedMotorConfig::default());
+        let motor = Motor::new(MotorDriver::Simulated(simulated.clone()), None);
+        let mut scale = TestScale::new([100.; 4]);
+        let result = block_on(dispense(
+            &motor,
+            &mut scale,
+            &settings(),
+            &CancelToken::default(),
+            &mut ProgressReporter::silent(),
+        ));
+        assert!(matches!(result, Err(AppError::Motor(_))));
+        assert!(!simulated.is_moving());
+        assert_eq!(scale.get_weight().unwrap(), 400.);
+    }
+
+    // Drops by `drop` once the starting median has been read, and then stays put
+    struct StallingScale {
+        reads: std::cell::Cell<usize>,
+        drop: f64,
+    }
+    impl Scale for StallingScale {
+        fn get_phidget_id(&self) -> i32 {
+            1
+        }
+        fn set_data_intervals(&mut self, _interval: Duration) -> Result<(), AppError> {
+            Ok(())
+        }
+        fn update_coefficients(self: Box<Self>, _coefficients: [f64; 4]) -> Box<dyn Scale> {
+            self
+        }
+        fn get_coefficients(&self) -> Option<[f64; 4]> {
+            None
+        }
+        fn get_raw_readings(&mut self) -> Result<[f64; 4], AppError> {
+            Ok([0.; 4])
+        }
+        fn get_weight(&self) -> Result<f64, AppError> {
+            let reads = self.reads.get();
+            self.reads.set(reads + 1);
+            Ok(if reads < settings().check_samples {
+                100.
+            } else {
+                100. - self.drop
+            })
+        }
+        fn wait(&self, _period: Duration) {}
+    }
+
+    #[test]
+    fn short_after_the_last_check_is_underweight() {
+        let simulated = SimulatedMotor::new(SimulatedMotorConfig::default());
+        simulated.enable().unwrap();
+        let motor = Motor::new(MotorDriver::Simulated(simulated), None);
+        let dispense_until = |drop| {
+            let mut scale = StallingScale {
+                reads: std::cell::Cell::new(0),
+                drop,
+            };
+            let (_, result) = block_on(dispense(
+                &motor,
+                &mut scale,
+                &settings(),
+                &CancelToken::default(),
+                &mut ProgressReporter::silent(),
+            ))
+            .unwrap();
+            result
+        };
+        // Past the check offset but short of the target
+        let result = dispense_until(9.5);
+        assert_eq!(result.get_outcome(), DispenseOutcome::Underweight);
+        assert_eq!(result.checks, MAX_CHECKS);
+        assert_eq!(result.get_dispensed(), 9.5);
+        let result = dispense_until(10.);
+        assert_eq!(result.get_outcome(), DispenseOutcome::Success);
+        assert_eq!(result.checks, 1);
+    }
+
+    // Refused before the motor moves
+    fn assert_refused(change: impl FnOnce(&mut DispenseSettings)) {
+        let simulated = SimulatedMotor::new(SimulatedMotorConfig::default());
+        simulated.enable().unwrap();
+        let motor = Motor::new(MotorDriver::Simulated(simulated.clone()), None);
+        let mut settings = settings();
+        change(&mut settings);
+        let result = block_on(dispense(
+            &motor,
+            &mut TestScale::new([100.; 4]),
+            &settings,
+            &CancelToken::default(),
+            &mut ProgressReporter::silent(),
+        ));
+        assert!(matches!(result, Err(AppError::Other(_))));
+        assert_eq!(simulated.get_position(), 0.);
+        assert!(!simulated.is_moving());
+    }
+
+    #[test]
+    fn non_positive_weight_is_refused() {
+        assert_refused(|settings| settings.weight = 0.);
+    }
+
+    #[test]
+    fn non_finite_velocities_are_refused() {
+        assert_refused(|settings| settings.max_velocity = f64::NAN);
+        assert_refused(|settings| settings.min_velocity = f64::NAN);
+        assert_refused(|settings| settings.max_velocity = f64::INFINITY);
+    }
+
+    #[test]
+    fn non_positive_min_velocity_is_refused() {
+        assert_refused(|settings| settings.min_velocity = 0.);
+    }
+
+    #[test]
+    fn min_velocity_above_max_is_refused() {
+        assert_refused(|settings| settings.min_velocity = 2.);
+    }
+
+    #[test]
+    fn zero_check_samples_are_refused() {
+        assert_refused(|settings| settings.check_samples = 0);
+    }
+
+    #[test]
+    fn zero_sample_period_is_refused() {
+        assert_refused(|settings| settings.sample_period = Duration::ZERO);
+    }
+
+    #[test]
+    fn non_positive_cutoff_frequency_is_refused() {
+        assert_refused(|settings| settings.cutoff_frequency = 0.);
+        assert_refused(|settings| settings.cutoff_frequency = f64::NAN);
+    }
+}
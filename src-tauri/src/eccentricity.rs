@@ -0,0 +1,274 @@
+use crate::calibration_data::Coefficients;
+use crate::errors::AppError;
+use crate::procedure::Placement;
+use crate::solver::solve_linear_system;
+use serde::Serialize;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CornerResult {
+    corner: usize,
+    total: f64,
+    error: f64,
+    error_percent: f64,
+    dominant_cell: usize,
+    passed: bool,
+}
+#[derive(Debug, Clone, Serialize)]
+pub struct CornerLoadReport {
+    weight: f64,
+    tolerance: f64,
+    reference: f64,
+    center_total: Option<f64>,
+    corners: Vec<CornerResult>,
+    suggested_coefficients: Option<[f64; 4]>,
+    coefficient_ratios: Option<[f64; 4]>,
+    suspect_cell: Option<usize>,
+    complete: bool,
+    passed: bool,
+}
+
+pub struct CornerLoadTest {
+    weight: f64,
+    tolerance: f64,
+    empty: Option<[f64; 4]>,
+    center: Option<[f64; 4]>,
+    corners: [Option<[f64; 4]>; 4],
+}
+impl CornerLoadTest {
+    pub fn new(weight: f64, tolerance: f64) -> Self {
+        Self {
+            weight,
+            tolerance,
+            empty: None,
+            center: None,
+            corners: [None; 4],
+        }
+    }
+    pub fn capture(&mut self, position: Placement, readings: [f64; 4]) -> Result<(), AppError> {
+        match position {
+            Placement::Empty => self.empty = Some(readings),
+            Placement::Center => self.center = Some(readings),
+            Placement::Corner(corner) => {
+                *self
+                    .corners
+                    .get_mut(corner)
+                    .ok_or(AppError::Other(format!("No corner {corner}!")))? = Some(readings)
+            }
+        }
+        Ok(())
+    }
+    // Totals are taken relative to the empty platter so offsets do not count as error.
+    // The center reading is the reference when present, since it exercises all four
+    // load cells evenly; otherwise the known test weight is used.
+    pub fn report(&self, coefficients: &Coefficients) -> Result<CornerLoadReport, AppError> {
+        let empty = self.empty.ok_or(AppError::Other(
+            "Capture the empty platter before the corners!".into(),
+        ))?;
+        let coefficients = coefficients.get_coefficients();
+        let deltas = |readings: &[f64; 4]| -> [f64; 4] {
+            std::array::from_fn(|cell| readings[cell] - empty[cell])
+        };
+        let total = |deltas: &[f64; 4]| -> f64 {
+            deltas
+                .iter()
+                .zip(coefficients)
+                .map(|(delta, coefficient)| delta * coefficient)
+                .sum()
+        };
+
+        let center_total = self.center.as_ref().map(|center| total(&deltas(center)));
+        let reference = center_total.unwrap_or(self.weight);
+        // Errors are reported relative to the reference, so it has to be a real load
+        if reference <= 0. {
+            return Err(AppError::Other(format!(
+                "Corner test reference must be positive, got {reference}! Check the test weight and center capture."
+            )));
+        }
+        let corners: Vec<CornerResult> = self
+            .corners
+            .iter()
+            .enumerate()
+            .filter_map(|(corner, readings)| readings.as_ref().map(|r| (corner, deltas(r))))
+            .map(|(corner, deltas)| {
+                let total = total(&deltas);
+                let error = total - reference;
+                let dominant_cell = (0..4)
+                    .max_by(|&a, &b| deltas[a].abs().total_cmp(&deltas[b].abs()))
+                    .unwrap();
+                CornerResult {
+                    corner,
+                    total,
+                    error,
+                    error_percent: 100. * error / reference,
+                    dominant_cell,
+                    passed: error.abs() <= self.tolerance,
+                }
+            })
+            .collect();
+
+        let complete = self.corners.iter().all(Option::is_some);
+        let suggested_coefficients = complete
+            .then(|| {
+                let matrix = self
+                    .corners
+                    .iter()
+                    .flatten()
+                    .map(|r| deltas(r).to_vec())
+                    .collect();
+                solve_linear_system(matrix, vec![reference; 4])
+            })
+            .flatten()
+            .map(|solution| [solution[0], solution[1], solution[2], solution[3]]);
+        let coefficient_ratios = suggested_coefficients
+            .map(|suggested| std::array::from_fn(|cell| suggested[cell] / coefficients[cell]));
+        let failed = corners.iter().any(|corner| !corner.passed);
+        let passed = complete && !failed;
+        // Only point at a load cell when a corner actually failed, not when some are missing
+        let suspect_cell = if !failed {
+            None
+        } else {
+            match coefficient_ratios {
+                Some(ratios) => (0..4)
+                    .max_by(|&a, &b| (ratios[a] - 1.).abs().total_cmp(&(ratios[b] - 1.).abs())),
+                None => corners
+                    .iter()
+                    .max_by(|a, b| a.error.abs().total_cmp(&b.error.abs()))
+                    .map(|corner| corner.dominant_cell),
+            }
+        };
+
+        Ok(CornerLoadReport {
+            weight: self.weight,
+            tolerance: self.tolerance,
+            reference,
+            center_total,
+            corners,
+            suggested_coefficients,
+            coefficient_ratios,
+            suspect_cell,
+            complete,
+            passed,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EMPTY: [f64; 4] = [10., 20., 30., 40.];
+    const WEIGHT: f64 = 1000.;
+
+    // The weight on `corner` puts 70% of its load on that corner's cell, and each cell
+    // reads `gains` of its true load
+    fn corner_readings(corner: usize, gains: [f64; 4]) -> [f64; 4] {
+        std::array::from_fn(|cell| {
+            let share = if cell == corner { 0.7 } else { 0.1 };
+            EMPTY[cell] + WEIGHT * share * gains[cell]
+        })
+    }
+    fn test_with(corners: &[usize], gains: [f64; 4]) -> CornerLoadTest {
+        let mut test = CornerLoadTest::new(WEIGHT, 5.);
+        test.capture(Placement::Empty, EMPTY).unwrap();
+        for &corner in corners {
+            test.capture(Placement::Corner(corner), corner_readings(corner, gains))
+                .unwrap();
+        }
+        test
+    }
+    fn unit_coefficients() -> Coefficients {
+        Coefficients::new([1.; 4], None)
+    }
+
+    #[test]
+    fn needs_the_empty_platter() {
+        let mut test = CornerLoadTest::new(WEIGHT, 5.);
+        test.capture(Placement::Corner(0), corner_readings(0, [1.; 4]))
+            .unwrap();
+        assert!(test.report(&unit_coefficients()).is_err());
+        assert!(test.capture(Placement::Corner(4), EMPTY).is_err());
+    }
+
+    #[test]
+    fn no_corners_do_not_pass() {
+        let report = test_with(&[], [1.; 4])
+            .report(&unit_coefficients())
+            .unwrap();
+        assert!(report.corners.is_empty());
+        assert!(!report.complete);
+        assert!(!report.passed);
+        assert_eq!(report.suspect_cell, None);
+        assert_eq!(report.suggested_coefficients, None);
+    }
+
+    #[test]
+    fn passing_corners_do_not_pass_an_incomplete_test() {
+        let report = test_with(&[0, 3], [1.; 4])
+            .report(&unit_coefficients())
+            .unwrap();
+        assert_eq!(report.corners.len(), 2);
+        assert!(report.corners.iter().all(|corner| corner.passed));
+        assert!(!report.complete);
+        assert!(!report.passed);
+        assert_eq!(report.suspect_cell, None);
+    }
+
+    #[test]
+    fn a_complete_even_scale_passes() {
+        let report = test_with(&[0, 1, 2, 3], [1.; 4])
+            .report(&unit_coefficients())
+            .unwrap();
+        assert!(report.complete);
+        assert!(report.passed);
+        assert_eq!(report.reference, WEIGHT);
+        assert_eq!(report.suspect_cell, None);
+        for ratio in report.coefficient_ratios.unwrap() {
+            assert!((ratio - 1.).abs() < 1e-9);
+        }
+        let dominant: Vec<usize> = report
+            .corners
+            .iter()
+            .map(|corner| corner.dominant_cell)
+            .collect();
+        assert_eq!(dominant, [0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn a_weak_cell_fails_and_is_suspected() {
+        let gains = [1., 1., 0.9, 1.];
+        let report = test_with(&[0, 1, 2, 3], gains)
+            .report(&unit_coefficients())
+            .unwrap();
+        assert!(report.complete);
+        assert!(!report.passed);
+        let corner = &report.corners[2];
+        assert!((corner.error + 70.).abs() < 1e-9);
+        assert!((corner.error_percent + 7.).abs() < 1e-9);
+        // The suggested coefficients undo the weak cell's gain
+        let ratios = report.coefficient_ratios.unwrap();
+        assert!((ratios[2] - 1. / 0.9).abs() < 1e-9);
+        assert_eq!(report.suspect_cell, Some(2));
+
+        // Without every corner there is no fit, so the worst corner's dominant cell is used
+        let report = test_with(&[0, 2], gains)
+            .report(&unit_coefficients())
+            .unwrap();
+        assert!(!report.passed);
+        assert_eq!(report.coefficient_ratios, None);
+        assert_eq!(report.suspect_cell, Some(2));
+    }
+
+    #[test]
+    fn the_center_capture_is_the_reference() {
+        let mut test = test_with(&[0, 1, 2, 3], [1.; 4]);
+        test.capture(
+            Placement::Center,
+            EMPTY.map(|empty| empty + WEIGHT / 4. * 1.01),
+        )
+        .unwrap();
+        let report = test.report(&unit_coefficients()).unwrap();
+        assert!((report.reference - 1010.).abs() < 1e-9);
+        assert_eq!(report.center_total, Some(report.reference));
+        assert!(!report.passed);
+    }
+}
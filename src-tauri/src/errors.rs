@@ -23,8 +23,82 @@ pub enum AppError {
     // Anyhow(anyhow::Error),
     #[error("Node Diagnostics Error: {0}")]
     NodeDiagnostics(node_diagnostics::error::Error),
-    #[error("Scale already exists!")]
-    ScaleExists,
+    #[error("Need at least {required} calibration trials, only have {actual}!")]
+    InsufficientTrials { required: usize, actual: usize },
+    #[error("Calibration trials do not determine a unique fit!")]
+    SingularFit,
+    #[error("Calibration trial {index} has {readings} load cell readings, expected 4!")]
+    TrialReadings { index: usize, readings: usize },
+    #[error("No coefficients have been applied yet!")]
+    NoCoefficients,
+    #[error("The scale cannot apply a fitted offset ({0:.3e}), refit without one!")]
+    OffsetNotSupported(f64),
+    #[error("Scale capacity must be positive, got {0}!")]
+    InvalidCapacity(f64),
+    #[error("File Error: {0}")]
+    Io(std::io::Error),
+    #[error("Tauri Error: {0}")]
+    Tauri(tauri::Error),
+    #[error("No calibration session in progress!")]
+    NoSession,
+    #[error("Invalid session ID: {0}")]
+    InvalidSessionId(String),
+    #[error("Session file version {0} is newer than this app supports!")]
+    SessionVersion(u32),
+    #[error("Session belongs to phidget {expected}, but phidget {actual} is connected!")]
+    PhidgetMismatch { expected: i32, actual: i32 },
+    #[error("No trial at index {index}, session has {trials} trials!")]
+    TrialIndex { index: usize, trials: usize },
+    #[error("Load cell {cell} is unstable (std dev {std_dev:.3e}, drift {drift:.3e}/s)!")]
+    UnstableLoadCell { cell: usize, std_dev: f64, drift: f64 },
+    #[error("No calibration procedure in progress!")]
+    NoProcedure,
+    #[error("No procedure step at index {0}!")]
+    StepIndex(usize),
+    #[error("Readings do not match placement for step '{step}' (load cell shares {shares})!")]
+    PlacementMismatch { step: String, shares: String },
+    #[error("Readings for step '{step}' do not match its weight next to step '{previous}' (load per gram off by {deviation:.0}%)!")]
+    LoadMismatch {
+        step: String,
+        previous: String,
+        deviation: f64,
+    },
+    #[error("No verification run in progress!")]
+    NoVerification,
+    #[error("All reference weights have already been verified!")]
+    VerificationComplete,
+    #[error("No corner load test in progress!")]
+    NoCornerTest,
+    #[error("No coefficient history entry at index {0}!")]
+    HistoryIndex(usize),
+    #[error("Not running with simulated hardware!")]
+    NotSimulated,
+    #[error("Motor Error: {0}")]
+    Motor(String),
+    #[error("Hardware manager is not running!")]
+    HardwareUnavailable,
+    #[error("Hardware job '{0}' did not complete!")]
+    JobFailed(String),
+    #[error("Hardware is busy with '{0}'!")]
+    HardwareBusy(String),
+    #[error("Raw capture {0} is already running!")]
+    CaptureRunning(String),
+    #[error("No raw capture running!")]
+    NoCapture,
+    #[error("No recording running!")]
+    NoRecording,
+    #[error("Recording {0} is already running!")]
+    RecordingRunning(String),
+    #[error("Invalid recording ID: {0}")]
+    InvalidRecordingId(String),
+    #[error("Recording version {0} is newer than this app supports!")]
+    RecordingVersion(u32),
+    #[error("Replay reached the end of the recording!")]
+    ReplayFinished,
+    #[error("Cancelled!")]
+    Cancelled,
+    #[error("No job to cancel ({0:?})!")]
+    NoJob(Option<u64>),
     #[error("Other Error: {0}")]
     Other(String)
 }
@@ -48,7 +122,90 @@ impl Debug for AppError {
             AppError::NotImplemented => write!(f, "NotImplemented"),
             // AppError::Anyhow(err) => f.debug_tuple("Anyhow").field(err).finish(),
             AppError::NodeDiagnostics(err) => f.debug_tuple("NodeDiagnostics").field(err).finish(),
-            AppError::ScaleExists => write!(f, "ScaleExists"),
+            AppError::InsufficientTrials { required, actual } => f
+                .debug_struct("InsufficientTrials")
+                .field("required", required)
+                .field("actual", actual)
+                .finish(),
+            AppError::SingularFit => write!(f, "SingularFit"),
+            AppError::TrialReadings { index, readings } => f
+                .debug_struct("TrialReadings")
+                .field("index", index)
+                .field("readings", readings)
+                .finish(),
+            AppError::NoCoefficients => write!(f, "NoCoefficients"),
+            AppError::OffsetNotSupported(offset) => {
+                f.debug_tuple("OffsetNotSupported").field(offset).finish()
+            }
+            AppError::InvalidCapacity(capacity) => {
+                f.debug_tuple("InvalidCapacity").field(capacity).finish()
+            }
+            AppError::Io(err) => f.debug_tuple("Io").field(err).finish(),
+            AppError::Tauri(err) => f.debug_tuple("Tauri").field(err).finish(),
+            AppError::NoSession => write!(f, "NoSession"),
+            AppError::InvalidSessionId(id) => f.debug_tuple("InvalidSessionId").field(id).finish(),
+            AppError::SessionVersion(version) => {
+                f.debug_tuple("SessionVersion").field(version).finish()
+            }
+            AppError::PhidgetMismatch { expected, actual } => f
+                .debug_struct("PhidgetMismatch")
+                .field("expected", expected)
+                .field("actual", actual)
+                .finish(),
+            AppError::TrialIndex { index, trials } => f
+                .debug_struct("TrialIndex")
+                .field("index", index)
+                .field("trials", trials)
+                .finish(),
+            AppError::UnstableLoadCell {
+                cell,
+                std_dev,
+                drift,
+            } => f
+                .debug_struct("UnstableLoadCell")
+                .field("cell", cell)
+                .field("std_dev", std_dev)
+                .field("drift", drift)
+                .finish(),
+            AppError::NoProcedure => write!(f, "NoProcedure"),
+            AppError::StepIndex(index) => f.debug_tuple("StepIndex").field(index).finish(),
+            AppError::PlacementMismatch { step, shares } => f
+                .debug_struct("PlacementMismatch")
+                .field("step", step)
+                .field("shares", shares)
+                .finish(),
+            AppError::LoadMismatch {
+                step,
+                previous,
+                deviation,
+            } => f
+                .debug_struct("LoadMismatch")
+                .field("step", step)
+                .field("previous", previous)
+                .field("deviation", deviation)
+                .finish(),
+            AppError::NoVerification => write!(f, "NoVerification"),
+            AppError::VerificationComplete => write!(f, "VerificationComplete"),
+            AppError::NoCornerTest => write!(f, "NoCornerTest"),
+            AppError::HistoryIndex(index) => f.debug_tuple("HistoryIndex").field(index).finish(),
+            AppError::NotSimulated => write!(f, "NotSimulated"),
+            AppError::Motor(err) => f.debug_tuple("Motor").field(err).finish(),
+            AppError::HardwareUnavailable => write!(f, "HardwareUnavailable"),
+            AppError::JobFailed(name) => f.debug_tuple("JobFailed").field(name).finish(),
+            AppError::HardwareBusy(name) => f.debug_tuple("HardwareBusy").field(name).finish(),
+            AppError::CaptureRunning(id) => f.debug_tuple("CaptureRunning").field(id).finish(),
+            AppError::NoCapture => write!(f, "NoCapture"),
+            AppError::NoRecording => write!(f, "NoRecording"),
+            AppError::RecordingRunning(id) => f.debug_tuple("RecordingRunning").field(id).finish(),
+            AppError::InvalidRecordingId(id) => {
+                f.debug_tuple("InvalidRecordingId").field(id).finish()
+            }
+            AppError::RecordingVersion(version) => {
+                f.debug_tuple("RecordingVersion").field(version).finish()
+            }
+            AppError::ReplayFinished => write!(f, "ReplayFinished"),
+            AppError::Cancelled => write!(f, "Cancelled"),
+            AppError::NoJob(id) => f.debug_tuple("NoJob").field(id).finish(),
             AppError::Other(s) => f.debug_tuple("Other").field(s).finish(),
             // AppError::DispenseTimeout((data, _scale)) => {
             //     // Assuming Data implements Debug.
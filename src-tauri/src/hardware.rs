@@ -0,0 +1,598 @@
+use crate::buffer::{Acquisition, ReadingBuffer};
+use crate::calibration_data::Coefficients;
+use crate::capture::{CaptureInfo, RawCapture};
+use crate::errors::AppError;
+use crate::motor::{Motor, MotorDriver};
+use crate::progress::ProgressReporter;
+use crate::recording::{Recorder, RecordingScale, RecordingSummary};
+use crate::scale::Scale;
+use crate::simulation::Simulation;
+use crate::stream::{Stream, StreamSettings, STREAM_STOPPED_EVENT};
+use crate::study::StudyControl;
+use control_components::controllers::clear_core;
+use libra::scale::ConnectedScale;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::{SocketAddr, TcpStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tauri::{AppHandle, Emitter};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
+
+const DATA_INTERVAL: Duration = Duration::from_millis(40);
+const CLEAR_CORE_ADDRESS: &str = "192.168.1.12:8888";
+const CLEAR_CORE_TIMEOUT: Duration = Duration::from_secs(5);
+// Emitted with the job's info as soon as it is accepted, so a command's caller can cancel it
+pub const JOB_EVENT: &str = "hardware_job";
+
+pub type JobFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, AppError>> + 'a>>;
+type Job =
+    Box<dyn for<'a> FnOnce(&'a mut Hardware) -> Pin<Box<dyn Future<Output = ()> + 'a>> + Send>;
+// Every motor handed to a job, by ID
+type Motors = Arc<Mutex<HashMap<usize, Motor>>>;
+// Answers a queued job's caller with Cancelled, by job ID
+type Aborts = Mutex<HashMap<u64, Box<dyn FnOnce() + Send>>>;
+
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+impl CancelToken {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+// The scale and motors, only ever touched from the hardware thread
+pub struct Hardware {
+    scale: Option<RecordingScale>,
+    clear_core: Option<clear_core::Controller>,
+    simulation: Simulation,
+    app: AppHandle,
+    // The job currently running
+    job: Option<JobInfo>,
+    stream: Option<Stream>,
+    acquisition: Option<Acquisition>,
+    buffer: Arc<Mutex<ReadingBuffer>>,
+    capture: Option<RawCapture>,
+    recorder: Option<Recorder>,
+    motors: Motors,
+}
+impl Hardware {
+    fn new(
+        simulation: Simulation,
+        app: AppHandle,
+        buffer: Arc<Mutex<ReadingBuffer>>,
+        motors: Motors,
+    ) -> Self {
+        Self {
+            scale: None,
+            clear_core: None,
+            simulation,
+            app,
+            job: None,
+            stream: None,
+            acquisition: None,
+            buffer,
+            capture: None,
+            recorder: None,
+            motors,
+        }
+    }
+    pub fn get_cancel_token(&self) -> CancelToken {
+        self.job
+            .as_ref()
+            .map(|job| job.cancel.clone())
+            .unwrap_or_default()
+    }
+    pub fn get_progress_reporter(&self) -> ProgressReporter {
+        let (id, name) = self
+            .job
+            .as_ref()
+            .map(|job| (Some(job.id), job.name.clone()))
+            .unwrap_or_default();
+        ProgressReporter::new(self.app.clone(), id, name)
+    }
+    pub fn connect_scale(&mut self) -> Result<i32, AppError> {
+        if let Some(scale) = self.scale.as_ref() {
+            println!("Already connected!");
+            return Ok(scale.get_phidget_id());
+        }
+        let mut scale: Box<dyn Scale> = match self.simulation.build_scale() {
+            Some(scale) => Box::new(scale),
+            None => Box::new(
+                ConnectedScale::without_id(Duration::from_secs(3)).map_err(AppError::Libra)?,
+            ),
+        };
+        scale.set_data_intervals(DATA_INTERVAL)?;
+        let phidget_id = scale.get_phidget_id();
+        self.scale.replace(RecordingScale::new(scale));
+        // Readings from a previous scale would be mixed in otherwise
+        self.buffer.lock().unwrap().clear();
+        self.acquisition.replace(Acquisition::new(DATA_INTERVAL));
+        Ok(phidget_id)
+    }
+    pub fn drop_scale(&mut self) -> Result<(), AppError> {
+        self.stream.take();
+        self.acquisition.take();
+        if self.capture.is_some() {
+            if let Err(e) = self.stop_capture() {
+                log::warn!("Failed to finish raw capture: {e}");
+            }
+        }
+        if self.recorder.is_some() {
+            if let Err(e) = self.stop_recording() {
+                log::warn!("Failed to save recording: {e}");
+            }
+        }
+        self.scale.take().map(|_| ()).ok_or(AppError::NoScale)
+    }
+    pub fn get_scale(&mut self) -> Result<&mut (dyn Scale + 'static), AppError> {
+        self.scale
+            .as_mut()
+            .map(|scale| scale as &mut dyn Scale)
+            .ok_or(AppError::NoScale)
+    }
+    pub fn set_data_intervals(&mut self, sample_period: Duration) -> Result<(), AppError> {
+        self.get_scale()?.set_data_intervals(sample_period)?;
+        if let Some(acquisition) = self.acquisition.as_mut() {
+            acquisition.set_period(sample_period);
+        }
+        Ok(())
+    }
+    pub fn update_coefficients(&mut self, coefficients: [f64; 4]) -> Result<(), AppError> {
+        let scale = self
+            .scale
+            .take()
+            .ok_or(AppError::NoScale)?
+            .with_coefficients(coefficients);
+        self.scale.replace(scale);
+        Ok(())
+    }
+    pub fn get_coefficients(&self) -> Option<[f64; 4]> {
+        self.scale
+            .as_ref()
+            .and_then(RecordingScale::get_coefficients)
+    }
+    pub fn start_stream(&mut self, settings: StreamSettings) -> Result<(), AppError> {
+        self.get_scale()?;
+        self.stream.replace(Stream::new(settings)?);
+        Ok(())
+    }
+    pub fn stop_stream(&mut self) {
+        self.stream.take();
+    }
+    // Records every background reading until stopped, the scale stays connected throughout
+    pub fn start_capture(&mut self) -> Result<CaptureInfo, AppError> {
+        let phidget_id = self.get_scale()?.get_phidget_id();
+        if let Some(capture) = self.capture.as_ref() {
+            return Err(AppError::CaptureRunning(capture.get_info().get_id().into()));
+        }
+        let sample_period = self
+            .acquisition
+            .as_ref()
+            .map(Acquisition::get_period)
+            .unwrap_or(DATA_INTERVAL);
+        let coefficients = self.get_coefficients();
+        let capture = RawCapture::new(&self.app, phidget_id, sample_period, coefficients)?;
+        let info = capture.get_info().clone();
+        self.capture.replace(capture);
+        Ok(info)
+    }
+    pub fn stop_capture(&mut self) -> Result<CaptureInfo, AppError> {
+        self.capture.take().ok_or(AppError::NoCapture)?.finish()
+    }
+    // Samples read by requests and motor commands are recorded until stopped
+    pub fn start_recording(&mut self) -> Result<String, AppError> {
+        let phidget_id = self.get_scale()?.get_phidget_id();
+        if let Some(recorder) = self.recorder.as_ref() {
+            return Err(AppError::RecordingRunning(recorder.get_id()));
+        }
+        let recorder = Recorder::new(phidget_id, self.get_coefficients());
+        if let Some(scale) = self.scale.as_mut() {
+            scale.set_recorder(Some(recorder.clone()));
+        }
+        let id = recorder.get_id();
+        self.recorder.replace(recorder);
+        Ok(id)
+    }
+    pub fn stop_recording(&mut self) -> Result<RecordingSummary, AppError> {
+        let recorder = self.recorder.take().ok_or(AppError::NoRecording)?;
+        if let Some(scale) = self.scale.as_mut() {
+            scale.set_recorder(None);
+        }
+        recorder.finish(&self.app)
+    }
+    // When the hardware thread next has background sampling to do
+    fn get_idle_deadline(&self) -> Option<Instant> {
+        let stream = self.stream.as_ref().map(Stream::get_deadline);
+        let acquisition = self.acquisition.as_ref().map(Acquisition::get_deadline);
+        match (stream, acquisition) {
+            (Some(stream), Some(acquisition)) => Some(stream.min(acquisition)),
+            (stream, acquisition) => stream.or(acquisition),
+        }
+    }
+    // A failing stream is stopped, background acquisition retries on its own
+    fn sample_idle(&mut self) {
+        let Some(scale) = self.scale.as_mut() else {
+            return;
+        };
+        let now = Instant::now();
+        if let Some(stream) = self.stream.as_mut() {
+            if stream.get_deadline() <= now {
+                if let Err(e) = stream.sample(scale, &self.app) {
+                    log::warn!("Stopping scale stream: {e}");
+                    self.stream.take();
+                    if let Err(e) = self.app.emit(STREAM_STOPPED_EVENT, e.to_string()) {
+                        log::warn!("Failed to announce the stopped stream: {e}");
+                    }
+                }
+            }
+        }
+        if let Some(acquisition) = self.acquisition.as_mut() {
+            if acquisition.get_deadline() <= now {
+                if let Ok(cells) = acquisition.sample(scale) {
+                    self.buffer.lock().unwrap().push(cells);
+                    if let Some(Err(e)) = self.capture.as_mut().map(|capture| capture.record(cells))
+                    {
+                        log::warn!("Stopping raw capture: {e}");
+                        self.capture.take();
+                    }
+                }
+            }
+        }
+    }
+    pub fn get_motor(&mut self, id: usize) -> Result<Motor, AppError> {
+        let driver = self.get_motor_driver(id)?;
+        let mut motor = Motor::new(driver, self.recorder.clone());
+        let mut motors = self.motors.lock().unwrap();
+        if let Some(previous) = motors.get(&id) {
+            motor = motor.share_estimate(previous);
+        }
+        motors.insert(id, motor.clone());
+        Ok(motor)
+    }
+    fn get_motor_driver(&mut self, id: usize) -> Result<MotorDriver, AppError> {
+        if self.simulation.simulates_motors() {
+            return self
+                .simulation
+                .get_simulated_motor(id)
+                .map(MotorDriver::Simulated);
+        }
+        if self.clear_core.is_none() {
+            // Returns as soon as the ClearCore accepts connections, instead of waiting out
+            // a fixed delay, and fails rather than handing out motors nothing will move
+            let address: SocketAddr = CLEAR_CORE_ADDRESS
+                .parse()
+                .map_err(|e| AppError::Other(format!("Invalid ClearCore address: {e}")))?;
+            TcpStream::connect_timeout(&address, CLEAR_CORE_TIMEOUT).map_err(|e| {
+                log::warn!("ClearCore at {address} is not reachable: {e}");
+                AppError::HardwareUnavailable
+            })?;
+            let (controller, controller_client) = clear_core::Controller::with_client(
+                CLEAR_CORE_ADDRESS,
+                &[
+                    clear_core::MotorBuilder { id: 0, scale: 800 },
+                    clear_core::MotorBuilder { id: 1, scale: 800 },
+                ],
+            );
+            tauri::async_runtime::spawn(async move {
+                if controller_client.await.is_err() {
+                    println!("No motor/io controller connected...");
+                }
+            });
+            let motor = controller.get_motor(id);
+            self.clear_core.replace(controller);
+            Ok(MotorDriver::ClearCore(motor))
+        } else {
+            Ok(MotorDriver::ClearCore(
+                self.clear_core.clone().unwrap().get_motor(id),
+            ))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct JobInfo {
+    id: u64,
+    name: String,
+    queued: Duration,
+    started: Option<Duration>,
+    cancelled: bool,
+    #[serde(skip)]
+    cancel: CancelToken,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct HardwareStatus {
+    running: Option<JobInfo>,
+    queued: Vec<JobInfo>,
+}
+impl HardwareStatus {
+    fn start(&mut self, id: u64) -> Option<JobInfo> {
+        let index = self.queued.iter().position(|job| job.id == id)?;
+        let mut job = self.queued.remove(index);
+        job.started
+            .replace(SystemTime::now().duration_since(UNIX_EPOCH).unwrap());
+        self.running.replace(job.clone());
+        Some(job)
+    }
+    // Without an ID the running job is cancelled. A queued job is taken off the queue so it
+    // never starts. Returns whether the cancelled job was running, or None without a job to
+    // cancel.
+    fn cancel(&mut self, id: Option<u64>) -> Option<bool> {
+        if let Some(job) = self
+            .running
+            .as_mut()
+            .filter(|job| id.is_none_or(|id| job.id == id))
+        {
+            job.cancel.cancel();
+            job.cancelled = true;
+            return Some(true);
+        }
+        let index = self.queued.iter().position(|job| Some(job.id) == id)?;
+        self.queued.remove(index);
+        Some(false)
+    }
+    fn finish(&mut self) {
+        self.running.take();
+    }
+}
+
+// Owns the hardware on a dedicated thread and runs jobs against it one at a time, so
+// commands only wait for the hardware they need and never for a lock held by another
+// acquisition
+pub struct HardwareManager {
+    sender: mpsc::UnboundedSender<(u64, Job)>,
+    app: AppHandle,
+    motors: Motors,
+    status: Arc<Mutex<HardwareStatus>>,
+    aborts: Aborts,
+    next_id: AtomicU64,
+    simulation: Simulation,
+    buffer: Arc<Mutex<ReadingBuffer>>,
+    study_control: StudyControl,
+}
+impl HardwareManager {
+    pub fn new(simulation: Simulation, app: AppHandle) -> Self {
+        let (sender, mut receiver) = mpsc::unbounded_channel::<(u64, Job)>();
+        let status = Arc::new(Mutex::new(HardwareStatus::default()));
+        let buffer = Arc::new(Mutex::new(ReadingBuffer::default()));
+        let motors = Motors::default();
+        let mut hardware =
+            Hardware::new(simulation.clone(), app.clone(), buffer.clone(), motors.clone());
+        let job_status = status.clone();
+        thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .expect("Failed to start hardware runtime");
+            loop {
+                // The scale is sampled in the background whenever no job arrives in time
+                let received = match hardware.get_idle_deadline() {
+                    Some(deadline) => {
+                        match runtime.block_on(tokio::time::timeout_at(deadline, receiver.recv())) {
+                            Ok(received) => received,
+                            Err(_) => {
+                                hardware.sample_idle();
+                                continue;
+                            }
+                        }
+                    }
+                    None => receiver.blocking_recv(),
+                };
+                let Some((id, job)) = received else {
+                    break;
+                };
+                // Cancelled while still queued
+                let Some(info) = job_status.lock().unwrap().start(id) else {
+                    continue;
+                };
+                hardware.job.replace(info);
+                // A panicking job only fails its own command, the hardware stays usable
+                if panic::catch_unwind(AssertUnwindSafe(|| runtime.block_on(job(&mut hardware))))
+                    .is_err()
+                {
+                    log::error!("Hardware job {id} panicked");
+                }
+                job_status.lock().unwrap().finish();
+            }
+        });
+        Self {
+            sender,
+            app,
+            motors,
+            status,
+            aborts: Aborts::default(),
+            next_id: AtomicU64::new(0),
+            simulation,
+            buffer,
+            study_control: StudyControl::default(),
+        }
+    }
+    pub fn get_status(&self) -> HardwareStatus {
+        self.status.lock().unwrap().clone()
+    }
+    // A queued job never touched the hardware, so its caller is just answered. A move keeps
+    // going after the job that started it returns, so cancelling a running job or a study
+    // also stops every motor.
+    pub async fn cancel(&self, id: Option<u64>) -> Result<(), AppError> {
+        let cancelled = self.status.lock().unwrap().cancel(id);
+        match cancelled {
+            Some(false) => {
+                if let Some(abort) = id.and_then(|id| self.aborts.lock().unwrap().remove(&id)) {
+                    abort();
+                }
+                return Ok(());
+            }
+            Some(true) => {}
+            // A study waiting between dispenses holds no job
+            None if self.study_control.cancel() => {}
+            None => return Err(AppError::NoJob(id)),
+        }
+        let motors: Vec<Motor> = self.motors.lock().unwrap().values().cloned().collect();
+        for motor in motors {
+            motor.abrupt_stop().await;
+        }
+        Ok(())
+    }
+    // Readings collected in the background, available without waiting on the hardware
+    pub fn get_buffer(&self) -> &Mutex<ReadingBuffer> {
+        &self.buffer
+    }
+    pub fn get_simulation(&self) -> &Simulation {
+        &self.simulation
+    }
+    pub fn get_study_control(&self) -> &StudyControl {
+        &self.study_control
+    }
+    // Queues a job behind the others and waits for its result. A job cancelled while queued
+    // never runs and returns Cancelled straight away. Jobs that can take a while should check
+    // the hardware's cancel token and stop early with what they have.
+    pub async fn run<T, F>(&self, name: &str, job: F) -> Result<T, AppError>
+    where
+        T: Send + 'static,
+        F: for<'a> FnOnce(&'a mut Hardware) -> JobFuture<'a, T> + Send + 'static,
+    {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (reply, response) = oneshot::channel();
+        // Whichever of the job and its cancellation takes the reply first answers the caller
+        let reply = Arc::new(Mutex::new(Some(reply)));
+        let job_reply = reply.clone();
+        let job: Job = Box::new(move |hardware| {
+            Box::pin(async move {
+                let Some(reply) = job_reply.lock().unwrap().take() else {
+                    return;
+                };
+                let _ = reply.send(job(hardware).await);
+            })
+        });
+        let abort = move || {
+            if let Some(reply) = reply.lock().unwrap().take() {
+                let _ = reply.send(Err(AppError::Cancelled));
+            }
+        };
+        {
+            let mut status = self.status.lock().unwrap();
+            let info = JobInfo {
+                id,
+                name: name.into(),
+                queued: SystemTime::now().duration_since(UNIX_EPOCH).unwrap(),
+                started: None,
+                cancelled: false,
+                cancel: CancelToken::default(),
+            };
+            if let Err(e) = self.app.emit(JOB_EVENT, &info) {
+                log::warn!("Failed to announce job {id}: {e}");
+            }
+            status.queued.push(info);
+            self.aborts.lock().unwrap().insert(id, Box::new(abort));
+        }
+        if self.sender.send((id, job)).is_err() {
+            self.status
+                .lock()
+                .unwrap()
+                .queued
+                .retain(|job| job.id != id);
+            self.aborts.lock().unwrap().remove(&id);
+            return Err(AppError::HardwareUnavailable);
+        }
+        let response = response.await;
+        self.aborts.lock().unwrap().remove(&id);
+        response.map_err(|_| AppError::JobFailed(name.into()))?
+    }
+    pub async fn run_sync<T, F>(&self, name: &str, job: F) -> Result<T, AppError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Hardware) -> Result<T, AppError> + Send + 'static,
+    {
+        self.run(name, move |hardware| Box::pin(async move { job(hardware) }))
+            .await
+    }
+    pub async fn apply_coefficients(&self, coefficients: &Coefficients) -> Result<(), AppError> {
+        // The scale only takes the four cell coefficients, so an offset fit would be applied
+        // without its offset
+        if let Some(offset) = coefficients.get_offset().filter(|offset| *offset != 0.) {
+            return Err(AppError::OffsetNotSupported(offset));
+        }
+        let coefficients = coefficients.get_coefficients();
+        self.run_sync("update_coefficients", move |hardware| {
+            hardware.update_coefficients(coefficients)
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job(id: u64) -> JobInfo {
+        JobInfo {
+            id,
+            name: format!("job {id}"),
+            queued: Duration::ZERO,
+            started: None,
+            cancelled: false,
+            cancel: CancelToken::default(),
+        }
+    }
+
+    fn status() -> HardwareStatus {
+        HardwareStatus {
+            running: Some(job(1)),
+            queued: vec![job(2)],
+        }
+    }
+
+    #[test]
+    fn cancel_without_id_cancels_the_running_job() {
+        let mut status = status();
+        assert_eq!(status.cancel(None), Some(true));
+        let running = status.running.as_ref().unwrap();
+        assert!(running.cancelled && running.cancel.is_cancelled());
+        assert!(!status.queued[0].cancelled);
+    }
+
+    #[test]
+    fn cancel_by_id_takes_queued_jobs_off_the_queue() {
+        let mut status = status();
+        status.queued.push(job(3));
+        assert_eq!(status.cancel(Some(2)), Some(false));
+        assert_eq!(status.queued.len(), 1);
+        assert!(!status.running.as_ref().unwrap().cancelled);
+        // The hardware thread skips it when its turn comes
+        status.finish();
+        assert!(status.start(2).is_none());
+        assert_eq!(status.start(3).unwrap().id, 3);
+    }
+
+    #[test]
+    fn jobs_start_in_the_order_they_were_queued() {
+        let mut status = HardwareStatus::default();
+        status.queued.extend([job(1), job(2), job(3)]);
+        for id in 1..=3 {
+            let started = status.start(id).unwrap();
+            assert!(started.started.is_some());
+            assert_eq!(status.running.as_ref().unwrap().id, id);
+            let queued: Vec<u64> = status.queued.iter().map(|job| job.id).collect();
+            assert_eq!(queued, (id + 1..=3).collect::<Vec<_>>());
+            status.finish();
+        }
+    }
+
+    #[test]
+    fn cancel_of_a_finished_job_finds_nothing() {
+        let mut status = status();
+        assert_eq!(status.cancel(Some(7)), None);
+        status.finish();
+        assert_eq!(status.cancel(None), None);
+    }
+}
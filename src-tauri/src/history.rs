@@ -0,0 +1,256 @@
+use crate::calibration_data::Coefficients;
+use crate::errors::AppError;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tauri::Manager;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub enum CoefficientSource {
+    Connected,
+    Cloud,
+    LocalSolver,
+    Manual,
+    File,
+    Rollback,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CoefficientRecord {
+    index: usize,
+    coefficients: Coefficients,
+    source: CoefficientSource,
+    timestamp: Duration,
+    phidget_id: i32,
+    session_id: Option<String>,
+}
+impl CoefficientRecord {
+    pub fn get_coefficients(&self) -> &Coefficients {
+        &self.coefficients
+    }
+    pub fn get_phidget_id(&self) -> i32 {
+        self.phidget_id
+    }
+}
+
+#[derive(Default)]
+pub struct CoefficientHistory {
+    path: Option<PathBuf>,
+    records: Vec<CoefficientRecord>,
+}
+impl CoefficientHistory {
+    pub fn load(app: &tauri::AppHandle) -> Result<Self, AppError> {
+        let dir = app.path().app_data_dir().map_err(AppError::Tauri)?;
+        fs::create_dir_all(&dir).map_err(AppError::Io)?;
+        Self::load_from(dir.join("coefficient_history.json"))
+    }
+    // A corrupt history is moved aside rather than keeping the app from starting
+    fn load_from(path: PathBuf) -> Result<Self, AppError> {
+        let records = match fs::read_to_string(&path) {
+            Ok(contents) => match serde_json::from_str(&contents) {
+                Ok(records) => records,
+                Err(e) => {
+                    let corrupt =
+                        path.with_extension(format!("json.corrupt-{}", now().as_millis()));
+                    log::warn!(
+                        "Coefficient history is unreadable ({e}), moving it to {} and starting over",
+                        corrupt.display()
+                    );
+                    fs::rename(&path, corrupt).map_err(AppError::Io)?;
+                    Vec::new()
+                }
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(AppError::Io(e)),
+        };
+        Ok(Self {
+            path: Some(path),
+            records,
+        })
+    }
+    pub fn record(
+        &mut self,
+        coefficients: Coefficients,
+        source: CoefficientSource,
+        phidget_id: i32,
+        session_id: Option<String>,
+    ) -> Result<(), AppError> {
+        self.records.push(CoefficientRecord {
+            index: self.records.len(),
+            coefficients,
+            source,
+            timestamp: now(),
+            phidget_id,
+            session_id,
+        });
+        self.save()
+    }
+    // Records what a scale connected with, so there is something to roll back to before
+    // its first calibration. Reconnecting with the coefficients last recorded adds nothing.
+    pub fn record_connected(
+        &mut self,
+        coefficients: Coefficients,
+        phidget_id: i32,
+    ) -> Result<(), AppError> {
+        let latest = self
+            .records
+            .iter()
+            .rev()
+            .find(|record| record.phidget_id == phidget_id);
+        if latest.is_some_and(|record| {
+            record.coefficients.get_coefficients() == coefficients.get_coefficients()
+                && record.coefficients.get_offset().is_none()
+        }) {
+            return Ok(());
+        }
+        self.record(coefficients, CoefficientSource::Connected, phidget_id, None)
+    }
+    pub fn get_records(&self, phidget_id: Option<i32>) -> Vec<CoefficientRecord> {
+        self.records
+            .iter()
+            .filter(|record| phidget_id.is_none_or(|id| record.phidget_id == id))
+            .cloned()
+            .collect()
+    }
+    pub fn get_record(&self, index: usize) -> Result<&CoefficientRecord, AppError> {
+        self.records.get(index).ok_or(AppError::HistoryIndex(index))
+    }
+    fn save(&self) -> Result<(), AppError> {
+        let Some(path) = self.path.as_ref() else {
+            return Ok(());
+        };
+        let contents = serde_json::to_string_pretty(&self.records).map_err(AppError::Serde)?;
+        let tmp = path.with_extension("json.tmp");
+        fs::write(&tmp, contents).map_err(AppError::Io)?;
+        fs::rename(tmp, path).map_err(AppError::Io)
+    }
+}
+
+fn now() -> Duration {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn history_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "caldo-history-{name}-{}-{}",
+            std::process::id(),
+            now().as_nanos()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        dir.join("coefficient_history.json")
+    }
+    fn coefficients(value: f64) -> Coefficients {
+        Coefficients::new([value; 4], None)
+    }
+
+    #[test]
+    fn starts_empty_without_a_file() {
+        let path = history_path("missing");
+        let history = CoefficientHistory::load_from(path.clone()).unwrap();
+        assert!(history.get_records(None).is_empty());
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn records_survive_a_reload() {
+        let path = history_path("reload");
+        let mut history = CoefficientHistory::load_from(path.clone()).unwrap();
+        history
+            .record(
+                coefficients(1.),
+                CoefficientSource::Cloud,
+                7,
+                Some("7-1".into()),
+            )
+            .unwrap();
+        history
+            .record(coefficients(2.), CoefficientSource::Manual, 8, None)
+            .unwrap();
+        let history = CoefficientHistory::load_from(path.clone()).unwrap();
+        let records = history.get_records(None);
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1].index, 1);
+        assert_eq!(records[0].source, CoefficientSource::Cloud);
+        assert_eq!(records[0].session_id.as_deref(), Some("7-1"));
+        let records = history.get_records(Some(8));
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].get_coefficients().get_coefficients(), [2.; 4]);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn moves_a_corrupt_history_aside() {
+        let path = history_path("corrupt");
+        fs::write(&path, "[{ truncated").unwrap();
+        let mut history = CoefficientHistory::load_from(path.clone()).unwrap();
+        assert!(history.get_records(None).is_empty());
+        let moved: Vec<String> = fs::read_dir(path.parent().unwrap())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        assert_eq!(moved.len(), 1);
+        assert!(moved[0].starts_with("coefficient_history.json.corrupt-"));
+        // The fresh history saves over the old name
+        history
+            .record(coefficients(1.), CoefficientSource::Manual, 7, None)
+            .unwrap();
+        assert_eq!(
+            CoefficientHistory::load_from(path.clone())
+                .unwrap()
+                .get_records(None)
+                .len(),
+            1
+        );
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn records_connected_coefficients_once() {
+        let mut history = CoefficientHistory::default();
+        history.record_connected(coefficients(1.), 7).unwrap();
+        history.record_connected(coefficients(1.), 7).unwrap();
+        history.record_connected(coefficients(1.), 8).unwrap();
+        assert_eq!(history.get_records(None).len(), 2);
+        // Coefficients changed while the app was closed are recorded again
+        history
+            .record(coefficients(2.), CoefficientSource::LocalSolver, 7, None)
+            .unwrap();
+        history.record_connected(coefficients(2.), 7).unwrap();
+        history.record_connected(coefficients(3.), 7).unwrap();
+        let records = history.get_records(Some(7));
+        let sources: Vec<CoefficientSource> = records.iter().map(|record| record.source).collect();
+        assert_eq!(
+            sources,
+            [
+                CoefficientSource::Connected,
+                CoefficientSource::LocalSolver,
+                CoefficientSource::Connected
+            ]
+        );
+    }
+
+    #[test]
+    fn rolls_back_to_the_connected_coefficients() {
+        let mut history = CoefficientHistory::default();
+        history.record_connected(coefficients(1.), 7).unwrap();
+        history
+            .record(coefficients(2.), CoefficientSource::Cloud, 7, None)
+            .unwrap();
+        let original = history.get_record(0).unwrap().get_coefficients().clone();
+        history
+            .record(original, CoefficientSource::Rollback, 7, None)
+            .unwrap();
+        let records = history.get_records(Some(7));
+        assert_eq!(records[2].source, CoefficientSource::Rollback);
+        assert_eq!(records[2].get_coefficients().get_coefficients(), [1.; 4]);
+        assert!(matches!(
+            history.get_record(3),
+            Err(AppError::HistoryIndex(3))
+        ));
+    }
+}
@@ -1,173 +1,734 @@
 use crate::backend::Backend;
-use crate::calibration_data::CalibrationTrial;
-use crate::data::{DataRequest, LoadCellDataRequest};
-// use crate::dispenser::{DispenseSettings, Dispenser};
-use node_diagnostics::dispenser::{DispenseSettings, DispenseOutcome};
+use crate::buffer::{BufferStatus, BufferWindow};
+use crate::calibration_data::{CalibrationTrial, Coefficients, TrialEntry, TrialSampling};
+use crate::capture::CaptureInfo;
+use crate::data::{DataRequest, LoadCellDataRequest, TrialData};
+use crate::dispenser::DispenseSettings;
+use crate::eccentricity::{CornerLoadReport, CornerLoadTest};
 use crate::errors::AppError;
+use crate::hardware::{HardwareManager, HardwareStatus};
+use crate::history::{CoefficientHistory, CoefficientRecord, CoefficientSource};
+use crate::motor::MotorStatus;
+use crate::procedure::{CalibrationPlan, Placement, ProcedureStatus};
+use crate::progress::ProgressReporter;
+use crate::recording::{Recording, RecordingSummary, ReplayResult};
+use crate::report::FitReport;
+use crate::session::{CalibrationSession, SessionStore, SessionSummary};
+use crate::simulation::Simulation;
+use crate::solver::{LocalSolver, Solver};
+use crate::stability::StabilityCriteria;
 use crate::state::AppData;
+use crate::stream::StreamSettings;
+use crate::study::{DispenseStudy, StudySettings};
+use crate::verification::{Tolerance, VerificationRun};
 use node_diagnostics::data::Data;
 use std::sync::Mutex;
 use std::time::Duration;
-use tauri::State;
+use tauri::{Manager, State};
 
 mod backend;
+mod buffer;
 mod calibration_data;
+mod capture;
 mod data;
 mod dispenser;
+mod eccentricity;
 mod errors;
+mod hardware;
+mod history;
+mod motor;
+mod procedure;
+mod progress;
+mod recording;
+mod report;
+mod scale;
+mod session;
+mod simulation;
+mod solver;
+mod stability;
 mod state;
+mod strategy;
+mod stream;
+mod study;
+mod statistics;
+mod verification;
 
 #[tauri::command]
 fn check_app_data(state: tauri::State<'_, Mutex<AppData>>) -> String {
     format!("{:}", state.lock().unwrap())
 }
 
+#[tauri::command]
+fn hardware_status(hardware: tauri::State<'_, HardwareManager>) -> HardwareStatus {
+    hardware.get_status()
+}
+
+#[tauri::command]
+async fn cancel_job(
+    hardware: tauri::State<'_, HardwareManager>,
+    id: Option<u64>,
+) -> Result<(), AppError> {
+    hardware.cancel(id).await
+}
+
 #[tauri::command(async)]
-fn connect_scale(state: tauri::State<'_, Mutex<AppData>>) -> Result<String, AppError> {
-    match state.lock().unwrap().connect_scale() {
-        Ok(_) => Ok("Scale Connected!".into()),
-        Err(e) => Err(e),
+async fn connect_scale(
+    state: tauri::State<'_, Mutex<AppData>>,
+    hardware: tauri::State<'_, HardwareManager>,
+) -> Result<String, AppError> {
+    let (phidget_id, coefficients) = hardware
+        .run_sync("connect_scale", |hardware| {
+            Ok((hardware.connect_scale()?, hardware.get_coefficients()))
+        })
+        .await?;
+    let mut state = state.lock().unwrap();
+    state.set_connected_scale(phidget_id);
+    if let Some(coefficients) = coefficients {
+        state.record_connected_coefficients(phidget_id, coefficients);
     }
+    Ok("Scale Connected!".into())
 }
 
 #[tauri::command(async)]
-fn add_trial(
+async fn add_trial(
     state: tauri::State<'_, Mutex<AppData>>,
+    hardware: tauri::State<'_, HardwareManager>,
     samples: usize,
     weight: f64,
     sample_period: Duration,
 ) -> Result<String, AppError> {
-    let new_trial = CalibrationTrial::new(state.clone(), samples, weight, sample_period)?;
+    let stability = { state.lock().unwrap().get_stability_criteria() };
+    let new_trial =
+        CalibrationTrial::capture(&hardware, stability, samples, weight, sample_period).await?;
     let trial = state.lock().unwrap().add_calibration_trial(new_trial)?;
     serde_json::to_string(&trial).map_err(AppError::Serde)
 }
 
+#[tauri::command]
+fn set_stability_criteria(
+    state: tauri::State<'_, Mutex<AppData>>,
+    stability_criteria: Option<StabilityCriteria>,
+) {
+    state
+        .lock()
+        .unwrap()
+        .set_stability_criteria(stability_criteria);
+}
+
+#[tauri::command]
+fn list_trials(state: tauri::State<'_, Mutex<AppData>>) -> Result<Vec<TrialEntry>, AppError> {
+    state.lock().unwrap().get_calibration_trials()
+}
+
+#[tauri::command]
+fn delete_trial(
+    state: tauri::State<'_, Mutex<AppData>>,
+    index: usize,
+) -> Result<CalibrationTrial, AppError> {
+    state.lock().unwrap().remove_calibration_trial(index)
+}
+
 #[tauri::command(async)]
-async fn calibrate(state: tauri::State<'_, Mutex<AppData>>) -> Result<String, AppError> {
-    Backend::calibrate(state).await
+async fn retake_trial(
+    state: tauri::State<'_, Mutex<AppData>>,
+    hardware: tauri::State<'_, HardwareManager>,
+    index: usize,
+    samples: usize,
+    weight: Option<f64>,
+    sample_period: Duration,
+) -> Result<CalibrationTrial, AppError> {
+    let (previous, stability) = {
+        let state = state.lock().unwrap();
+        (state.get_calibration_trial(index)?, state.get_stability_criteria())
+    };
+    let weight = weight.unwrap_or(previous.get_weight());
+    let mut new_trial =
+        CalibrationTrial::capture(&hardware, stability, samples, weight, sample_period).await?;
+    new_trial.set_note(previous.get_note().map(String::from));
+    state
+        .lock()
+        .unwrap()
+        .replace_calibration_trial(index, new_trial)
+}
+
+#[tauri::command]
+fn annotate_trial(
+    state: tauri::State<'_, Mutex<AppData>>,
+    index: usize,
+    note: Option<String>,
+) -> Result<CalibrationTrial, AppError> {
+    state.lock().unwrap().annotate_calibration_trial(index, note)
+}
+
+#[tauri::command]
+fn start_procedure(
+    state: tauri::State<'_, Mutex<AppData>>,
+    plan: Option<CalibrationPlan>,
+) -> Result<ProcedureStatus, AppError> {
+    state
+        .lock()
+        .unwrap()
+        .start_procedure(plan.unwrap_or_default())
+}
+
+#[tauri::command]
+fn procedure_status(state: tauri::State<'_, Mutex<AppData>>) -> Result<ProcedureStatus, AppError> {
+    state.lock().unwrap().get_procedure_status()
 }
 
 #[tauri::command(async)]
-async fn get_coefficients(state: tauri::State<'_, Mutex<AppData>>) -> Result<String, AppError> {
-    Backend::get_coefficients(state).await
+async fn capture_step(
+    state: tauri::State<'_, Mutex<AppData>>,
+    hardware: tauri::State<'_, HardwareManager>,
+    step: usize,
+    samples: usize,
+    sample_period: Duration,
+) -> Result<ProcedureStatus, AppError> {
+    let (weight, stability) = {
+        let state = state.lock().unwrap();
+        (state.get_procedure_step(step)?.get_weight(), state.get_stability_criteria())
+    };
+    let trial =
+        CalibrationTrial::capture(&hardware, stability, samples, weight, sample_period).await?;
+    state.lock().unwrap().record_procedure_step(step, trial)
 }
 
 #[tauri::command(async)]
-fn plot(
+async fn calibrate(
+    state: tauri::State<'_, Mutex<AppData>>,
+    hardware: tauri::State<'_, HardwareManager>,
+) -> Result<String, AppError> {
+    let solver = { state.lock().unwrap().get_solver() };
+    match solver {
+        Solver::Cloud => Backend::calibrate(state).await,
+        Solver::Local => LocalSolver::new(false).calibrate(state, hardware).await,
+    }
+}
+
+#[tauri::command]
+fn fit_report(
+    state: tauri::State<'_, Mutex<AppData>>,
+    capacity: f64,
+) -> Result<FitReport, AppError> {
+    let state = state.lock().unwrap();
+    let calibration_data = state.get_calibration_data().ok_or(AppError::NoSession)?;
+    let coefficients = state.get_coefficients().ok_or(AppError::NoCoefficients)?;
+    FitReport::new(&calibration_data, &coefficients, capacity)
+}
+
+#[tauri::command]
+fn set_solver(state: tauri::State<'_, Mutex<AppData>>, solver: Solver) -> Result<(), AppError> {
+    state.lock().unwrap().set_solver(solver)
+}
+
+#[tauri::command]
+fn solve_locally(
+    state: tauri::State<'_, Mutex<AppData>>,
+    fit_offset: bool,
+) -> Result<Coefficients, AppError> {
+    let calibration_data = state
+        .lock()
+        .unwrap()
+        .get_calibration_data()
+        .ok_or(AppError::NoSession)?;
+    LocalSolver::new(fit_offset).solve(&calibration_data)
+}
+
+#[tauri::command]
+fn start_verification(
     state: State<'_, Mutex<AppData>>,
-    data_request: DataRequest,
-) -> Result<Data, AppError> {
-    let mut state = state.lock().unwrap();
-    let scale = state.get_mut_scale_ref().ok_or(AppError::NoScale)?;
-    data_request.conduct(scale)
+    references: Vec<f64>,
+    tolerance: Tolerance,
+) -> Result<VerificationRun, AppError> {
+    let verification = VerificationRun::new(references, tolerance)?;
+    state.lock().unwrap().start_verification(verification)
+}
+#[tauri::command]
+fn verification_status(state: State<'_, Mutex<AppData>>) -> Result<VerificationRun, AppError> {
+    state.lock().unwrap().get_verification()
 }
 #[tauri::command(async)]
-fn plot_lc(state: State<'_, Mutex<AppData>>, data_request: LoadCellDataRequest) -> Result<[Data; 4], AppError> {
+async fn verify_next(
+    state: State<'_, Mutex<AppData>>,
+    hardware: State<'_, HardwareManager>,
+    samples: usize,
+    sample_period: Duration,
+) -> Result<VerificationRun, AppError> {
+    if samples == 0 {
+        return Err(AppError::ZeroSamples);
+    }
+    state
+        .lock()
+        .unwrap()
+        .get_verification()?
+        .get_next_reference()
+        .ok_or(AppError::VerificationComplete)?;
+    let measured = hardware
+        .run_sync("verify_next", move |hardware| {
+            hardware
+                .get_scale()?
+                .get_median_weight(samples, sample_period)
+        })
+        .await?;
     let mut state = state.lock().unwrap();
-    let scale = state.get_mut_scale_ref().ok_or(AppError::NoScale)?;
-    let data = data_request.conduct(scale)?;
-    Ok(data)
+    state.record_verification(measured)?;
+    state.get_verification()
+}
+
+#[tauri::command]
+fn start_corner_test(state: State<'_, Mutex<AppData>>, weight: f64, tolerance: f64) {
+    state
+        .lock()
+        .unwrap()
+        .start_corner_test(CornerLoadTest::new(weight, tolerance));
 }
 #[tauri::command(async)]
-fn set_phidget_interval(
-    state: tauri::State<'_, Mutex<AppData>>,
+async fn capture_corner(
+    state: State<'_, Mutex<AppData>>,
+    hardware: State<'_, HardwareManager>,
+    position: Placement,
+    samples: usize,
     sample_period: Duration,
 ) -> Result<(), AppError> {
+    if samples == 0 {
+        return Err(AppError::ZeroSamples);
+    }
+    let readings = hardware
+        .run_sync("capture_corner", move |hardware| {
+            let cancel = hardware.get_cancel_token();
+            let mut progress = hardware.get_progress_reporter();
+            Ok(TrialSampling::new(
+                hardware.get_scale()?,
+                samples,
+                sample_period,
+                &cancel,
+                &mut progress,
+            )?
+            .medians())
+        })
+        .await?;
+    state.lock().unwrap().capture_corner(position, readings)
+}
+#[tauri::command]
+fn corner_test_report(state: State<'_, Mutex<AppData>>) -> Result<CornerLoadReport, AppError> {
+    state.lock().unwrap().get_corner_report()
+}
+
+#[tauri::command]
+fn coefficient_history(
+    state: State<'_, Mutex<AppData>>,
+    phidget_id: Option<i32>,
+) -> Vec<CoefficientRecord> {
+    state.lock().unwrap().get_coefficient_history(phidget_id)
+}
+#[tauri::command]
+async fn set_coefficients(
+    state: State<'_, Mutex<AppData>>,
+    hardware: State<'_, HardwareManager>,
+    coefficients: Coefficients,
+) -> Result<(), AppError> {
+    hardware.apply_coefficients(&coefficients).await?;
+    state
+        .lock()
+        .unwrap()
+        .update_coefficients(coefficients, CoefficientSource::Manual)
+}
+#[tauri::command]
+async fn import_coefficients(
+    state: State<'_, Mutex<AppData>>,
+    hardware: State<'_, HardwareManager>,
+    path: String,
+) -> Result<Coefficients, AppError> {
+    let contents = std::fs::read_to_string(path).map_err(AppError::Io)?;
+    let coefficients: Coefficients = serde_json::from_str(&contents).map_err(AppError::Serde)?;
+    hardware.apply_coefficients(&coefficients).await?;
+    state
+        .lock()
+        .unwrap()
+        .update_coefficients(coefficients.clone(), CoefficientSource::File)?;
+    Ok(coefficients)
+}
+#[tauri::command]
+async fn rollback_coefficients(
+    state: State<'_, Mutex<AppData>>,
+    hardware: State<'_, HardwareManager>,
+    index: usize,
+) -> Result<Coefficients, AppError> {
+    let coefficients = { state.lock().unwrap().get_rollback_coefficients(index)? };
+    hardware.apply_coefficients(&coefficients).await?;
     state
         .lock()
         .unwrap()
-        .get_mut_scale_ref()
-        .ok_or(AppError::NoScale)?
-        .set_data_intervals(sample_period)
-        .map_err(AppError::Libra)
+        .update_coefficients(coefficients.clone(), CoefficientSource::Rollback)?;
+    Ok(coefficients)
 }
 
 #[tauri::command]
-async fn enable_motor(state: tauri::State<'_, Mutex<AppData>>) -> Result<(), AppError> {
-    let motor = { state.lock().unwrap().get_motor(0) };
-    motor.enable().await.expect("Clear core bug");
-    Ok(())
+fn list_sessions(state: State<'_, Mutex<AppData>>) -> Result<Vec<SessionSummary>, AppError> {
+    state.lock().unwrap().get_session_store()?.list()
 }
 #[tauri::command]
-async fn disable_motor(state: tauri::State<'_, Mutex<AppData>>) -> Result<(), AppError> {
-    let motor = { state.lock().unwrap().get_motor(0) };
-    motor.disable().await;
-    motor.clear_alerts().await;
-    Ok(())
+fn open_session(
+    state: State<'_, Mutex<AppData>>,
+    id: String,
+) -> Result<CalibrationSession, AppError> {
+    state.lock().unwrap().get_session_store()?.load(&id)
 }
 #[tauri::command]
-async fn move_motor(state: tauri::State<'_, Mutex<AppData>>, steps: f64) -> Result<(), AppError> {
-    let motor = { state.lock().unwrap().get_motor(0) };
-    motor.relative_move(steps).await.expect("Clear core bug");
-    Ok(())
+fn current_session(state: State<'_, Mutex<AppData>>) -> Result<CalibrationSession, AppError> {
+    state.lock().unwrap().get_session().ok_or(AppError::NoSession)
 }
 #[tauri::command]
-async fn dispense(state: tauri::State<'_, Mutex<AppData>>, dispense_settings: DispenseSettings) -> Result<Data, AppError> {
-    let (scale, motor) = {
-        let mut state = state.lock().unwrap();
-        let scale = state.take_scale()?;
-        let motor = state.get_motor(0);
-        (scale, motor)
-    };
-    let (data, scale) = match DispenseOutcome::dispense(&motor, scale, dispense_settings).await.map_err(AppError::NodeDiagnostics)? {
-        DispenseOutcome::Success(data, scale) => (data, scale),
-        DispenseOutcome::Timeout(data, scale) => { 
-            println!("Dispense timed out!");
-            (data, scale )
-        },
-    };
-    state.lock().unwrap().return_scale(scale)?;
-    Ok(data)
+fn new_session(state: State<'_, Mutex<AppData>>) -> Result<String, AppError> {
+    state.lock().unwrap().new_session()
 }
-#[tauri::command(async)]
-fn drop_scale(state: State<'_, Mutex<AppData>>) -> Result<(), AppError> {
+#[tauri::command]
+fn resume_session(
+    state: State<'_, Mutex<AppData>>,
+    id: String,
+) -> Result<CalibrationSession, AppError> {
+    let mut state = state.lock().unwrap();
+    let session = state.get_session_store()?.load(&id)?;
+    state.resume_session(session.clone())?;
+    Ok(session)
+}
+#[tauri::command]
+fn delete_session(state: State<'_, Mutex<AppData>>, id: String) -> Result<(), AppError> {
     let mut state = state.lock().unwrap();
-    let _ = state.take_scale()?;
+    state.get_session_store()?.delete(&id)?;
+    state.close_session(&id);
     Ok(())
 }
+#[tauri::command]
+fn set_session_notes(state: State<'_, Mutex<AppData>>, notes: String) -> Result<(), AppError> {
+    state.lock().unwrap().set_session_notes(notes)
+}
+
 #[tauri::command(async)]
-fn setup_raw_data_collection(state: State<'_, Mutex<AppData>>) -> Result<(), AppError> {
-    let mut state = state.lock().unwrap();
-    let _scale = state.take_scale()?;
-    // TODO: need to implement this up a crate...
-    Err(AppError::NotImplemented)
+async fn get_coefficients(
+    state: tauri::State<'_, Mutex<AppData>>,
+    hardware: tauri::State<'_, HardwareManager>,
+) -> Result<String, AppError> {
+    Backend::get_coefficients(state, hardware).await
+}
+
+#[tauri::command(async)]
+async fn plot(
+    hardware: State<'_, HardwareManager>,
+    data_request: DataRequest,
+) -> Result<TrialData, AppError> {
+    hardware
+        .run("plot", |hardware| {
+            Box::pin(async move {
+                let cancel = hardware.get_cancel_token();
+                let mut progress = hardware.get_progress_reporter();
+                if data_request.is_dispense() {
+                    let motor = hardware.get_motor(0)?;
+                    return data_request
+                        .dispense(&motor, hardware.get_scale()?, &cancel, &mut progress)
+                        .await;
+                }
+                data_request
+                    .conduct(hardware.get_scale()?, &cancel, &mut progress)
+                    .map(TrialData::from)
+            })
+        })
+        .await
+}
+#[tauri::command(async)]
+async fn plot_lc(hardware: State<'_, HardwareManager>, data_request: LoadCellDataRequest) -> Result<[Data; 4], AppError> {
+    hardware
+        .run_sync("plot_lc", |hardware| {
+            let cancel = hardware.get_cancel_token();
+            let mut progress = hardware.get_progress_reporter();
+            data_request.conduct(hardware.get_scale()?, &cancel, &mut progress)
+        })
+        .await
+}
+#[tauri::command(async)]
+async fn set_phidget_interval(
+    hardware: tauri::State<'_, HardwareManager>,
+    sample_period: Duration,
+) -> Result<(), AppError> {
+    hardware
+        .run_sync("set_phidget_interval", move |hardware| {
+            hardware.set_data_intervals(sample_period)
+        })
+        .await
+}
+
+#[tauri::command(async)]
+async fn start_recording(hardware: State<'_, HardwareManager>) -> Result<String, AppError> {
+    hardware
+        .run_sync("start_recording", |hardware| hardware.start_recording())
+        .await
 }
 #[tauri::command(async)]
-async fn set_velo(state: State<'_, Mutex<AppData>>, velo: f64) -> Result<(), AppError> {
-    let motor = { state.lock().unwrap().get_motor(0) };
-    motor.set_velocity(velo).await;
+async fn stop_recording(hardware: State<'_, HardwareManager>) -> Result<RecordingSummary, AppError> {
+    hardware
+        .run_sync("stop_recording", |hardware| hardware.stop_recording())
+        .await
+}
+// Runs a request against a recording instead of the scale, so different filter settings
+// can be compared on identical readings
+#[tauri::command(async)]
+async fn replay_recording(
+    app: tauri::AppHandle,
+    id: String,
+    data_request: DataRequest,
+) -> Result<ReplayResult, AppError> {
+    let recording = Recording::load(&app, &id)?;
+    let mut progress = ProgressReporter::new(app, None, "replay_recording".into());
+    recording::replay(recording, data_request, &mut progress).await
+}
+#[tauri::command]
+fn buffered_readings(
+    hardware: State<'_, HardwareManager>,
+    window: BufferWindow,
+    decimation: Option<usize>,
+) -> Result<[Data; 4], AppError> {
+    hardware.get_buffer().lock().unwrap().query(window, decimation)
+}
+#[tauri::command]
+fn buffer_status(hardware: State<'_, HardwareManager>) -> BufferStatus {
+    hardware.get_buffer().lock().unwrap().get_status()
+}
+#[tauri::command]
+fn set_buffer_length(hardware: State<'_, HardwareManager>, length: Duration) {
+    hardware.get_buffer().lock().unwrap().set_length(length);
+}
+#[tauri::command(async)]
+async fn start_stream(
+    hardware: State<'_, HardwareManager>,
+    stream_settings: StreamSettings,
+) -> Result<(), AppError> {
+    hardware
+        .run_sync("start_stream", move |hardware| {
+            hardware.start_stream(stream_settings)
+        })
+        .await
+}
+#[tauri::command(async)]
+async fn stop_stream(hardware: State<'_, HardwareManager>) -> Result<(), AppError> {
+    hardware
+        .run_sync("stop_stream", |hardware| {
+            hardware.stop_stream();
+            Ok(())
+        })
+        .await
+}
+
+#[tauri::command]
+async fn enable_motor(hardware: tauri::State<'_, HardwareManager>) -> Result<(), AppError> {
+    hardware
+        .run("enable_motor", |hardware| {
+            Box::pin(async move { hardware.get_motor(0)?.enable().await })
+        })
+        .await
+}
+#[tauri::command]
+async fn disable_motor(hardware: tauri::State<'_, HardwareManager>) -> Result<(), AppError> {
+    hardware
+        .run("disable_motor", |hardware| {
+            Box::pin(async move {
+                let motor = hardware.get_motor(0)?;
+                motor.disable().await;
+                motor.clear_alerts().await;
+                Ok(())
+            })
+        })
+        .await
+}
+#[tauri::command]
+async fn move_motor(hardware: tauri::State<'_, HardwareManager>, steps: f64) -> Result<(), AppError> {
+    hardware
+        .run("move_motor", move |hardware| {
+            Box::pin(async move { hardware.get_motor(0)?.relative_move(steps).await })
+        })
+        .await
+}
+#[tauri::command]
+async fn dispense(hardware: tauri::State<'_, HardwareManager>, dispense_settings: DispenseSettings) -> Result<TrialData, AppError> {
+    hardware
+        .run("dispense", move |hardware| {
+            Box::pin(async move {
+                let motor = hardware.get_motor(0)?;
+                let cancel = hardware.get_cancel_token();
+                let mut progress = hardware.get_progress_reporter();
+                let (data, result) = dispenser::dispense(&motor, hardware.get_scale()?, &dispense_settings, &cancel, &mut progress).await?;
+                Ok(TrialData::from_dispense(data, result))
+            })
+        })
+        .await
+}
+// Repeats one dispense to measure how accurately it lands, saved to the studies folder
+#[tauri::command]
+async fn dispense_study(
+    app: tauri::AppHandle,
+    hardware: State<'_, HardwareManager>,
+    dispense_settings: DispenseSettings,
+    study_settings: StudySettings,
+) -> Result<DispenseStudy, AppError> {
+    DispenseStudy::run(&hardware, app, dispense_settings, study_settings).await
+}
+#[tauri::command]
+fn resume_study(hardware: State<'_, HardwareManager>) {
+    hardware.get_study_control().resume();
+}
+#[tauri::command]
+fn refill_simulated_hopper(hardware: State<'_, HardwareManager>) -> Result<(), AppError> {
+    hardware.get_simulation().get_simulated_feeder()?.refill();
+    Ok(())
+}
+#[tauri::command]
+fn simulated_motor_status(hardware: State<'_, HardwareManager>, id: usize) -> Result<MotorStatus, AppError> {
+    Ok(hardware.get_simulation().get_simulated_motor(id)?.get_status())
+}
+#[tauri::command]
+fn simulate_load(
+    hardware: State<'_, HardwareManager>,
+    weight: f64,
+    position: Placement,
+) -> Result<(), AppError> {
+    hardware.get_simulation().get_simulated_load()?.place(weight, position)
+}
+#[tauri::command]
+fn clear_simulated_load(hardware: State<'_, HardwareManager>) -> Result<(), AppError> {
+    hardware.get_simulation().get_simulated_load()?.clear();
     Ok(())
 }
 #[tauri::command(async)]
-async fn mock_dispense(state: State<'_, Mutex<AppData>>, steps: f64, retract: f64) -> Result<(), AppError> {
-    let motor = { state.lock().unwrap().get_motor(0) };
-    motor.relative_move(steps/10.).await.expect("Clear core bug");
-    tokio::time::sleep(Duration::from_millis(10)).await;
-    motor.wait_for_move(Duration::from_millis(10)).await.expect("Clear core bug");
-    motor.relative_move(-retract/10.).await.expect("Clear core bug");
+async fn drop_scale(
+    state: State<'_, Mutex<AppData>>,
+    hardware: State<'_, HardwareManager>,
+) -> Result<(), AppError> {
+    hardware
+        .run_sync("drop_scale", |hardware| hardware.drop_scale())
+        .await?;
+    state.lock().unwrap().disconnect_scale();
     Ok(())
 }
+#[tauri::command(async)]
+async fn setup_raw_data_collection(
+    hardware: State<'_, HardwareManager>,
+) -> Result<CaptureInfo, AppError> {
+    hardware
+        .run_sync("setup_raw_data_collection", |hardware| hardware.start_capture())
+        .await
+}
+#[tauri::command(async)]
+async fn stop_raw_data_collection(
+    hardware: State<'_, HardwareManager>,
+) -> Result<CaptureInfo, AppError> {
+    hardware
+        .run_sync("stop_raw_data_collection", |hardware| hardware.stop_capture())
+        .await
+}
+#[tauri::command(async)]
+async fn set_velo(hardware: State<'_, HardwareManager>, velo: f64) -> Result<(), AppError> {
+    hardware
+        .run("set_velo", move |hardware| {
+            Box::pin(async move {
+                hardware.get_motor(0)?.set_velocity(velo).await;
+                Ok(())
+            })
+        })
+        .await
+}
+#[tauri::command(async)]
+async fn mock_dispense(hardware: State<'_, HardwareManager>, steps: f64, retract: f64) -> Result<(), AppError> {
+    hardware
+        .run("mock_dispense", move |hardware| {
+            Box::pin(async move {
+                let motor = hardware.get_motor(0)?;
+                motor.relative_move(steps/10.).await?;
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                motor.wait_for_move(Duration::from_millis(10)).await?;
+                motor.relative_move(-retract/10.).await?;
+                Ok(())
+            })
+        })
+        .await
+}
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
     tauri::Builder::default()
         .manage(Mutex::new(AppData::new()))
         .plugin(tauri_plugin_opener::init())
+        .setup(|app| {
+            app.manage(HardwareManager::new(
+                Simulation::from_env(),
+                app.handle().clone(),
+            ));
+            let session_store = SessionStore::new(app.handle())?;
+            let coefficient_history = CoefficientHistory::load(app.handle())?;
+            let state = app.state::<Mutex<AppData>>();
+            let mut state = state.lock().unwrap();
+            state.set_session_store(session_store);
+            state.set_coefficient_history(coefficient_history);
+            Ok(())
+        })
         .invoke_handler(tauri::generate_handler![
             check_app_data,
+            hardware_status,
+            cancel_job,
             connect_scale,
             add_trial,
+            set_stability_criteria,
+            list_trials,
+            delete_trial,
+            retake_trial,
+            annotate_trial,
+            start_procedure,
+            procedure_status,
+            capture_step,
             calibrate,
+            fit_report,
+            set_solver,
+            solve_locally,
             get_coefficients,
+            start_verification,
+            verification_status,
+            verify_next,
+            start_corner_test,
+            capture_corner,
+            corner_test_report,
+            coefficient_history,
+            set_coefficients,
+            import_coefficients,
+            rollback_coefficients,
+            list_sessions,
+            open_session,
+            current_session,
+            new_session,
+            resume_session,
+            delete_session,
+            set_session_notes,
             plot,
             enable_motor,
             disable_motor,
             set_phidget_interval,
             dispense,
             move_motor,
+            simulated_motor_status,
+            refill_simulated_hopper,
+            simulate_load,
+            clear_simulated_load,
             drop_scale,
             setup_raw_data_collection,
+            stop_raw_data_collection,
             plot_lc,
+            buffered_readings,
+            buffer_status,
+            set_buffer_length,
+            start_recording,
+            stop_recording,
+            replay_recording,
+            start_stream,
+            stop_stream,
+            dispense_study,
+            resume_study,
             set_velo,
             mock_dispense
         ])
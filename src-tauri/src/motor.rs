@@ -0,0 +1,463 @@
+use crate::errors::AppError;
+use crate::recording::{MotorCommand, RecordedEvent, Recorder};
+use control_components::components::clear_core_motor::ClearCoreMotor;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+#[derive(Clone)]
+pub enum MotorDriver {
+    ClearCore(ClearCoreMotor),
+    Simulated(SimulatedMotor),
+}
+
+#[derive(Clone)]
+pub struct Motor {
+    driver: MotorDriver,
+    // Set while a recording is running
+    recorder: Option<Recorder>,
+    // The ClearCore does not report its position, so a simulated motor follows the
+    // commands sent to it. It ignores acceleration, so the position is approximate.
+    estimate: Option<SimulatedMotor>,
+}
+impl Motor {
+    pub fn new(driver: MotorDriver, recorder: Option<Recorder>) -> Self {
+        let estimate = match driver {
+            MotorDriver::ClearCore(_) => {
+                let estimate = SimulatedMotor::new(SimulatedMotorConfig {
+                    max_velocity: f64::INFINITY,
+                    ..SimulatedMotorConfig::default()
+                });
+                // The ClearCore may have been enabled before the app started
+                estimate.enable().ok();
+                Some(estimate)
+            }
+            MotorDriver::Simulated(_) => None,
+        };
+        Self {
+            driver,
+            recorder,
+            estimate,
+        }
+    }
+    // Handles for the same motor keep following one position
+    pub fn share_estimate(mut self, other: &Motor) -> Self {
+        if other.estimate.is_some() {
+            self.estimate = other.estimate.clone();
+        }
+        self
+    }
+    fn record(&self, command: MotorCommand) {
+        if let Some(recorder) = self.recorder.as_ref() {
+            recorder.record(RecordedEvent::Motor(command));
+        }
+    }
+    pub async fn enable(&self) -> Result<(), AppError> {
+        self.record(MotorCommand::Enable);
+        match &self.driver {
+            MotorDriver::ClearCore(motor) => {
+                motor
+                    .enable()
+                    .await
+                    .map_err(|e| AppError::Motor(format!("{e:?}")))?;
+                self.estimate(|estimate| estimate.enable().ok());
+                Ok(())
+            }
+            MotorDriver::Simulated(motor) => motor.enable(),
+        }
+    }
+    pub async fn disable(&self) {
+        self.record(MotorCommand::Disable);
+        match &self.driver {
+            MotorDriver::ClearCore(motor) => {
+                motor.disable().await;
+                self.estimate(SimulatedMotor::disable);
+            }
+            MotorDriver::Simulated(motor) => motor.disable(),
+        }
+    }
+    pub async fn clear_alerts(&self) {
+        self.record(MotorCommand::ClearAlerts);
+        match &self.driver {
+            MotorDriver::ClearCore(motor) => {
+                motor.clear_alerts().await;
+                self.estimate(SimulatedMotor::clear_alerts);
+            }
+            MotorDriver::Simulated(motor) => motor.clear_alerts(),
+        }
+    }
+    pub async fn relative_move(&self, distance: f64) -> Result<(), AppError> {
+        self.record(MotorCommand::RelativeMove(distance));
+        match &self.driver {
+            MotorDriver::ClearCore(motor) => {
+                motor
+                    .relative_move(distance)
+                    .await
+                    .map_err(|e| AppError::Motor(format!("{e:?}")))?;
+                self.estimate(|estimate| estimate.relative_move(distance).ok());
+                Ok(())
+            }
+            MotorDriver::Simulated(motor) => motor.relative_move(distance),
+        }
+    }
+    pub async fn set_velocity(&self, velocity: f64) {
+        self.record(MotorCommand::SetVelocity(velocity));
+        match &self.driver {
+            MotorDriver::ClearCore(motor) => {
+                motor.set_velocity(velocity).await;
+                self.estimate(|estimate| estimate.set_velocity(velocity));
+            }
+            MotorDriver::Simulated(motor) => motor.set_velocity(velocity),
+        }
+    }
+    fn estimate<T>(&self, command: impl FnOnce(&SimulatedMotor) -> T) {
+        if let Some(estimate) = self.estimate.as_ref() {
+            command(estimate);
+        }
+    }
+    pub async fn wait_for_move(&self, interval: Duration) -> Result<(), AppError> {
+        match &self.driver {
+            MotorDriver::ClearCore(motor) => motor
+                .wait_for_move(interval)
+                .await
+                .map_err(|e| AppError::Motor(format!("{e:?}"))),
+            MotorDriver::Simulated(motor) => {
+                while motor.is_moving() {
+                    tokio::time::sleep(interval).await;
+                }
+                Ok(())
+            }
+        }
+    }
+    // Estimated on the ClearCore
+    pub fn get_position(&self) -> Option<f64> {
+        match &self.driver {
+            MotorDriver::ClearCore(_) => self.estimate.as_ref().map(SimulatedMotor::get_position),
+            MotorDriver::Simulated(motor) => Some(motor.get_position()),
+        }
+    }
+    pub async fn abrupt_stop(&self) {
+        self.record(MotorCommand::AbruptStop);
+        match &self.driver {
+            MotorDriver::ClearCore(motor) => {
+                motor.abrupt_stop().await;
+                self.estimate(SimulatedMotor::abrupt_stop);
+            }
+            MotorDriver::Simulated(motor) => motor.abrupt_stop(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub enum MotorAlert {
+    MoveWhileDisabled,
+    MotionCanceledDisabled,
+    VelocityLimit,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SimulatedMotorConfig {
+    // Revolutions per second, the ClearCore is configured with 800 steps per revolution
+    initial_velocity: f64,
+    max_velocity: f64,
+}
+impl Default for SimulatedMotorConfig {
+    fn default() -> Self {
+        Self {
+            initial_velocity: 0.5,
+            max_velocity: 10.,
+        }
+    }
+}
+impl SimulatedMotorConfig {
+    // Same convention as CALDO_SIMULATED_SCALE: a JSON file path, or any other value for
+    // the defaults
+    pub fn from_env() -> Option<Self> {
+        let value = std::env::var("CALDO_SIMULATED_MOTOR").ok()?;
+        match std::fs::read_to_string(&value) {
+            Ok(contents) => match serde_json::from_str(&contents) {
+                Ok(config) => Some(config),
+                Err(e) => {
+                    log::warn!("Invalid simulated motor config {value}: {e}, using defaults");
+                    Some(Self::default())
+                }
+            },
+            Err(_) => Some(Self::default()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MotorStatus {
+    enabled: bool,
+    moving: bool,
+    position: f64,
+    velocity: f64,
+    target: Option<f64>,
+    alerts: Vec<MotorAlert>,
+}
+
+// A move runs at constant velocity from `from` towards `target`. Acceleration is not
+// modelled since the dispense controller only cares about the commanded speed.
+#[derive(Debug, Clone, Copy)]
+struct Segment {
+    start: Instant,
+    from: f64,
+    target: f64,
+    velocity: f64,
+}
+impl Segment {
+    fn position(&self, now: Instant) -> f64 {
+        let travelled = self.velocity * now.duration_since(self.start).as_secs_f64();
+        let distance = self.target - self.from;
+        self.from + distance.signum() * travelled.min(distance.abs())
+    }
+    fn is_done(&self, now: Instant) -> bool {
+        self.position(now) == self.target
+    }
+}
+
+#[derive(Debug)]
+struct MotorState {
+    enabled: bool,
+    velocity: f64,
+    position: f64,
+    segment: Option<Segment>,
+    alerts: Vec<MotorAlert>,
+}
+impl MotorState {
+    // Folds a finished or interrupted move into the resting position
+    fn settle(&mut self, now: Instant) {
+        if let Some(segment) = self.segment {
+            self.position = segment.position(now);
+            if segment.is_done(now) {
+                self.segment = None;
+            }
+        }
+    }
+    fn raise(&mut self, alert: MotorAlert) {
+        if !self.alerts.contains(&alert) {
+            self.alerts.push(alert);
+        }
+    }
+}
+
+// Handles share their state, like ClearCoreMotor handles share a controller
+#[derive(Debug, Clone)]
+pub struct SimulatedMotor {
+    config: SimulatedMotorConfig,
+    state: Arc<Mutex<MotorState>>,
+}
+impl SimulatedMotor {
+    pub fn new(config: SimulatedMotorConfig) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(MotorState {
+                enabled: false,
+                velocity: config.initial_velocity,
+                position: 0.,
+                segment: None,
+                alerts: Vec::new(),
+            })),
+            config,
+        }
+    }
+    pub fn enable(&self) -> Result<(), AppError> {
+        let mut state = self.state.lock().unwrap();
+        if !state.alerts.is_empty() {
+            return Err(AppError::Motor(format!(
+                "Cannot enable with active alerts {:?}",
+                state.alerts
+            )));
+        }
+        state.enabled = true;
+        Ok(())
+    }
+    pub fn disable(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.settle(Instant::now());
+        if state.segment.take().is_some() {
+            state.raise(MotorAlert::MotionCanceledDisabled);
+        }
+        state.enabled = false;
+    }
+    pub fn clear_alerts(&self) {
+        self.state.lock().unwrap().alerts.clear();
+    }
+    // Like the ClearCore, a new move replaces the one in progress and the commanded
+    // velocity only applies from the next move on
+    pub fn relative_move(&self, distance: f64) -> Result<(), AppError> {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        if !state.enabled {
+            state.raise(MotorAlert::MoveWhileDisabled);
+            return Err(AppError::Motor("Motor is not enabled".into()));
+        }
+        state.settle(now);
+        state.segment = Some(Segment {
+            start: now,
+            from: state.position,
+            target: state.position + distance,
+            velocity: state.velocity,
+        });
+        Ok(())
+    }
+    pub fn set_velocity(&self, velocity: f64) {
+        let mut state = self.state.lock().unwrap();
+        if velocity.abs() > self.config.max_velocity {
+            state.raise(MotorAlert::VelocityLimit);
+        }
+        state.velocity = velocity.abs().min(self.config.max_velocity);
+    }
+    pub fn abrupt_stop(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.settle(Instant::now());
+        state.segment = None;
+    }
+    pub fn get_position(&self) -> f64 {
+        let mut state = self.state.lock().unwrap();
+        state.settle(Instant::now());
+        state.position
+    }
+    pub fn is_moving(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.settle(Instant::now());
+        state.segment.is_some()
+    }
+    pub fn get_status(&self) -> MotorStatus {
+        let mut state = self.state.lock().unwrap();
+        state.settle(Instant::now());
+        MotorStatus {
+            enabled: state.enabled,
+            moving: state.segment.is_some(),
+            position: state.position,
+            velocity: state.velocity,
+            target: state.segment.map(|segment| segment.target),
+            alerts: state.alerts.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::future::Future;
+    use tokio::time::advance;
+
+    fn motor(initial_velocity: f64) -> SimulatedMotor {
+        SimulatedMotor::new(SimulatedMotorConfig {
+            initial_velocity,
+            max_velocity: 10.,
+        })
+    }
+    // Runs with tokio's clock paused, so moves only progress when a test advances it
+    fn paused(test: impl Future<Output = ()>) {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .start_paused(true)
+            .build()
+            .unwrap()
+            .block_on(test)
+    }
+
+    #[test]
+    fn move_runs_at_the_commanded_velocity() {
+        paused(async {
+            let motor = motor(10.);
+            motor.enable().unwrap();
+            motor.relative_move(0.5).unwrap();
+            assert!(motor.is_moving());
+            advance(Duration::from_millis(20)).await;
+            assert!((motor.get_position() - 0.2).abs() < 1e-9);
+            advance(Duration::from_millis(30)).await;
+            assert!(!motor.is_moving());
+            assert_eq!(motor.get_position(), 0.5);
+        });
+    }
+
+    #[test]
+    fn move_while_disabled_raises_an_alert() {
+        let motor = motor(1.);
+        assert!(motor.relative_move(1.).is_err());
+        assert_eq!(motor.get_status().alerts, vec![MotorAlert::MoveWhileDisabled]);
+        assert_eq!(motor.get_position(), 0.);
+        // Alerts have to be cleared before the motor enables again
+        assert!(motor.enable().is_err());
+        motor.clear_alerts();
+        motor.enable().unwrap();
+    }
+
+    #[test]
+    fn disabling_mid_move_cancels_it() {
+        paused(async {
+            let motor = motor(1.);
+            motor.enable().unwrap();
+            motor.relative_move(10.).unwrap();
+            advance(Duration::from_millis(20)).await;
+            motor.disable();
+            let status = motor.get_status();
+            assert!(!status.enabled && !status.moving);
+            assert_eq!(status.alerts, vec![MotorAlert::MotionCanceledDisabled]);
+            assert!((status.position - 0.02).abs() < 1e-9);
+        });
+    }
+
+    #[test]
+    fn velocity_is_clamped_to_the_limit() {
+        let motor = motor(1.);
+        motor.set_velocity(-25.);
+        let status = motor.get_status();
+        assert_eq!(status.velocity, 10.);
+        assert_eq!(status.alerts, vec![MotorAlert::VelocityLimit]);
+    }
+
+    #[test]
+    fn velocity_applies_from_the_next_move() {
+        paused(async {
+            let motor = motor(1.);
+            motor.enable().unwrap();
+            motor.relative_move(10.).unwrap();
+            motor.set_velocity(5.);
+            assert_eq!(motor.get_status().target, Some(10.));
+            advance(Duration::from_millis(50)).await;
+            assert!((motor.get_position() - 0.05).abs() < 1e-9);
+            motor.relative_move(1.).unwrap();
+            advance(Duration::from_millis(100)).await;
+            assert!((motor.get_position() - 0.55).abs() < 1e-9);
+        });
+    }
+
+    #[test]
+    fn abrupt_stop_holds_the_current_position() {
+        paused(async {
+            let motor = motor(1.);
+            motor.enable().unwrap();
+            motor.relative_move(-10.).unwrap();
+            advance(Duration::from_millis(20)).await;
+            motor.abrupt_stop();
+            let stopped = motor.get_position();
+            assert!((stopped + 0.02).abs() < 1e-9);
+            advance(Duration::from_millis(20)).await;
+            assert_eq!(motor.get_position(), stopped);
+            assert!(motor.get_status().alerts.is_empty());
+        });
+    }
+
+    #[test]
+    fn wait_for_move_returns_once_the_move_is_done() {
+        let simulated = motor(10.);
+        let motor = Motor::new(MotorDriver::Simulated(simulated.clone()), None);
+        paused(async {
+            motor.enable().await.unwrap();
+            motor.relative_move(0.3).await.unwrap();
+            let start = Instant::now();
+            motor.wait_for_move(Duration::from_millis(5)).await.unwrap();
+            // The paused clock skips ahead through each poll
+            assert!(start.elapsed() >= Duration::from_millis(30));
+            assert!(start.elapsed() < Duration::from_millis(40));
+        });
+        assert!(!simulated.is_moving());
+        assert_eq!(motor.get_position(), Some(0.3));
+    }
+}
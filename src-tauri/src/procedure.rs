@@ -0,0 +1,350 @@
+use crate::calibration_data::CalibrationTrial;
+use crate::errors::AppError;
+use serde::{Deserialize, Serialize};
+
+// Minimum share of the added load a corner step must put on its own load cell
+const CORNER_SHARE: f64 = 0.5;
+// Allowed relative difference in load per gram between a step and the loaded step before it
+const LOAD_TOLERANCE: f64 = 0.2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub enum Placement {
+    Empty,
+    Center,
+    Corner(usize),
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PlanStep {
+    label: String,
+    placement: Placement,
+    weight: f64,
+    #[serde(default = "default_required")]
+    required: bool,
+}
+impl PlanStep {
+    pub fn new(label: &str, placement: Placement, weight: f64) -> Self {
+        Self {
+            label: label.into(),
+            placement,
+            weight,
+            required: true,
+        }
+    }
+    pub fn get_weight(&self) -> f64 {
+        self.weight
+    }
+}
+fn default_required() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CalibrationPlan {
+    steps: Vec<PlanStep>,
+    // Allowed deviation of each load cell's share from 25% for center placements
+    center_tolerance: f64,
+}
+impl Default for CalibrationPlan {
+    fn default() -> Self {
+        Self {
+            steps: vec![
+                PlanStep::new("Empty", Placement::Empty, 0.),
+                PlanStep::new("Center 500 g", Placement::Center, 500.),
+                PlanStep::new("Corner 0 500 g", Placement::Corner(0), 500.),
+                PlanStep::new("Corner 1 500 g", Placement::Corner(1), 500.),
+                PlanStep::new("Corner 2 500 g", Placement::Corner(2), 500.),
+                PlanStep::new("Corner 3 500 g", Placement::Corner(3), 500.),
+                PlanStep::new("Center 2 kg", Placement::Center, 2000.),
+            ],
+            center_tolerance: 0.1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StepStatus {
+    index: usize,
+    #[serde(flatten)]
+    step: PlanStep,
+    done: bool,
+}
+#[derive(Debug, Clone, Serialize)]
+pub struct ProcedureStatus {
+    steps: Vec<StepStatus>,
+    next_step: Option<usize>,
+    remaining_required: usize,
+    complete: bool,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CalibrationProcedure {
+    plan: CalibrationPlan,
+    captured: Vec<Option<CalibrationTrial>>,
+}
+impl CalibrationProcedure {
+    pub fn new(plan: CalibrationPlan) -> Result<Self, AppError> {
+        if let Some(step) = plan
+            .steps
+            .iter()
+            .find(|step| matches!(step.placement, Placement::Corner(cell) if cell >= 4))
+        {
+            return Err(AppError::Other(format!(
+                "Plan step '{}' names a corner that does not exist!",
+                step.label
+            )));
+        }
+        let captured = vec![None; plan.steps.len()];
+        Ok(Self { plan, captured })
+    }
+    pub fn get_step(&self, index: usize) -> Result<&PlanStep, AppError> {
+        self.plan.steps.get(index).ok_or(AppError::StepIndex(index))
+    }
+    // Stores the trial for a step, returning whatever was captured for it before
+    pub fn complete_step(
+        &mut self,
+        index: usize,
+        trial: CalibrationTrial,
+    ) -> Result<Option<CalibrationTrial>, AppError> {
+        self.validate(index, &trial)?;
+        let captured = self
+            .captured
+            .get_mut(index)
+            .ok_or(AppError::StepIndex(index))?;
+        Ok(captured.replace(trial))
+    }
+    pub fn status(&self) -> ProcedureStatus {
+        let steps: Vec<StepStatus> = self
+            .plan
+            .steps
+            .iter()
+            .zip(&self.captured)
+            .enumerate()
+            .map(|(index, (step, captured))| StepStatus {
+                index,
+                step: step.clone(),
+                done: captured.is_some(),
+            })
+            .collect();
+        let remaining_required = steps
+            .iter()
+            .filter(|status| status.step.required && !status.done)
+            .count();
+        ProcedureStatus {
+            next_step: steps
+                .iter()
+                .find(|status| !status.done)
+                .map(|status| status.index),
+            steps,
+            remaining_required,
+            complete: remaining_required == 0,
+        }
+    }
+    // Checks that the load added on top of the empty platter landed where the step says,
+    // and that it reads like the loaded step before it for its weight, which catches a
+    // weight left on or never swapped. Without an empty reading there is no baseline, so
+    // neither can be checked.
+    fn validate(&self, index: usize, trial: &CalibrationTrial) -> Result<(), AppError> {
+        let step = self.get_step(index)?;
+        let baseline = self
+            .plan
+            .steps
+            .iter()
+            .zip(&self.captured)
+            .find_map(|(step, captured)| {
+                (step.placement == Placement::Empty)
+                    .then_some(captured.as_ref())
+                    .flatten()
+            });
+        let Some(baseline) = baseline else {
+            return Ok(());
+        };
+        let deltas: Vec<f64> = trial
+            .get_readings()
+            .iter()
+            .zip(baseline.get_readings())
+            .map(|(reading, base)| (reading - base).abs())
+            .collect();
+        let total: f64 = deltas.iter().sum();
+        let shares: Vec<f64> = deltas.iter().map(|delta| delta / total).collect();
+        let consistent = match step.placement {
+            Placement::Empty => true,
+            _ if total == 0. => false,
+            Placement::Center => shares
+                .iter()
+                .all(|share| (share - 0.25).abs() <= self.plan.center_tolerance),
+            Placement::Corner(cell) => shares[cell] >= CORNER_SHARE,
+        };
+        if !consistent {
+            return Err(AppError::PlacementMismatch {
+                step: step.label.clone(),
+                shares: format!("{shares:.2?}"),
+            });
+        }
+        if step.placement == Placement::Empty || step.weight <= 0. {
+            return Ok(());
+        }
+        let previous = self.plan.steps[..index]
+            .iter()
+            .zip(&self.captured)
+            .rev()
+            .find_map(|(step, captured)| {
+                captured
+                    .as_ref()
+                    .filter(|_| step.placement != Placement::Empty && step.weight > 0.)
+                    .map(|captured| (step, captured))
+            });
+        let Some((previous_step, previous)) = previous else {
+            return Ok(());
+        };
+        let load = |trial: &CalibrationTrial| {
+            trial
+                .get_readings()
+                .iter()
+                .zip(baseline.get_readings())
+                .map(|(reading, base)| reading - base)
+                .sum::<f64>()
+        };
+        let per_gram = load(trial) / step.weight;
+        let previous_per_gram = load(previous) / previous_step.weight;
+        let deviation = per_gram / previous_per_gram - 1.;
+        // A NaN deviation (nothing loaded on the previous step) fails the check too
+        if deviation.abs() <= LOAD_TOLERANCE {
+            Ok(())
+        } else {
+            Err(AppError::LoadMismatch {
+                step: step.label.clone(),
+                previous: previous_step.label.clone(),
+                deviation: deviation * 100.,
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EMPTY: [f64; 4] = [100., 200., 300., 400.];
+
+    // Readings for `weight` grams split across the load cells by `shares`, at 0.01 per gram
+    fn trial(shares: [f64; 4], weight: f64) -> CalibrationTrial {
+        let readings = std::array::from_fn(|cell| EMPTY[cell] + shares[cell] * weight * 0.01);
+        CalibrationTrial::from_array(readings, weight)
+    }
+    fn center(weight: f64) -> CalibrationTrial {
+        trial([0.25; 4], weight)
+    }
+    fn corner(cell: usize, weight: f64) -> CalibrationTrial {
+        let mut shares = [0.1; 4];
+        shares[cell] = 0.7;
+        trial(shares, weight)
+    }
+    // The same readings recorded under a different weight
+    fn recorded_as(trial: CalibrationTrial, weight: f64) -> CalibrationTrial {
+        CalibrationTrial::from_array(trial.get_readings().try_into().unwrap(), weight)
+    }
+    fn procedure() -> CalibrationProcedure {
+        CalibrationProcedure::new(CalibrationPlan::default()).unwrap()
+    }
+
+    #[test]
+    fn steps_are_offered_in_plan_order() {
+        let mut procedure = procedure();
+        let status = procedure.status();
+        assert_eq!(status.next_step, Some(0));
+        assert_eq!(status.remaining_required, 7);
+        procedure.complete_step(0, trial([0.; 4], 0.)).unwrap();
+        procedure.complete_step(2, corner(0, 500.)).unwrap();
+        // A skipped step is offered before the later ones
+        let status = procedure.status();
+        assert_eq!(status.next_step, Some(1));
+        assert_eq!(status.remaining_required, 5);
+        procedure.complete_step(1, center(500.)).unwrap();
+        assert_eq!(procedure.status().next_step, Some(3));
+        for cell in 1..4 {
+            procedure
+                .complete_step(cell + 2, corner(cell, 500.))
+                .unwrap();
+        }
+        procedure.complete_step(6, center(2000.)).unwrap();
+        let status = procedure.status();
+        assert_eq!(status.next_step, None);
+        assert!(status.complete);
+    }
+
+    #[test]
+    fn recapturing_a_step_returns_the_old_trial() {
+        let mut procedure = procedure();
+        let first = trial([0.; 4], 0.);
+        let timestamp = first.get_timestamp();
+        assert!(procedure.complete_step(0, first).unwrap().is_none());
+        let previous = procedure.complete_step(0, trial([0.; 4], 0.)).unwrap();
+        assert_eq!(previous.unwrap().get_timestamp(), timestamp);
+        assert!(matches!(
+            procedure.complete_step(7, center(500.)),
+            Err(AppError::StepIndex(7))
+        ));
+    }
+
+    #[test]
+    fn rejects_plans_with_missing_corners() {
+        let plan = CalibrationPlan {
+            steps: vec![PlanStep::new("Corner 4", Placement::Corner(4), 500.)],
+            center_tolerance: 0.1,
+        };
+        assert!(CalibrationProcedure::new(plan).is_err());
+    }
+
+    #[test]
+    fn checks_placement_against_the_empty_baseline() {
+        let mut procedure = procedure();
+        // Nothing to compare against before the empty platter is captured
+        procedure.complete_step(2, corner(1, 500.)).unwrap();
+        procedure.complete_step(0, trial([0.; 4], 0.)).unwrap();
+        assert!(matches!(
+            procedure.complete_step(1, corner(0, 500.)),
+            Err(AppError::PlacementMismatch { .. })
+        ));
+        assert!(matches!(
+            procedure.complete_step(1, center(0.)),
+            Err(AppError::PlacementMismatch { .. })
+        ));
+        procedure.complete_step(1, center(500.)).unwrap();
+        assert!(matches!(
+            procedure.complete_step(3, corner(0, 500.)),
+            Err(AppError::PlacementMismatch { .. })
+        ));
+        procedure.complete_step(3, corner(1, 500.)).unwrap();
+    }
+
+    #[test]
+    fn checks_load_against_the_previous_step() {
+        let mut procedure = procedure();
+        procedure.complete_step(0, trial([0.; 4], 0.)).unwrap();
+        procedure.complete_step(1, center(500.)).unwrap();
+        for cell in 0..4 {
+            procedure
+                .complete_step(cell + 2, corner(cell, 500.))
+                .unwrap();
+        }
+        // The 500 g weight is still on the platter, centered, instead of the 2 kg one
+        let Err(AppError::LoadMismatch {
+            step,
+            previous,
+            deviation,
+        }) = procedure.complete_step(6, recorded_as(center(500.), 2000.))
+        else {
+            panic!("forgotten weight swap was accepted");
+        };
+        assert_eq!(step, "Center 2 kg");
+        assert_eq!(previous, "Corner 3 500 g");
+        assert!((deviation + 75.).abs() < 1e-9);
+        procedure.complete_step(6, center(2000.)).unwrap();
+        // The previous loaded step is the one before in plan order, not the last captured
+        assert!(matches!(
+            procedure.complete_step(2, recorded_as(corner(0, 2000.), 500.)),
+            Err(AppError::LoadMismatch { previous, .. }) if previous == "Center 500 g"
+        ));
+    }
+}
@@ -0,0 +1,90 @@
+use serde::Serialize;
+use std::time::{Duration, Instant};
+use tauri::{AppHandle, Emitter};
+
+pub const PROGRESS_EVENT: &str = "progress";
+// Keeps a fast sample period from flooding the frontend
+const EMIT_PERIOD: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Progress {
+    // Requests that do not run on the hardware have no job
+    job: Option<u64>,
+    name: String,
+    // Trials retried for stability start collecting again under the next attempt
+    attempt: usize,
+    collected: usize,
+    expected: usize,
+    elapsed: Duration,
+    weight: Option<f64>,
+    cells: Option<[f64; 4]>,
+}
+
+// Emits progress events for the job it was made for
+pub struct ProgressReporter {
+    // Silent reporters have no app to emit to
+    app: Option<AppHandle>,
+    job: Option<u64>,
+    name: String,
+    attempt: usize,
+    start: Instant,
+    last_emit: Option<Instant>,
+}
+impl ProgressReporter {
+    pub fn new(app: AppHandle, job: Option<u64>, name: String) -> Self {
+        Self {
+            app: Some(app),
+            job,
+            name,
+            attempt: 1,
+            start: Instant::now(),
+            last_emit: None,
+        }
+    }
+    pub fn silent() -> Self {
+        Self {
+            app: None,
+            job: None,
+            name: String::new(),
+            attempt: 1,
+            start: Instant::now(),
+            last_emit: None,
+        }
+    }
+    pub fn set_attempt(&mut self, attempt: usize) {
+        self.attempt = attempt;
+        self.last_emit = None;
+    }
+    // The last sample is always reported so the frontend sees the acquisition finish. Cells
+    // are only read for the events that are emitted.
+    pub fn report(
+        &mut self,
+        collected: usize,
+        expected: usize,
+        weight: Option<f64>,
+        cells: impl FnOnce() -> Option<[f64; 4]>,
+    ) {
+        let now = Instant::now();
+        if collected < expected
+            && self
+                .last_emit
+                .is_some_and(|last_emit| now - last_emit < EMIT_PERIOD)
+        {
+            return;
+        }
+        self.last_emit.replace(now);
+        let progress = Progress {
+            job: self.job,
+            name: self.name.clone(),
+            attempt: self.attempt,
+            collected,
+            expected,
+            elapsed: now - self.start,
+            weight,
+            cells: cells(),
+        };
+        if let Some(Err(e)) = self.app.as_ref().map(|app| app.emit(PROGRESS_EVENT, progress)) {
+            log::warn!("Failed to emit progress: {e}");
+        }
+    }
+}
@@ -0,0 +1,606 @@
+use crate::data::{DataRequest, TrialData};
+use crate::errors::AppError;
+use crate::hardware::CancelToken;
+use crate::motor::{Motor, MotorDriver, SimulatedMotor, SimulatedMotorConfig};
+use crate::progress::ProgressReporter;
+use crate::scale::{self, Scale};
+use crate::statistics;
+use libra::scale::ConnectedScale;
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tauri::{AppHandle, Manager};
+
+// Version 2 only records the samples requests read, not every read of the scale. Version 3
+// records every sample of a median, version 2 recorded the median as a single weight.
+const RECORDING_VERSION: u32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub enum MotorCommand {
+    Enable,
+    Disable,
+    ClearAlerts,
+    RelativeMove(f64),
+    SetVelocity(f64),
+    AbruptStop,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub enum RecordedEvent {
+    Readings([f64; 4]),
+    Weight(f64),
+    Motor(MotorCommand),
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TimedEvent {
+    time: Duration,
+    event: RecordedEvent,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Recording {
+    version: u32,
+    id: String,
+    phidget_id: i32,
+    coefficients: Option<[f64; 4]>,
+    started: Duration,
+    events: Vec<TimedEvent>,
+}
+impl Recording {
+    pub fn load(app: &AppHandle, id: &str) -> Result<Self, AppError> {
+        let contents = fs::read_to_string(path(app, id)?).map_err(AppError::Io)?;
+        Self::from_json(&contents)
+    }
+    fn from_json(contents: &str) -> Result<Self, AppError> {
+        let recording: Recording = serde_json::from_str(contents).map_err(AppError::Serde)?;
+        if recording.version > RECORDING_VERSION {
+            return Err(AppError::RecordingVersion(recording.version));
+        }
+        Ok(recording)
+    }
+    fn save(&self, app: &AppHandle) -> Result<(), AppError> {
+        let contents = serde_json::to_string(self).map_err(AppError::Serde)?;
+        fs::write(path(app, &self.id)?, contents).map_err(AppError::Io)
+    }
+    fn get_motor_commands(&self) -> Vec<MotorCommand> {
+        self.events
+            .iter()
+            .filter_map(|event| match event.event {
+                RecordedEvent::Motor(command) => Some(command),
+                _ => None,
+            })
+            .collect()
+    }
+    // Median spacing of the recorded weights, none without two weights apart
+    fn get_sample_period(&self) -> Option<Duration> {
+        let times: Vec<Duration> = self
+            .events
+            .iter()
+            .filter(|event| matches!(event.event, RecordedEvent::Weight(_)))
+            .map(|event| event.time)
+            .collect();
+        let periods: Vec<f64> = times
+            .windows(2)
+            .map(|pair| (pair[1] - pair[0]).as_secs_f64())
+            .filter(|period| *period > 0.)
+            .collect();
+        statistics::median(&periods).map(Duration::from_secs_f64)
+    }
+    fn summary(&self) -> RecordingSummary {
+        let count = |matches: fn(&RecordedEvent) -> bool| {
+            self.events
+                .iter()
+                .filter(|event| matches(&event.event))
+                .count()
+        };
+        RecordingSummary {
+            id: self.id.clone(),
+            phidget_id: self.phidget_id,
+            started: self.started,
+            duration: self
+                .events
+                .last()
+                .map_or(Duration::ZERO, |event| event.time),
+            readings: count(|event| matches!(event, RecordedEvent::Readings(_))),
+            weights: count(|event| matches!(event, RecordedEvent::Weight(_))),
+            motor_commands: count(|event| matches!(event, RecordedEvent::Motor(_))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RecordingSummary {
+    id: String,
+    phidget_id: i32,
+    started: Duration,
+    duration: Duration,
+    readings: usize,
+    weights: usize,
+    motor_commands: usize,
+}
+
+struct ActiveRecording {
+    recording: Recording,
+    start: Instant,
+}
+
+// Shared by the scale and every motor handed out while a recording is running. Motor
+// commands are recorded as they are sent, scale reads only when a request samples them.
+#[derive(Clone)]
+pub struct Recorder(Arc<Mutex<ActiveRecording>>);
+impl Recorder {
+    pub fn new(phidget_id: i32, coefficients: Option<[f64; 4]>) -> Self {
+        let started = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        Self(Arc::new(Mutex::new(ActiveRecording {
+            recording: Recording {
+                version: RECORDING_VERSION,
+                id: format!("{phidget_id}-{}", started.as_millis()),
+                phidget_id,
+                coefficients,
+                started,
+                events: Vec::new(),
+            },
+            start: Instant::now(),
+        })))
+    }
+    pub fn get_id(&self) -> String {
+        self.0.lock().unwrap().recording.id.clone()
+    }
+    pub fn record(&self, event: RecordedEvent) {
+        self.record_at(Instant::now(), event);
+    }
+    // Samples taken in a chunk are recorded after the fact, with the time they were read
+    pub fn record_at(&self, at: Instant, event: RecordedEvent) {
+        let mut active = self.0.lock().unwrap();
+        let time = at.saturating_duration_since(active.start);
+        active.recording.events.push(TimedEvent { time, event });
+    }
+    fn into_recording(self) -> Recording {
+        let mut recording = self.0.lock().unwrap().recording.clone();
+        recording.events.sort_by_key(|event| event.time);
+        recording
+    }
+    pub fn finish(self, app: &AppHandle) -> Result<RecordingSummary, AppError> {
+        let recording = self.into_recording();
+        recording.save(app)?;
+        Ok(recording.summary())
+    }
+}
+
+// Wraps the connected scale so requests can record what they read from it
+pub struct RecordingScale {
+    scale: Box<dyn Scale>,
+    recorder: Option<Recorder>,
+}
+impl RecordingScale {
+    pub fn new(scale: Box<dyn Scale>) -> Self {
+        Self {
+            scale,
+            recorder: None,
+        }
+    }
+    pub fn set_recorder(&mut self, recorder: Option<Recorder>) {
+        self.recorder = recorder;
+    }
+    pub fn with_coefficients(self, coefficients: [f64; 4]) -> Self {
+        Self {
+            scale: self.scale.update_coefficients(coefficients),
+            recorder: self.recorder,
+        }
+    }
+}
+impl Scale for RecordingScale {
+    fn get_phidget_id(&self) -> i32 {
+        self.scale.get_phidget_id()
+    }
+    fn set_data_intervals(&mut self, interval: Duration) -> Result<(), AppError> {
+        self.scale.set_data_intervals(interval)
+    }
+    fn update_coefficients(self: Box<Self>, coefficients: [f64; 4]) -> Box<dyn Scale> {
+        Box::new(self.with_coefficients(coefficients))
+    }
+    fn get_coefficients(&self) -> Option<[f64; 4]> {
+        self.scale.get_coefficients()
+    }
+    fn get_raw_readings(&mut self) -> Result<[f64; 4], AppError> {
+        self.scale.get_raw_readings()
+    }
+    fn get_weight(&self) -> Result<f64, AppError> {
+        self.scale.get_weight()
+    }
+    // Each sample is recorded while recording, so a replay takes the same median
+    fn get_median_weight(&self, samples: usize, sample_period: Duration) -> Result<f64, AppError> {
+        match self.recorder.as_ref() {
+            Some(recorder) => {
+                scale::sample_median(&*self.scale, samples, sample_period, |weight| {
+                    recorder.record(RecordedEvent::Weight(weight))
+                })
+            }
+            None => self.scale.get_median_weight(samples, sample_period),
+        }
+    }
+    fn as_phidget(&mut self) -> Option<&mut ConnectedScale> {
+        self.scale.as_phidget()
+    }
+    fn get_recorder(&self) -> Option<Recorder> {
+        self.recorder.clone()
+    }
+}
+
+// Plays a recording back one read at a time, as fast as it is read. Weights and raw
+// readings are separate streams, each replayed in order, and samples are timestamped with
+// the time they were recorded at.
+pub struct ReplayScale {
+    recording: Recording,
+    weights: Cell<usize>,
+    readings: Cell<usize>,
+    first: Cell<Option<Duration>>,
+    latest: Cell<Duration>,
+}
+impl ReplayScale {
+    pub fn new(recording: Recording) -> Self {
+        Self {
+            recording,
+            weights: Cell::new(0),
+            readings: Cell::new(0),
+            first: Cell::new(None),
+            latest: Cell::new(Duration::ZERO),
+        }
+    }
+    // Moves `position` past the next event `read` accepts
+    fn next<T>(
+        &self,
+        position: &Cell<usize>,
+        read: impl Fn(RecordedEvent) -> Option<T>,
+    ) -> Result<T, AppError> {
+        let start = position.get();
+        let (index, time, value) = self.recording.events[start..]
+            .iter()
+            .enumerate()
+            .find_map(|(index, event)| read(event.event).map(|value| (index, event.time, value)))
+            .ok_or(AppError::ReplayFinished)?;
+        position.set(start + index + 1);
+        if self.first.get().is_none() {
+            self.first.set(Some(time));
+        }
+        self.latest.set(time);
+        Ok(value)
+    }
+}
+impl Scale for ReplayScale {
+    fn get_phidget_id(&self) -> i32 {
+        self.recording.phidget_id
+    }
+    fn set_data_intervals(&mut self, _interval: Duration) -> Result<(), AppError> {
+        Ok(())
+    }
+    fn update_coefficients(self: Box<Self>, _coefficients: [f64; 4]) -> Box<dyn Scale> {
+        self
+    }
+    fn get_coefficients(&self) -> Option<[f64; 4]> {
+        self.recording.coefficients
+    }
+    fn get_raw_readings(&mut self) -> Result<[f64; 4], AppError> {
+        self.next(&self.readings, |event| match event {
+            RecordedEvent::Readings(readings) => Some(readings),
+            _ => None,
+        })
+    }
+    fn get_weight(&self) -> Result<f64, AppError> {
+        self.next(&self.weights, |event| match event {
+            RecordedEvent::Weight(weight) => Some(weight),
+            _ => None,
+        })
+    }
+    // Older recordings kept each median as a single weight
+    fn get_median_weight(&self, samples: usize, sample_period: Duration) -> Result<f64, AppError> {
+        if self.recording.version < 3 {
+            return self.get_weight();
+        }
+        scale::sample_median(self, samples, sample_period, |_| {})
+    }
+    fn get_sample_period(&self, requested: Duration) -> Duration {
+        self.recording.get_sample_period().unwrap_or(requested)
+    }
+    fn wait(&self, _period: Duration) {}
+    fn timestamp(&self, _start: Instant) -> Duration {
+        self.latest
+            .get()
+            .saturating_sub(self.first.get().unwrap_or_default())
+    }
+}
+
+// Compares the motor commands a replayed dispense sent with the recorded ones. Speeds follow
+// the weights read between updates, so a replay that times its reads differently can go
+// its own way, the first difference shows where.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MotorCheck {
+    recorded: usize,
+    replayed: usize,
+    // None when the replay sent the recorded commands
+    first_difference: Option<usize>,
+}
+impl MotorCheck {
+    fn new(recorded: &[MotorCommand], replayed: &[MotorCommand]) -> Self {
+        let first_difference = recorded
+            .iter()
+            .zip(replayed)
+            .position(|(recorded, replayed)| recorded != replayed)
+            .or((recorded.len() != replayed.len()).then(|| recorded.len().min(replayed.len())));
+        Self {
+            recorded: recorded.len(),
+            replayed: replayed.len(),
+            first_difference,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct ReplayResult {
+    #[serde(flatten)]
+    trial: TrialData,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    motor_check: Option<MotorCheck>,
+}
+
+// Runs a request against a recording instead of the scale. A dispense drives a simulated
+// motor, and is checked against the motor commands of a recording of a single dispense.
+pub async fn replay(
+    recording: Recording,
+    data_request: DataRequest,
+    progress: &mut ProgressReporter,
+) -> Result<ReplayResult, AppError> {
+    let recorded = recording.get_motor_commands();
+    let phidget_id = recording.phidget_id;
+    let mut scale = ReplayScale::new(recording);
+    let cancel = CancelToken::default();
+    if !data_request.is_dispense() {
+        let data = data_request.conduct(&mut scale, &cancel, progress)?;
+        return Ok(ReplayResult {
+            trial: data.into(),
+            motor_check: None,
+        });
+    }
+    let simulated = SimulatedMotor::new(SimulatedMotorConfig::default());
+    simulated.enable()?;
+    let recorder = Recorder::new(phidget_id, None);
+    let motor = Motor::new(MotorDriver::Simulated(simulated), Some(recorder.clone()));
+    let trial = data_request
+        .dispense(&motor, &mut scale, &cancel, progress)
+        .await?;
+    let replayed = recorder.into_recording().get_motor_commands();
+    Ok(ReplayResult {
+        trial,
+        motor_check: Some(MotorCheck::new(&recorded, &replayed)),
+    })
+}
+
+fn path(app: &AppHandle, id: &str) -> Result<PathBuf, AppError> {
+    if id.is_empty()
+        || !id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(AppError::InvalidRecordingId(id.into()));
+    }
+    let dir = app
+        .path()
+        .app_data_dir()
+        .map_err(AppError::Tauri)?
+        .join("recordings");
+    fs::create_dir_all(&dir).map_err(AppError::Io)?;
+    Ok(dir.join(format!("{id}.json")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::{DataRequest, LoadCellDataRequest};
+    use crate::hardware::CancelToken;
+    use crate::progress::ProgressReporter;
+    use crate::scale::testing::TestScale;
+
+    fn millis(time: u64) -> Duration {
+        Duration::from_millis(time)
+    }
+
+    fn recording(events: &[(u64, RecordedEvent)]) -> Recording {
+        let recorder = Recorder::new(7, Some([1.; 4]));
+        let start = recorder.0.lock().unwrap().start;
+        for (time, event) in events {
+            recorder.record_at(start + millis(*time), *event);
+        }
+        recorder.into_recording()
+    }
+
+    fn raw_request(samples: usize) -> DataRequest {
+        serde_json::from_value(serde_json::json!({
+            "trial": "Raw",
+            "samples": samples,
+            "sample_period": { "secs": 0, "nanos": 1_000_000 },
+            "cutoff_frequency": null,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn recording_round_trips() {
+        // Chunks are recorded after their samples, so events can arrive out of order
+        let recording = recording(&[
+            (30, RecordedEvent::Weight(2.)),
+            (10, RecordedEvent::Weight(1.)),
+            (20, RecordedEvent::Motor(MotorCommand::RelativeMove(5.))),
+            (40, RecordedEvent::Readings([1., 2., 3., 4.])),
+        ]);
+        let json = serde_json::to_string(&recording).unwrap();
+        let loaded = Recording::from_json(&json).unwrap();
+        assert_eq!(loaded.id, recording.id);
+        assert_eq!(loaded.phidget_id, 7);
+        assert_eq!(loaded.coefficients, Some([1.; 4]));
+        let events: Vec<_> = loaded.events.iter().map(|e| (e.time, e.event)).collect();
+        assert_eq!(
+            events,
+            [
+                (millis(10), RecordedEvent::Weight(1.)),
+                (millis(20), RecordedEvent::Motor(MotorCommand::RelativeMove(5.))),
+                (millis(30), RecordedEvent::Weight(2.)),
+                (millis(40), RecordedEvent::Readings([1., 2., 3., 4.])),
+            ]
+        );
+    }
+
+    #[test]
+    fn newer_recordings_are_refused() {
+        let mut recording = recording(&[]);
+        recording.version = RECORDING_VERSION + 1;
+        let json = serde_json::to_string(&recording).unwrap();
+        assert!(matches!(
+            Recording::from_json(&json),
+            Err(AppError::RecordingVersion(_))
+        ));
+    }
+
+    #[test]
+    fn replay_uses_recorded_times() {
+        let recording = recording(&[
+            (100, RecordedEvent::Weight(1.)),
+            (110, RecordedEvent::Readings([1.; 4])),
+            (125, RecordedEvent::Weight(2.)),
+            (130, RecordedEvent::Motor(MotorCommand::AbruptStop)),
+            (160, RecordedEvent::Weight(3.)),
+            (170, RecordedEvent::Readings([2.; 4])),
+        ]);
+        let mut scale = ReplayScale::new(recording.clone());
+        // The replay ends the request early once the recorded weights run out
+        let data = raw_request(10)
+            .conduct(
+                &mut scale,
+                &CancelToken::default(),
+                &mut ProgressReporter::silent(),
+            )
+            .unwrap();
+        assert_eq!(data.readings, [1., 2., 3.]);
+        assert_eq!(data.times, [millis(0), millis(25), millis(60)]);
+
+        let mut scale = ReplayScale::new(recording);
+        let data = LoadCellDataRequest::new(2, millis(1))
+            .conduct(
+                &mut scale,
+                &CancelToken::default(),
+                &mut ProgressReporter::silent(),
+            )
+            .unwrap();
+        assert_eq!(data[0].readings, [1., 2.]);
+        assert_eq!(data[3].times, [millis(0), millis(60)]);
+    }
+
+    #[test]
+    fn only_request_samples_are_recorded() {
+        let mut scale = RecordingScale::new(Box::new(TestScale::new([1.; 4])));
+        let recorder = Recorder::new(7, None);
+        scale.set_recorder(Some(recorder.clone()));
+        // Background reads of the scale stay out of the recording
+        scale.get_weight().unwrap();
+        scale.get_raw_readings().unwrap();
+        raw_request(3)
+            .conduct(
+                &mut scale,
+                &CancelToken::default(),
+                &mut ProgressReporter::silent(),
+            )
+            .unwrap();
+        let events: Vec<_> = recorder
+            .into_recording()
+            .events
+            .iter()
+            .map(|e| e.event)
+            .collect();
+        assert_eq!(events, [RecordedEvent::Weight(4.); 3]);
+    }
+
+    #[test]
+    fn replay_takes_the_median_the_request_asked_for() {
+        let events = [
+            (10, RecordedEvent::Weight(1.)),
+            (20, RecordedEvent::Weight(5.)),
+            (30, RecordedEvent::Weight(3.)),
+        ];
+        let scale = ReplayScale::new(recording(&events));
+        assert_eq!(scale.get_median_weight(3, millis(10)).unwrap(), 3.);
+        assert!(matches!(
+            scale.get_median_weight(1, millis(10)),
+            Err(AppError::ReplayFinished)
+        ));
+
+        // Older recordings kept only the median
+        let mut older = recording(&events);
+        older.version = 2;
+        let scale = ReplayScale::new(older);
+        assert_eq!(scale.get_median_weight(3, millis(10)).unwrap(), 1.);
+        assert_eq!(scale.get_median_weight(3, millis(10)).unwrap(), 5.);
+    }
+
+    #[test]
+    fn each_median_sample_is_recorded() {
+        let mut scale = RecordingScale::new(Box::new(TestScale::new([1.; 4])));
+        // Nothing to record it with yet
+        assert_eq!(scale.get_median_weight(3, millis(1)).unwrap(), 4.);
+        let recorder = Recorder::new(7, None);
+        scale.set_recorder(Some(recorder.clone()));
+        assert_eq!(scale.get_median_weight(3, millis(1)).unwrap(), 4.);
+        let events: Vec<_> = recorder
+            .into_recording()
+            .events
+            .iter()
+            .map(|e| e.event)
+            .collect();
+        assert_eq!(events, [RecordedEvent::Weight(4.); 3]);
+    }
+
+    #[test]
+    fn replay_samples_at_the_recorded_period() {
+        let scale = ReplayScale::new(recording(&[
+            (0, RecordedEvent::Weight(1.)),
+            (5, RecordedEvent::Readings([1.; 4])),
+            (10, RecordedEvent::Weight(2.)),
+            (20, RecordedEvent::Weight(3.)),
+            (40, RecordedEvent::Weight(4.)),
+        ]));
+        assert_eq!(scale.get_sample_period(millis(1)), millis(10));
+        let scale = ReplayScale::new(recording(&[(0, RecordedEvent::Weight(1.))]));
+        assert_eq!(scale.get_sample_period(millis(1)), millis(1));
+    }
+
+    #[test]
+    fn motor_check_finds_the_first_difference() {
+        let recorded = [
+            MotorCommand::SetVelocity(1.),
+            MotorCommand::RelativeMove(5.),
+            MotorCommand::AbruptStop,
+        ];
+        assert_eq!(
+            MotorCheck::new(&recorded, &recorded),
+            MotorCheck {
+                recorded: 3,
+                replayed: 3,
+                first_difference: None,
+            }
+        );
+        let first_difference =
+            |replayed: &[MotorCommand]| MotorCheck::new(&recorded, replayed).first_difference;
+        let replayed = [
+            MotorCommand::SetVelocity(1.),
+            MotorCommand::SetVelocity(0.5),
+        ];
+        assert_eq!(first_difference(&replayed), Some(1));
+        assert_eq!(first_difference(&recorded[..2]), Some(2));
+        assert_eq!(
+            MotorCheck::new(&recorded[..1], &recorded).first_difference,
+            Some(1)
+        );
+    }
+}
@@ -0,0 +1,344 @@
+use crate::calibration_data::{CalibrationData, Coefficients};
+use crate::errors::AppError;
+use serde::{Deserialize, Serialize};
+
+const CONDITION_WARNING: f64 = 1e3;
+const MIN_CELL_EXCITATION: f64 = 0.05;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TrialFit {
+    index: usize,
+    actual: f64,
+    predicted: f64,
+    residual: f64,
+}
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct FitReport {
+    trials: Vec<TrialFit>,
+    r_squared: Option<f64>,
+    rmse: f64,
+    max_error: f64,
+    capacity: f64,
+    max_error_percent_capacity: f64,
+    condition_number: f64,
+    warnings: Vec<String>,
+}
+impl FitReport {
+    pub fn new(
+        calibration_data: &CalibrationData,
+        coefficients: &Coefficients,
+        capacity: f64,
+    ) -> Result<Self, AppError> {
+        if capacity <= 0. {
+            return Err(AppError::InvalidCapacity(capacity));
+        }
+        let trials = calibration_data.get_trials();
+        if trials.is_empty() {
+            return Err(AppError::InsufficientTrials {
+                required: 1,
+                actual: 0,
+            });
+        }
+        calibration_data.check_readings()?;
+        let fits: Vec<TrialFit> = trials
+            .iter()
+            .enumerate()
+            .map(|(index, trial)| {
+                let predicted = coefficients.predict(trial.get_readings());
+                TrialFit {
+                    index,
+                    actual: trial.get_weight(),
+                    predicted,
+                    residual: predicted - trial.get_weight(),
+                }
+            })
+            .collect();
+
+        let n = fits.len() as f64;
+        let mean = fits.iter().map(|fit| fit.actual).sum::<f64>() / n;
+        let ss_res: f64 = fits.iter().map(|fit| fit.residual.powi(2)).sum();
+        let ss_tot: f64 = fits.iter().map(|fit| (fit.actual - mean).powi(2)).sum();
+        let r_squared = (ss_tot > 0.).then(|| 1. - ss_res / ss_tot);
+        let rmse = (ss_res / n).sqrt();
+        let max_error = fits.iter().map(|fit| fit.residual.abs()).fold(0., f64::max);
+
+        let fit_offset = coefficients.get_offset().is_some();
+        let mut warnings = Vec::new();
+        if let Some(offset) = coefficients.get_offset().filter(|offset| *offset != 0.) {
+            warnings.push(format!(
+                "Fitted offset {offset:.3e} is not applied by the scale, residuals are computed without it"
+            ));
+        }
+        let unknowns = if fit_offset { 5 } else { 4 };
+        if trials.len() <= unknowns {
+            warnings.push(format!(
+                "Only {} trials for {} unknowns, residuals cannot show a bad fit",
+                trials.len(),
+                unknowns
+            ));
+        }
+        let condition_number = condition_number(calibration_data, fit_offset);
+        if !condition_number.is_finite() || condition_number > CONDITION_WARNING {
+            warnings.push(format!(
+                "Trial set is ill-conditioned (condition number {condition_number:.1}), place weights at more positions"
+            ));
+        }
+        warnings.extend(excitation_warnings(calibration_data));
+
+        Ok(Self {
+            trials: fits,
+            r_squared,
+            rmse,
+            max_error,
+            capacity,
+            max_error_percent_capacity: 100. * max_error / capacity,
+            condition_number,
+            warnings,
+        })
+    }
+}
+
+// Condition number of the design matrix with unit-norm columns, so it reflects the
+// trial placement rather than the magnitude of the raw readings.
+fn condition_number(calibration_data: &CalibrationData, fit_offset: bool) -> f64 {
+    let rows: Vec<Vec<f64>> = calibration_data
+        .get_trials()
+        .iter()
+        .map(|trial| {
+            let mut row = trial.get_readings().to_vec();
+            if fit_offset {
+                row.push(1.);
+            }
+            row
+        })
+        .collect();
+    let unknowns = rows[0].len();
+    let norms: Vec<f64> = (0..unknowns)
+        .map(|j| rows.iter().map(|row| row[j].powi(2)).sum::<f64>().sqrt())
+        .collect();
+    if norms.contains(&0.) {
+        return f64::INFINITY;
+    }
+    let mut gram = vec![vec![0_f64; unknowns]; unknowns];
+    for row in &rows {
+        for i in 0..unknowns {
+            for j in 0..unknowns {
+                gram[i][j] += row[i] / norms[i] * row[j] / norms[j];
+            }
+        }
+    }
+    let eigenvalues = symmetric_eigenvalues(gram);
+    let max = eigenvalues.iter().cloned().fold(f64::MIN, f64::max);
+    let min = eigenvalues.iter().cloned().fold(f64::MAX, f64::min);
+    if min <= 0. {
+        f64::INFINITY
+    } else {
+        (max / min).sqrt()
+    }
+}
+
+fn excitation_warnings(calibration_data: &CalibrationData) -> Vec<String> {
+    let trials = calibration_data.get_trials();
+    let ranges: Vec<f64> = (0..4)
+        .map(|cell| {
+            let readings = trials.iter().map(|trial| trial.get_readings()[cell]);
+            let max = readings.clone().fold(f64::MIN, f64::max);
+            let min = readings.fold(f64::MAX, f64::min);
+            max - min
+        })
+        .collect();
+    let largest = ranges.iter().cloned().fold(0., f64::max);
+    ranges
+        .iter()
+        .enumerate()
+        .filter(|(_, range)| **range < MIN_CELL_EXCITATION * largest)
+        .map(|(cell, _)| {
+            format!("Load cell {cell} barely changes across trials, its coefficient is poorly determined")
+        })
+        .collect()
+}
+
+// Cyclic Jacobi rotations, plenty for the 4x4/5x5 matrices used here.
+fn symmetric_eigenvalues(mut a: Vec<Vec<f64>>) -> Vec<f64> {
+    let n = a.len();
+    for _ in 0..100 {
+        let off_diagonal: f64 = (0..n)
+            .flat_map(|i| (0..n).filter(move |&j| j != i).map(move |j| (i, j)))
+            .map(|(i, j)| a[i][j].powi(2))
+            .sum();
+        if off_diagonal < 1e-20 {
+            break;
+        }
+        for p in 0..n {
+            for q in p + 1..n {
+                if a[p][q].abs() < 1e-300 {
+                    continue;
+                }
+                let theta = (a[q][q] - a[p][p]) / (2. * a[p][q]);
+                let t = theta.signum() / (theta.abs() + (theta * theta + 1.).sqrt());
+                let c = 1. / (t * t + 1.).sqrt();
+                let s = t * c;
+                for row in a.iter_mut() {
+                    let (akp, akq) = (row[p], row[q]);
+                    row[p] = c * akp - s * akq;
+                    row[q] = s * akp + c * akq;
+                }
+                let (head, tail) = a.split_at_mut(q);
+                for (apk, aqk) in head[p].iter_mut().zip(tail[0].iter_mut()) {
+                    let (x, y) = (*apk, *aqk);
+                    *apk = c * x - s * y;
+                    *aqk = s * x + c * y;
+                }
+            }
+        }
+    }
+    (0..n).map(|i| a[i][i]).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::calibration_data::CalibrationTrial;
+
+    fn calibration_data(trials: &[([f64; 4], f64)]) -> CalibrationData {
+        let mut calibration_data = CalibrationData::new(1);
+        for (readings, weight) in trials {
+            calibration_data.add_trial(CalibrationTrial::from_array(*readings, *weight));
+        }
+        calibration_data
+    }
+    fn unit_coefficients() -> Coefficients {
+        Coefficients::new([1.; 4], None)
+    }
+    // Each cell on its own, then all four, weighed 1 g heavy on the last trial
+    const TRIALS: [([f64; 4], f64); 5] = [
+        ([10., 0., 0., 0.], 10.),
+        ([0., 10., 0., 0.], 10.),
+        ([0., 0., 10., 0.], 10.),
+        ([0., 0., 0., 10.], 10.),
+        ([10., 10., 10., 10.], 41.),
+    ];
+
+    #[test]
+    fn residuals_of_the_applied_coefficients() {
+        let report =
+            FitReport::new(&calibration_data(&TRIALS), &unit_coefficients(), 100.).unwrap();
+        let residuals: Vec<f64> = report.trials.iter().map(|fit| fit.residual).collect();
+        assert_eq!(residuals, [0., 0., 0., 0., -1.]);
+        assert_eq!(report.max_error, 1.);
+        assert!((report.rmse - (1_f64 / 5.).sqrt()).abs() < 1e-12);
+        assert_eq!(report.max_error_percent_capacity, 1.);
+        // Mean weight 16.2, so ss_tot = 4 * 6.2² + 24.8²
+        let ss_tot = 4. * 6.2_f64.powi(2) + 24.8_f64.powi(2);
+        assert!((report.r_squared.unwrap() - (1. - 1. / ss_tot)).abs() < 1e-12);
+    }
+
+    #[test]
+    fn offsets_are_left_out_of_the_residuals() {
+        let coefficients = Coefficients::new([1.; 4], Some(1.));
+        let report = FitReport::new(&calibration_data(&TRIALS), &coefficients, 100.).unwrap();
+        assert_eq!(report.trials[0].predicted, 10.);
+        assert!(report
+            .warnings
+            .iter()
+            .any(|warning| warning.contains("not applied")));
+    }
+
+    #[test]
+    fn rejects_bad_capacities_and_reading_counts() {
+        let calibration_data = calibration_data(&TRIALS);
+        assert!(matches!(
+            FitReport::new(&calibration_data, &unit_coefficients(), 0.),
+            Err(AppError::InvalidCapacity(_))
+        ));
+        let mut calibration_data = calibration_data;
+        let trial: CalibrationTrial = serde_json::from_value(serde_json::json!({
+            "readings": [1., 2.],
+            "weight": 1.,
+            "timestamp": { "secs": 0, "nanos": 0 },
+        }))
+        .unwrap();
+        calibration_data.add_trial(trial);
+        assert!(matches!(
+            FitReport::new(&calibration_data, &unit_coefficients(), 100.),
+            Err(AppError::TrialReadings {
+                index: 5,
+                readings: 2
+            })
+        ));
+    }
+
+    #[test]
+    fn eigenvalues_of_a_known_matrix() {
+        let mut eigenvalues = symmetric_eigenvalues(vec![vec![2., 1.], vec![1., 2.]]);
+        eigenvalues.sort_by(f64::total_cmp);
+        assert!((eigenvalues[0] - 1.).abs() < 1e-12);
+        assert!((eigenvalues[1] - 3.).abs() < 1e-12);
+
+        let mut eigenvalues = symmetric_eigenvalues(vec![
+            vec![4., 1., 0., 0.],
+            vec![1., 4., 0., 0.],
+            vec![0., 0., 2., 0.],
+            vec![0., 0., 0., 7.],
+        ]);
+        eigenvalues.sort_by(f64::total_cmp);
+        for (eigenvalue, expected) in eigenvalues.iter().zip([2., 3., 5., 7.]) {
+            assert!(
+                (eigenvalue - expected).abs() < 1e-12,
+                "{eigenvalue} != {expected}"
+            );
+        }
+    }
+
+    #[test]
+    fn condition_number_of_the_design() {
+        // Orthogonal columns of any scale are perfectly conditioned
+        let orthogonal = calibration_data(&[
+            ([1., 0., 0., 0.], 1.),
+            ([0., 1000., 0., 0.], 1.),
+            ([0., 0., 0.01, 0.], 1.),
+            ([0., 0., 0., 5.], 1.),
+        ]);
+        assert!((condition_number(&orthogonal, false) - 1.).abs() < 1e-12);
+        // Unit columns [1, 1] and [1, 0] / [0, 1] give a Gram matrix of [[1, a], [a, 1]]
+        // blocks with a = 1/√2, so the condition number is √((1 + a) / (1 - a))
+        let paired = calibration_data(&[
+            ([1., 1., 0., 0.], 1.),
+            ([1., 0., 0., 0.], 1.),
+            ([0., 0., 1., 1.], 1.),
+            ([0., 0., 1., 0.], 1.),
+        ]);
+        let a = 0.5_f64.sqrt();
+        let expected = ((1. + a) / (1. - a)).sqrt();
+        assert!((condition_number(&paired, false) - expected).abs() < 1e-9);
+        // Cells that always move together cannot be told apart
+        let collinear = calibration_data(&[
+            ([1., 1., 0., 0.], 1.),
+            ([2., 2., 0., 0.], 2.),
+            ([0., 0., 1., 0.], 1.),
+            ([0., 0., 0., 1.], 1.),
+            ([1., 1., 1., 1.], 3.),
+        ]);
+        let condition = condition_number(&collinear, false);
+        assert!(!condition.is_finite() || condition > CONDITION_WARNING);
+        let report = FitReport::new(&collinear, &unit_coefficients(), 100.).unwrap();
+        assert!(report
+            .warnings
+            .iter()
+            .any(|warning| warning.contains("ill-conditioned")));
+    }
+
+    #[test]
+    fn warns_about_cells_that_barely_move() {
+        let calibration_data = calibration_data(&[
+            ([10., 0., 0., 1.], 10.),
+            ([0., 10., 0., 1.], 10.),
+            ([0., 0., 10., 1.], 10.),
+            ([10., 10., 10., 1.2], 30.),
+        ]);
+        let warnings = excitation_warnings(&calibration_data);
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].starts_with("Load cell 3 "));
+        assert!(excitation_warnings(&self::calibration_data(&TRIALS)).is_empty());
+    }
+}
@@ -0,0 +1,168 @@
+use crate::errors::AppError;
+use crate::recording::Recorder;
+use crate::statistics;
+use libra::scale::ConnectedScale;
+use node_diagnostics::trial::LoadCellTrial;
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub trait Scale: Send {
+    fn get_phidget_id(&self) -> i32;
+    fn set_data_intervals(&mut self, interval: Duration) -> Result<(), AppError>;
+    fn update_coefficients(self: Box<Self>, coefficients: [f64; 4]) -> Box<dyn Scale>;
+    // None when the scale cannot tell, like a replayed recording made without them
+    fn get_coefficients(&self) -> Option<[f64; 4]>;
+    fn get_raw_readings(&mut self) -> Result<[f64; 4], AppError>;
+    fn get_weight(&self) -> Result<f64, AppError>;
+    // Waits out a sample period between reads
+    fn wait(&self, period: Duration) {
+        thread::sleep(period);
+    }
+    fn get_median_weight(&self, samples: usize, sample_period: Duration) -> Result<f64, AppError> {
+        sample_median(self, samples, sample_period, |_| {})
+    }
+    // Period the samples are read at, a replay reads them at the recorded one
+    fn get_sample_period(&self, requested: Duration) -> Duration {
+        requested
+    }
+    // The Phidget bridge keeps using the node-diagnostics trials for acquisition
+    fn as_phidget(&mut self) -> Option<&mut ConnectedScale> {
+        None
+    }
+    // Set while a recording is running, requests record the samples they read with it
+    fn get_recorder(&self) -> Option<Recorder> {
+        None
+    }
+    // Time since `start` of the sample just read
+    fn timestamp(&self, start: Instant) -> Duration {
+        start.elapsed()
+    }
+}
+
+// Median of `samples` weights a sample period apart, handing each weight to `read` as well
+pub fn sample_median(
+    scale: &(impl Scale + ?Sized),
+    samples: usize,
+    sample_period: Duration,
+    mut read: impl FnMut(f64),
+) -> Result<f64, AppError> {
+    let mut weights = Vec::with_capacity(samples);
+    for _ in 0..samples {
+        let weight = scale.get_weight()?;
+        read(weight);
+        weights.push(weight);
+        scale.wait(sample_period);
+    }
+    statistics::median(&weights).ok_or(AppError::ZeroSamples)
+}
+
+impl Scale for ConnectedScale {
+    fn get_phidget_id(&self) -> i32 {
+        ConnectedScale::get_phidget_id(self)
+    }
+    fn set_data_intervals(&mut self, interval: Duration) -> Result<(), AppError> {
+        ConnectedScale::set_data_intervals(self, interval).map_err(AppError::Libra)
+    }
+    fn update_coefficients(self: Box<Self>, coefficients: [f64; 4]) -> Box<dyn Scale> {
+        Box::new(ConnectedScale::update_coefficients(*self, coefficients))
+    }
+    fn get_coefficients(&self) -> Option<[f64; 4]> {
+        Some(ConnectedScale::get_coefficients(self))
+    }
+    // Reads each load cell through the node diagnostics load cell trial. Its first sample
+    // is bad, so two are taken and the first is dropped, like the other node trials.
+    fn get_raw_readings(&mut self) -> Result<[f64; 4], AppError> {
+        let data = LoadCellTrial::new(2, Duration::ZERO)
+            .conduct(self)
+            .map_err(AppError::NodeDiagnostics)?;
+        let readings = data.each_ref().map(|cell| cell.readings.get(1).copied());
+        if readings.iter().any(Option::is_none) {
+            return Err(AppError::ZeroSamples);
+        }
+        Ok(readings.map(Option::unwrap))
+    }
+    fn get_weight(&self) -> Result<f64, AppError> {
+        Ok(ConnectedScale::get_weight(self)
+            .map_err(AppError::Libra)?
+            .get())
+    }
+    fn get_median_weight(&self, samples: usize, sample_period: Duration) -> Result<f64, AppError> {
+        Ok(
+            ConnectedScale::get_median_weight(self, samples, sample_period)
+                .map_err(AppError::Libra)?
+                .get(),
+        )
+    }
+    fn as_phidget(&mut self) -> Option<&mut ConnectedScale> {
+        Some(self)
+    }
+}
+
+#[cfg(test)]
+pub mod testing {
+    use super::Scale;
+    use crate::errors::AppError;
+    use std::cell::Cell;
+    use std::time::Duration;
+
+    // Reads fixed cell values weighed with its coefficients, and fails every read once its
+    // budget of reads is used up, like a scale that has been unplugged
+    pub struct TestScale {
+        readings: [f64; 4],
+        coefficients: [f64; 4],
+        reads_left: Cell<Option<usize>>,
+    }
+    impl TestScale {
+        pub fn new(readings: [f64; 4]) -> Self {
+            Self {
+                readings,
+                coefficients: [1.; 4],
+                reads_left: Cell::new(None),
+            }
+        }
+        pub fn failing_after(self, reads: usize) -> Self {
+            self.reads_left.set(Some(reads));
+            self
+        }
+        pub fn reconnect(&self) {
+            self.reads_left.set(None);
+        }
+        fn read(&self) -> Result<[f64; 4], AppError> {
+            match self.reads_left.get() {
+                Some(0) => Err(AppError::Other("Scale disconnected".into())),
+                Some(reads) => {
+                    self.reads_left.set(Some(reads - 1));
+                    Ok(self.readings)
+                }
+                None => Ok(self.readings),
+            }
+        }
+    }
+    impl Scale for TestScale {
+        fn get_phidget_id(&self) -> i32 {
+            1
+        }
+        fn set_data_intervals(&mut self, _interval: Duration) -> Result<(), AppError> {
+            Ok(())
+        }
+        fn update_coefficients(mut self: Box<Self>, coefficients: [f64; 4]) -> Box<dyn Scale> {
+            self.coefficients = coefficients;
+            self
+        }
+        fn get_coefficients(&self) -> Option<[f64; 4]> {
+            Some(self.coefficients)
+        }
+        fn get_raw_readings(&mut self) -> Result<[f64; 4], AppError> {
+            self.read()
+        }
+        fn get_weight(&self) -> Result<f64, AppError> {
+            Ok(self
+                .read()?
+                .iter()
+                .zip(self.coefficients)
+                .map(|(reading, coefficient)| reading * coefficient)
+                .sum())
+        }
+        fn wait(&self, _period: Duration) {}
+    }
+}
@@ -0,0 +1,349 @@
+use crate::calibration_data::{CalibrationData, CalibrationTrial, Coefficients};
+use crate::errors::AppError;
+use crate::procedure::{CalibrationProcedure, ProcedureStatus};
+use crate::solver::Solver;
+use crate::verification::VerificationRun;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tauri::Manager;
+
+const SESSION_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CalibrationSession {
+    version: u32,
+    id: String,
+    created: Duration,
+    updated: Duration,
+    #[serde(default)]
+    notes: String,
+    #[serde(default)]
+    coefficients: Option<Coefficients>,
+    #[serde(default)]
+    solver: Solver,
+    calibration_data: CalibrationData,
+    #[serde(default)]
+    verifications: Vec<VerificationRun>,
+    #[serde(default)]
+    procedure: Option<CalibrationProcedure>,
+}
+impl CalibrationSession {
+    pub fn new(phidget_id: i32) -> Self {
+        let now = now();
+        Self {
+            version: SESSION_VERSION,
+            id: format!("{phidget_id}-{}", now.as_millis()),
+            created: now,
+            updated: now,
+            notes: String::new(),
+            coefficients: None,
+            solver: Solver::default(),
+            calibration_data: CalibrationData::new(phidget_id),
+            verifications: Vec::new(),
+            procedure: None,
+        }
+    }
+    pub fn get_id(&self) -> &str {
+        &self.id
+    }
+    pub fn get_phidget_id(&self) -> i32 {
+        self.calibration_data.get_phidget_id()
+    }
+    pub fn get_calibration_data(&self) -> &CalibrationData {
+        &self.calibration_data
+    }
+    pub fn get_mut_calibration_data(&mut self) -> &mut CalibrationData {
+        &mut self.calibration_data
+    }
+    pub fn add_trial(&mut self, trial: CalibrationTrial) {
+        self.calibration_data.add_trial(trial);
+    }
+    pub fn set_coefficients(&mut self, coefficients: Coefficients) {
+        self.coefficients.replace(coefficients);
+    }
+    pub fn get_solver(&self) -> Solver {
+        self.solver
+    }
+    pub fn set_solver(&mut self, solver: Solver) {
+        self.solver = solver;
+    }
+    pub fn add_verification(&mut self, verification: VerificationRun) {
+        self.verifications.push(verification);
+    }
+    pub fn get_verification(&self) -> Option<&VerificationRun> {
+        self.verifications.last()
+    }
+    pub fn get_mut_verification(&mut self) -> Option<&mut VerificationRun> {
+        self.verifications.last_mut()
+    }
+    pub fn start_procedure(&mut self, procedure: CalibrationProcedure) {
+        self.procedure.replace(procedure);
+    }
+    pub fn get_procedure(&self) -> Option<&CalibrationProcedure> {
+        self.procedure.as_ref()
+    }
+    // A recaptured step replaces its earlier trial instead of adding another
+    pub fn record_procedure_step(
+        &mut self,
+        index: usize,
+        trial: CalibrationTrial,
+    ) -> Result<ProcedureStatus, AppError> {
+        let procedure = self.procedure.as_mut().ok_or(AppError::NoProcedure)?;
+        let previous = procedure.complete_step(index, trial.clone())?;
+        let status = procedure.status();
+        let calibration_data = &mut self.calibration_data;
+        match previous.and_then(|previous| calibration_data.find_trial(previous.get_timestamp())) {
+            Some(trial_index) => {
+                calibration_data.replace_trial(trial_index, trial)?;
+            }
+            None => calibration_data.add_trial(trial),
+        }
+        Ok(status)
+    }
+    pub fn set_notes(&mut self, notes: String) {
+        self.notes = notes;
+    }
+    fn summary(&self) -> SessionSummary {
+        SessionSummary {
+            id: self.id.clone(),
+            phidget_id: self.get_phidget_id(),
+            created: self.created,
+            updated: self.updated,
+            trials: self.calibration_data.get_trials().len(),
+            has_coefficients: self.coefficients.is_some(),
+            notes: self.notes.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionSummary {
+    id: String,
+    phidget_id: i32,
+    created: Duration,
+    updated: Duration,
+    trials: usize,
+    has_coefficients: bool,
+    notes: String,
+}
+
+pub struct SessionStore {
+    dir: PathBuf,
+}
+impl SessionStore {
+    pub fn new(app: &tauri::AppHandle) -> Result<Self, AppError> {
+        let dir = app
+            .path()
+            .app_data_dir()
+            .map_err(AppError::Tauri)?
+            .join("sessions");
+        Self::in_dir(dir)
+    }
+    fn in_dir(dir: PathBuf) -> Result<Self, AppError> {
+        fs::create_dir_all(&dir).map_err(AppError::Io)?;
+        Ok(Self { dir })
+    }
+    pub fn save(&self, session: &mut CalibrationSession) -> Result<(), AppError> {
+        session.updated = now();
+        let contents = serde_json::to_string_pretty(session).map_err(AppError::Serde)?;
+        // Write then rename so a crash mid-save never leaves a truncated session behind
+        let path = self.path(&session.id)?;
+        let tmp = path.with_extension("json.tmp");
+        fs::write(&tmp, contents).map_err(AppError::Io)?;
+        fs::rename(tmp, path).map_err(AppError::Io)
+    }
+    pub fn load(&self, id: &str) -> Result<CalibrationSession, AppError> {
+        let contents = fs::read_to_string(self.path(id)?).map_err(AppError::Io)?;
+        let session: CalibrationSession =
+            serde_json::from_str(&contents).map_err(AppError::Serde)?;
+        if session.version > SESSION_VERSION {
+            return Err(AppError::SessionVersion(session.version));
+        }
+        session.calibration_data.check_readings()?;
+        Ok(session)
+    }
+    pub fn list(&self) -> Result<Vec<SessionSummary>, AppError> {
+        let mut summaries = Vec::new();
+        for entry in fs::read_dir(&self.dir).map_err(AppError::Io)? {
+            let path = entry.map_err(AppError::Io)?.path();
+            if path.extension().is_none_or(|extension| extension != "json") {
+                continue;
+            }
+            let Some(id) = path.file_stem().and_then(|stem| stem.to_str()) else {
+                continue;
+            };
+            match self.load(id) {
+                Ok(session) => summaries.push(session.summary()),
+                Err(e) => log::warn!("Skipping unreadable session {id}: {e}"),
+            }
+        }
+        summaries.sort_by_key(|summary| std::cmp::Reverse(summary.updated));
+        Ok(summaries)
+    }
+    pub fn delete(&self, id: &str) -> Result<(), AppError> {
+        fs::remove_file(self.path(id)?).map_err(AppError::Io)
+    }
+    fn path(&self, id: &str) -> Result<PathBuf, AppError> {
+        if id.is_empty()
+            || !id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(AppError::InvalidSessionId(id.into()));
+        }
+        Ok(self.dir.join(format!("{id}.json")))
+    }
+}
+
+fn now() -> Duration {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::procedure::CalibrationPlan;
+
+    fn store(name: &str) -> SessionStore {
+        let dir = std::env::temp_dir().join(format!(
+            "caldo-sessions-{name}-{}-{}",
+            std::process::id(),
+            now().as_nanos()
+        ));
+        SessionStore::in_dir(dir).unwrap()
+    }
+    fn session() -> CalibrationSession {
+        let mut session = CalibrationSession::new(716709);
+        session.add_trial(CalibrationTrial::from_array([1., 2., 3., 4.], 10.));
+        session.add_trial(CalibrationTrial::from_array([4., 3., 2., 1.], 20.));
+        session.set_notes("left shelf".into());
+        session.set_solver(Solver::Local);
+        session
+    }
+
+    #[test]
+    fn round_trips_a_session() {
+        let store = store("round-trip");
+        let mut session = session();
+        store.save(&mut session).unwrap();
+        let loaded = store.load(session.get_id()).unwrap();
+        assert_eq!(
+            serde_json::to_value(&loaded).unwrap(),
+            serde_json::to_value(&session).unwrap()
+        );
+        let summaries = store.list().unwrap();
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[0].id, session.get_id());
+        assert_eq!(summaries[0].trials, 2);
+        store.delete(session.get_id()).unwrap();
+        assert!(store.list().unwrap().is_empty());
+        fs::remove_dir_all(&store.dir).unwrap();
+    }
+
+    #[test]
+    fn keeps_the_procedure_across_reloads() {
+        let store = store("procedure");
+        let mut session = CalibrationSession::new(716709);
+        session.start_procedure(CalibrationProcedure::new(CalibrationPlan::default()).unwrap());
+        session
+            .record_procedure_step(0, CalibrationTrial::from_array([1., 2., 3., 4.], 0.))
+            .unwrap();
+        store.save(&mut session).unwrap();
+        let mut loaded = store.load(session.get_id()).unwrap();
+        let status = serde_json::to_value(loaded.get_procedure().unwrap().status()).unwrap();
+        assert_eq!(status["next_step"], 1);
+        assert_eq!(status["steps"][0]["done"], true);
+        // Recapturing after a reload still replaces the trial rather than adding one
+        loaded
+            .record_procedure_step(0, CalibrationTrial::from_array([1., 2., 3., 5.], 0.))
+            .unwrap();
+        assert_eq!(loaded.get_calibration_data().get_trials().len(), 1);
+        fs::remove_dir_all(&store.dir).unwrap();
+    }
+
+    #[test]
+    fn migrates_older_sessions_and_rejects_newer_ones() {
+        let store = store("version");
+        let mut session = session();
+        let mut contents = serde_json::to_value(&session).unwrap();
+        // Sessions from before notes, coefficients, solvers, verifications and procedures existed
+        for field in [
+            "notes",
+            "coefficients",
+            "solver",
+            "verifications",
+            "procedure",
+        ] {
+            contents.as_object_mut().unwrap().remove(field);
+        }
+        let path = store.path(session.get_id()).unwrap();
+        fs::write(&path, contents.to_string()).unwrap();
+        let loaded = store.load(session.get_id()).unwrap();
+        assert_eq!(loaded.get_solver(), Solver::Cloud);
+        assert_eq!(loaded.get_calibration_data().get_trials().len(), 2);
+        assert!(loaded.get_verification().is_none());
+
+        session.version = SESSION_VERSION + 1;
+        fs::write(&path, serde_json::to_string(&session).unwrap()).unwrap();
+        assert!(matches!(
+            store.load(session.get_id()),
+            Err(AppError::SessionVersion(version)) if version == SESSION_VERSION + 1
+        ));
+        // Unreadable sessions are skipped rather than failing the whole list
+        assert!(store.list().unwrap().is_empty());
+        fs::remove_dir_all(&store.dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_bad_session_ids() {
+        let store = store("ids");
+        for id in [
+            "",
+            "../sessions",
+            "..",
+            "a/b",
+            "a\\b",
+            "716709.json",
+            "séance",
+        ] {
+            assert!(
+                matches!(store.load(id), Err(AppError::InvalidSessionId(_))),
+                "{id:?} was accepted"
+            );
+            assert!(matches!(
+                store.delete(id),
+                Err(AppError::InvalidSessionId(_))
+            ));
+        }
+        let mut session = session();
+        session.id = "../escaped".into();
+        assert!(matches!(
+            store.save(&mut session),
+            Err(AppError::InvalidSessionId(_))
+        ));
+        assert!(!store.dir.parent().unwrap().join("escaped.json").exists());
+        fs::remove_dir_all(&store.dir).unwrap();
+    }
+
+    #[test]
+    fn overwrites_sessions_atomically() {
+        let store = store("overwrite");
+        let mut session = session();
+        store.save(&mut session).unwrap();
+        let path = store.path(session.get_id()).unwrap();
+        // A crash mid-save leaves only the temporary file behind
+        fs::write(path.with_extension("json.tmp"), "{ truncated").unwrap();
+        assert_eq!(store.list().unwrap().len(), 1);
+
+        session.add_trial(CalibrationTrial::from_array([5., 5., 5., 5.], 40.));
+        store.save(&mut session).unwrap();
+        let loaded = store.load(session.get_id()).unwrap();
+        assert_eq!(loaded.get_calibration_data().get_trials().len(), 3);
+        assert!(!path.with_extension("json.tmp").exists());
+        let files = fs::read_dir(&store.dir).unwrap().count();
+        assert_eq!(files, 1);
+        fs::remove_dir_all(&store.dir).unwrap();
+    }
+}
@@ -0,0 +1,411 @@
+use crate::errors::AppError;
+use crate::motor::{SimulatedMotor, SimulatedMotorConfig};
+use crate::procedure::Placement;
+use crate::scale::Scale;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Share of a corner load carried by the load cell under that corner
+const CORNER_SHARE: f64 = 0.7;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SimulatedScaleConfig {
+    phidget_id: i32,
+    // Grams per unit of bridge reading, what a perfect calibration would find
+    true_coefficients: [f64; 4],
+    // Coefficients the scale starts with before any calibration is applied
+    initial_coefficients: [f64; 4],
+    // Readings of the empty platter
+    offsets: [f64; 4],
+    // Standard deviation of the white noise on each load cell, in bridge units
+    noise: f64,
+    // Bridge units per second
+    drift: f64,
+    vibration_amplitude: f64,
+    vibration_frequency: f64,
+    // Only used when the motor is simulated as well
+    feeder: Option<FeederConfig>,
+}
+impl Default for SimulatedScaleConfig {
+    fn default() -> Self {
+        Self {
+            phidget_id: 700_000,
+            true_coefficients: [2.05e6, 1.98e6, 2.01e6, 1.95e6],
+            initial_coefficients: [2.0e6; 4],
+            offsets: [1.2e-4, -4.0e-5, 6.5e-5, 9.0e-5],
+            noise: 2e-7,
+            drift: 0.,
+            vibration_amplitude: 0.,
+            vibration_frequency: 12.,
+            feeder: Some(FeederConfig::default()),
+        }
+    }
+}
+impl SimulatedScaleConfig {
+    pub fn get_feeder(&self) -> Option<&FeederConfig> {
+        self.feeder.as_ref()
+    }
+    // Reads the config from the JSON file named by CALDO_SIMULATED_SCALE. Any value
+    // that is not a readable file (e.g. "1") selects the default simulated scale.
+    pub fn from_env() -> Option<Self> {
+        let value = std::env::var("CALDO_SIMULATED_SCALE").ok()?;
+        match std::fs::read_to_string(&value) {
+            Ok(contents) => match serde_json::from_str(&contents) {
+                Ok(config) => Some(config),
+                Err(e) => {
+                    log::warn!("Invalid simulated scale config {value}: {e}, using defaults");
+                    Some(Self::default())
+                }
+            },
+            Err(_) => Some(Self::default()),
+        }
+    }
+}
+
+// Grams resting on each load cell, shared so commands can load the platter while the
+// hardware manager owns the scale. The hopper is kept apart from the placed weights so
+// clearing the platter leaves the feeder's material in place.
+#[derive(Debug, Clone, Default)]
+pub struct SimulatedLoad {
+    cells: Arc<Mutex<[f64; 4]>>,
+    hopper: Arc<Mutex<f64>>,
+}
+impl SimulatedLoad {
+    pub fn place(&self, weight: f64, position: Placement) -> Result<(), AppError> {
+        let distribution = match position {
+            Placement::Empty => [0.; 4],
+            Placement::Center => [0.25; 4],
+            Placement::Corner(corner) if corner < 4 => {
+                let mut distribution = [(1. - CORNER_SHARE) / 3.; 4];
+                distribution[corner] = CORNER_SHARE;
+                distribution
+            }
+            Placement::Corner(corner) => {
+                return Err(AppError::Other(format!("No corner {corner}!")));
+            }
+        };
+        let mut cells = self.cells.lock().unwrap();
+        for (cell, share) in cells.iter_mut().zip(distribution) {
+            *cell += weight * share;
+        }
+        Ok(())
+    }
+    fn shift(&self, weight: f64) {
+        *self.hopper.lock().unwrap() += weight;
+    }
+    // Removes the placed weights, the hopper stays on the scale
+    pub fn clear(&self) {
+        *self.cells.lock().unwrap() = [0.; 4];
+    }
+    // Material in the hopper sits over the center, so it is spread evenly
+    pub fn get(&self) -> [f64; 4] {
+        let hopper = *self.hopper.lock().unwrap();
+        self.cells.lock().unwrap().map(|cell| cell + hopper / 4.)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct FeederConfig {
+    // Grams leaving the hopper per motor revolution
+    flow_per_rev: f64,
+    // Material moved by the auger stays on the scale until it falls off the outlet
+    delay: Duration,
+    // Relative standard deviation of the flow, material comes out in clumps
+    clumping: f64,
+    // Grams of vibration on each load cell while the motor is turning
+    vibration: f64,
+    hopper_weight: f64,
+}
+impl Default for FeederConfig {
+    fn default() -> Self {
+        Self {
+            flow_per_rev: 10.,
+            delay: Duration::from_millis(300),
+            clumping: 0.3,
+            vibration: 0.5,
+            hopper_weight: 2000.,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct FeederState {
+    // Furthest the auger has advanced, travel back over it does not move new material
+    furthest_position: f64,
+    // Grams still in the hopper, flow stops when it runs empty
+    remaining: f64,
+    in_flight: VecDeque<(Instant, f64)>,
+}
+
+// Couples a simulated motor to the simulated load: forward motion pulls material out of
+// the hopper, which leaves the scale once it has fallen off the outlet. The model is
+// advanced whenever the scale is read.
+#[derive(Debug, Clone)]
+pub struct SimulatedFeeder {
+    config: FeederConfig,
+    motor: SimulatedMotor,
+    load: SimulatedLoad,
+    state: Arc<Mutex<FeederState>>,
+}
+impl SimulatedFeeder {
+    pub fn new(config: FeederConfig, motor: SimulatedMotor, load: SimulatedLoad) -> Self {
+        load.shift(config.hopper_weight);
+        Self {
+            state: Arc::new(Mutex::new(FeederState {
+                furthest_position: motor.get_position(),
+                remaining: config.hopper_weight,
+                in_flight: VecDeque::new(),
+            })),
+            config,
+            motor,
+            load,
+        }
+    }
+    // Tops the hopper back up to its configured weight
+    pub fn refill(&self) {
+        let mut state = self.state.lock().unwrap();
+        self.load.shift(self.config.hopper_weight - state.remaining);
+        state.remaining = self.config.hopper_weight;
+    }
+    fn update(&self, rng: &mut impl Rng) {
+        let now = Instant::now();
+        let position = self.motor.get_position();
+        let mut state = self.state.lock().unwrap();
+        // Retracting pulls the auger back but does not return material to the hopper, and
+        // advancing over the retracted distance again only pushes the same material back
+        let travel = (position - state.furthest_position).max(0.);
+        state.furthest_position = state.furthest_position.max(position);
+        if travel > 0. {
+            let clump = (1. + self.config.clumping * gaussian(rng)).max(0.);
+            let mass = (travel * self.config.flow_per_rev * clump).min(state.remaining);
+            state.remaining -= mass;
+            state.in_flight.push_back((now + self.config.delay, mass));
+        }
+        while let Some(&(lands, mass)) = state.in_flight.front() {
+            if lands > now {
+                break;
+            }
+            state.in_flight.pop_front();
+            self.load.shift(-mass);
+        }
+    }
+    fn vibration(&self, rng: &mut impl Rng) -> f64 {
+        if self.motor.is_moving() {
+            self.config.vibration * gaussian(rng)
+        } else {
+            0.
+        }
+    }
+}
+
+pub struct SimulatedScale {
+    config: SimulatedScaleConfig,
+    coefficients: [f64; 4],
+    load: SimulatedLoad,
+    feeder: Option<SimulatedFeeder>,
+    start: Instant,
+}
+impl SimulatedScale {
+    pub fn new(
+        config: SimulatedScaleConfig,
+        load: SimulatedLoad,
+        feeder: Option<SimulatedFeeder>,
+    ) -> Self {
+        Self {
+            coefficients: config.initial_coefficients,
+            config,
+            load,
+            feeder,
+            start: Instant::now(),
+        }
+    }
+    fn read_cells(&self) -> Result<[f64; 4], AppError> {
+        let t = self.start.elapsed().as_secs_f64();
+        let mut rng = rand::thread_rng();
+        if let Some(feeder) = self.feeder.as_ref() {
+            feeder.update(&mut rng);
+        }
+        let load = self.load.get();
+        let motor_vibration: [f64; 4] = std::array::from_fn(|_| {
+            self.feeder
+                .as_ref()
+                .map_or(0., |feeder| feeder.vibration(&mut rng))
+        });
+        Ok(std::array::from_fn(|cell| {
+            let vibration = self.config.vibration_amplitude
+                * (2. * PI * self.config.vibration_frequency * t + cell as f64).sin();
+            (load[cell] + motor_vibration[cell]) / self.config.true_coefficients[cell]
+                + self.config.offsets[cell]
+                + self.config.drift * t
+                + vibration
+                + self.config.noise * gaussian(&mut rng)
+        }))
+    }
+}
+impl Scale for SimulatedScale {
+    fn get_phidget_id(&self) -> i32 {
+        self.config.phidget_id
+    }
+    fn set_data_intervals(&mut self, _interval: Duration) -> Result<(), AppError> {
+        Ok(())
+    }
+    fn update_coefficients(mut self: Box<Self>, coefficients: [f64; 4]) -> Box<dyn Scale> {
+        self.coefficients = coefficients;
+        self
+    }
+    fn get_coefficients(&self) -> Option<[f64; 4]> {
+        Some(self.coefficients)
+    }
+    fn get_raw_readings(&mut self) -> Result<[f64; 4], AppError> {
+        self.read_cells()
+    }
+    fn get_weight(&self) -> Result<f64, AppError> {
+        Ok(self
+            .read_cells()?
+            .iter()
+            .zip(self.coefficients)
+            .map(|(reading, coefficient)| reading * coefficient)
+            .sum())
+    }
+}
+
+// Box-Muller transform, avoids pulling in rand_distr for one normal sample
+fn gaussian(rng: &mut impl Rng) -> f64 {
+    let u1: f64 = rng.gen_range(f64::EPSILON..1.);
+    let u2: f64 = rng.gen();
+    (-2. * u1.ln()).sqrt() * (2. * PI * u2).cos()
+}
+
+// Everything simulated for this run, shared by the hardware manager that builds the scale
+// and motors from it and by the commands that play the part of the outside world
+#[derive(Debug, Clone, Default)]
+pub struct Simulation {
+    scale: Option<(SimulatedScaleConfig, SimulatedLoad)>,
+    motors: Option<[SimulatedMotor; 2]>,
+    feeder: Option<SimulatedFeeder>,
+}
+impl Simulation {
+    pub fn from_env() -> Self {
+        let scale =
+            SimulatedScaleConfig::from_env().map(|config| (config, SimulatedLoad::default()));
+        let motors: Option<[SimulatedMotor; 2]> = SimulatedMotorConfig::from_env()
+            .map(|config| std::array::from_fn(|_| SimulatedMotor::new(config.clone())));
+        // The feeder is driven by the dispensing motor, so it needs both halves simulated
+        let feeder = match (scale.as_ref(), motors.as_ref()) {
+            (Some((config, load)), Some(motors)) => config.get_feeder().map(|feeder| {
+                SimulatedFeeder::new(feeder.clone(), motors[0].clone(), load.clone())
+            }),
+            _ => None,
+        };
+        Self {
+            scale,
+            motors,
+            feeder,
+        }
+    }
+    pub fn build_scale(&self) -> Option<SimulatedScale> {
+        self.scale.as_ref().map(|(config, load)| {
+            SimulatedScale::new(config.clone(), load.clone(), self.feeder.clone())
+        })
+    }
+    pub fn simulates_motors(&self) -> bool {
+        self.motors.is_some()
+    }
+    pub fn get_simulated_motor(&self, id: usize) -> Result<SimulatedMotor, AppError> {
+        self.motors
+            .as_ref()
+            .ok_or(AppError::NotSimulated)?
+            .get(id)
+            .cloned()
+            .ok_or(AppError::Motor(format!("No motor {id}!")))
+    }
+    pub fn get_simulated_load(&self) -> Result<SimulatedLoad, AppError> {
+        self.scale
+            .as_ref()
+            .map(|(_, load)| load.clone())
+            .ok_or(AppError::NotSimulated)
+    }
+    pub fn get_simulated_feeder(&self) -> Result<&SimulatedFeeder, AppError> {
+        self.feeder.as_ref().ok_or(AppError::NotSimulated)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    // Steady flow that lands straight away, so the hopper weight is exact
+    fn feeder() -> (SimulatedFeeder, SimulatedMotor, SimulatedLoad) {
+        let motor = SimulatedMotor::new(SimulatedMotorConfig::default());
+        motor.set_velocity(10.);
+        motor.enable().unwrap();
+        let load = SimulatedLoad::default();
+        let config = FeederConfig {
+            flow_per_rev: 10.,
+            delay: Duration::ZERO,
+            clumping: 0.,
+            vibration: 0.,
+            hopper_weight: 100.,
+        };
+        let feeder = SimulatedFeeder::new(config, motor.clone(), load.clone());
+        (feeder, motor, load)
+    }
+
+    fn total(load: &SimulatedLoad) -> f64 {
+        load.get().iter().sum()
+    }
+
+    fn move_by(feeder: &SimulatedFeeder, motor: &SimulatedMotor, distance: f64) {
+        motor.relative_move(distance).unwrap();
+        while motor.is_moving() {
+            thread::sleep(Duration::from_millis(5));
+        }
+        feeder.update(&mut rand::thread_rng());
+    }
+
+    #[test]
+    fn clearing_the_platter_keeps_the_hopper() {
+        let (_feeder, _motor, load) = feeder();
+        load.place(50., Placement::Corner(2)).unwrap();
+        assert!((total(&load) - 150.).abs() < 1e-9);
+        load.clear();
+        assert!((total(&load) - 100.).abs() < 1e-9);
+    }
+
+    #[test]
+    fn forward_travel_empties_the_hopper() {
+        let (feeder, motor, load) = feeder();
+        move_by(&feeder, &motor, 0.5);
+        assert!((total(&load) - 95.).abs() < 1e-9);
+        // The hopper never goes below empty
+        move_by(&feeder, &motor, 10.);
+        assert!(total(&load).abs() < 1e-9);
+    }
+
+    #[test]
+    fn readvancing_after_a_retract_does_not_move_material_twice() {
+        let (feeder, motor, load) = feeder();
+        move_by(&feeder, &motor, 0.5);
+        move_by(&feeder, &motor, -0.3);
+        move_by(&feeder, &motor, 0.3);
+        assert!((total(&load) - 95.).abs() < 1e-9);
+        move_by(&feeder, &motor, 0.2);
+        assert!((total(&load) - 93.).abs() < 1e-9);
+    }
+
+    #[test]
+    fn refill_tops_the_hopper_up() {
+        let (feeder, motor, load) = feeder();
+        load.place(20., Placement::Center).unwrap();
+        move_by(&feeder, &motor, 0.5);
+        feeder.refill();
+        assert!((total(&load) - 120.).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,298 @@
+use crate::calibration_data::{CalibrationData, Coefficients};
+use crate::errors::AppError;
+use crate::hardware::HardwareManager;
+use crate::history::CoefficientSource;
+use crate::state::AppData;
+use crate::statistics;
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+pub enum Solver {
+    #[default]
+    Cloud,
+    // The scale cannot apply an offset, so calibrating never fits one
+    Local,
+}
+
+pub struct LocalSolver {
+    fit_offset: bool,
+}
+impl LocalSolver {
+    pub fn new(fit_offset: bool) -> Self {
+        Self { fit_offset }
+    }
+    pub async fn calibrate(
+        &self,
+        state: tauri::State<'_, Mutex<AppData>>,
+        hardware: tauri::State<'_, HardwareManager>,
+    ) -> Result<String, AppError> {
+        let calibration_data = {
+            state
+                .lock()
+                .unwrap()
+                .get_calibration_data()
+                .ok_or(AppError::NoSession)?
+        };
+        let coefficients = self.solve(&calibration_data)?;
+        hardware.apply_coefficients(&coefficients).await?;
+        state
+            .lock()
+            .unwrap()
+            .update_coefficients(coefficients.clone(), CoefficientSource::LocalSolver)?;
+        serde_json::to_string(&coefficients).map_err(AppError::Serde)
+    }
+    // Ordinary least squares on weight = sum(c_i * r_i) (+ offset), solved through the
+    // normal equations. Columns are normalized first since raw bridge readings are tiny
+    // compared to the resulting coefficients.
+    pub fn solve(&self, calibration_data: &CalibrationData) -> Result<Coefficients, AppError> {
+        calibration_data.check_readings()?;
+        let trials = calibration_data.get_trials();
+        let unknowns = if self.fit_offset { 5 } else { 4 };
+        if trials.len() < unknowns {
+            return Err(AppError::InsufficientTrials {
+                required: unknowns,
+                actual: trials.len(),
+            });
+        }
+        let rows: Vec<Vec<f64>> = trials
+            .iter()
+            .map(|trial| {
+                let mut row = trial.get_readings().to_vec();
+                if self.fit_offset {
+                    row.push(1.);
+                }
+                row
+            })
+            .collect();
+        let mut scales = vec![0_f64; unknowns];
+        for row in &rows {
+            for (scale, value) in scales.iter_mut().zip(row) {
+                *scale = scale.max(value.abs());
+            }
+        }
+        if scales.contains(&0.) {
+            return Err(AppError::SingularFit);
+        }
+
+        let fit_weights = fit_weights(calibration_data);
+        let mut normal = vec![vec![0_f64; unknowns]; unknowns];
+        let mut rhs = vec![0_f64; unknowns];
+        for ((row, trial), w) in rows.iter().zip(trials).zip(fit_weights) {
+            let x: Vec<f64> = row.iter().zip(&scales).map(|(v, s)| v / s).collect();
+            for i in 0..unknowns {
+                rhs[i] += w * x[i] * trial.get_weight();
+                for j in 0..unknowns {
+                    normal[i][j] += w * x[i] * x[j];
+                }
+            }
+        }
+        let solution = solve_linear_system(normal, rhs).ok_or(AppError::SingularFit)?;
+        let solution: Vec<f64> = solution.iter().zip(&scales).map(|(v, s)| v / s).collect();
+
+        let coefficients = [solution[0], solution[1], solution[2], solution[3]];
+        let offset = self.fit_offset.then(|| solution[4]);
+        Ok(Coefficients::new(coefficients, offset))
+    }
+}
+
+// Inverse-variance weights, damped by the mean variance so a single unusually quiet
+// trial cannot dominate the fit. Trials without sampling statistics are weighted equally.
+fn fit_weights(calibration_data: &CalibrationData) -> Vec<f64> {
+    let trials = calibration_data.get_trials();
+    let variances: Option<Vec<f64>> = trials
+        .iter()
+        .map(|trial| {
+            trial
+                .get_sampling()
+                .map(|sampling| sampling.noise_variance())
+        })
+        .collect();
+    match variances.and_then(|variances| Some((statistics::mean(&variances)?, variances))) {
+        Some((mean, variances)) if mean > 0. => {
+            variances.iter().map(|v| mean / (v + mean)).collect()
+        }
+        _ => vec![1.; trials.len()],
+    }
+}
+
+// Gaussian elimination with partial pivoting, returns None for a singular system.
+pub fn solve_linear_system(mut a: Vec<Vec<f64>>, mut b: Vec<f64>) -> Option<Vec<f64>> {
+    let n = b.len();
+    for col in 0..n {
+        let pivot = (col..n).max_by(|&i, &j| a[i][col].abs().total_cmp(&a[j][col].abs()))?;
+        if a[pivot][col].abs() < 1e-12 {
+            return None;
+        }
+        a.swap(col, pivot);
+        b.swap(col, pivot);
+        let pivot_row = a[col].clone();
+        for row in col + 1..n {
+            let factor = a[row][col] / pivot_row[col];
+            for (value, pivot_value) in a[row][col..].iter_mut().zip(&pivot_row[col..]) {
+                *value -= factor * pivot_value;
+            }
+            b[row] -= factor * b[col];
+        }
+    }
+    let mut x = vec![0_f64; n];
+    for row in (0..n).rev() {
+        let sum: f64 = (row + 1..n).map(|k| a[row][k] * x[k]).sum();
+        x[row] = (b[row] - sum) / a[row][row];
+    }
+    Some(x)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::calibration_data::{CalibrationTrial, TrialSampling};
+    use node_diagnostics::data::Data;
+    use std::time::Duration;
+
+    const COEFFICIENTS: [f64; 4] = [2., 3., 4., 5.];
+    const READINGS: [[f64; 4]; 6] = [
+        [1., 0., 0., 0.],
+        [0., 1., 0., 0.],
+        [0., 0., 1., 0.],
+        [0., 0., 0., 1.],
+        [1., 1., 1., 1.],
+        [0.5, 0.2, 0.8, 0.1],
+    ];
+
+    fn weight(readings: &[f64; 4], offset: f64) -> f64 {
+        readings
+            .iter()
+            .zip(COEFFICIENTS)
+            .map(|(r, c)| r * c)
+            .sum::<f64>()
+            + offset
+    }
+    fn with_trials(trials: impl IntoIterator<Item = CalibrationTrial>) -> CalibrationData {
+        let mut calibration_data = CalibrationData::new(1);
+        for trial in trials {
+            calibration_data.add_trial(trial);
+        }
+        calibration_data
+    }
+    // Every cell alternates `spread` either side of its reading, so the medians are the
+    // readings and the spread sets the noise variance
+    fn sampled_trial(readings: [f64; 4], weight: f64, spread: f64) -> CalibrationTrial {
+        let data = readings.map(|reading| {
+            let mut data = Data::new(4);
+            for (sample, sign) in [-1., 1., -1., 1.].into_iter().enumerate() {
+                data.push(
+                    Duration::from_millis(sample as u64),
+                    reading + sign * spread,
+                );
+            }
+            data
+        });
+        let sampling = TrialSampling::from_data(&data, Duration::from_millis(1)).unwrap();
+        CalibrationTrial::from_sampling(sampling, weight)
+    }
+    fn assert_coefficients(coefficients: &Coefficients) {
+        for (fitted, expected) in coefficients.get_coefficients().iter().zip(COEFFICIENTS) {
+            assert!((fitted - expected).abs() < 1e-9, "{fitted} != {expected}");
+        }
+    }
+
+    #[test]
+    fn recovers_known_coefficients() {
+        let calibration_data = with_trials(
+            READINGS.map(|readings| CalibrationTrial::from_array(readings, weight(&readings, 0.))),
+        );
+        let coefficients = LocalSolver::new(false).solve(&calibration_data).unwrap();
+        assert_coefficients(&coefficients);
+        assert_eq!(coefficients.get_offset(), None);
+    }
+
+    #[test]
+    fn recovers_a_fitted_offset() {
+        let calibration_data = with_trials(
+            READINGS.map(|readings| CalibrationTrial::from_array(readings, weight(&readings, 7.))),
+        );
+        let coefficients = LocalSolver::new(true).solve(&calibration_data).unwrap();
+        assert_coefficients(&coefficients);
+        assert!((coefficients.get_offset().unwrap() - 7.).abs() < 1e-9);
+    }
+
+    #[test]
+    fn needs_a_trial_per_unknown() {
+        let calibration_data = with_trials(
+            READINGS[..4]
+                .iter()
+                .map(|readings| CalibrationTrial::from_array(*readings, weight(readings, 0.))),
+        );
+        assert!(LocalSolver::new(false).solve(&calibration_data).is_ok());
+        assert!(matches!(
+            LocalSolver::new(true).solve(&calibration_data),
+            Err(AppError::InsufficientTrials {
+                required: 5,
+                actual: 4
+            })
+        ));
+    }
+
+    #[test]
+    fn rejects_trials_without_four_readings() {
+        let mut trials: Vec<CalibrationTrial> = READINGS
+            .iter()
+            .map(|readings| CalibrationTrial::from_array(*readings, weight(readings, 0.)))
+            .collect();
+        trials[2] = serde_json::from_value(serde_json::json!({
+            "readings": [1., 2., 3.],
+            "weight": 1.,
+            "timestamp": { "secs": 0, "nanos": 0 },
+        }))
+        .unwrap();
+        assert!(matches!(
+            LocalSolver::new(false).solve(&with_trials(trials)),
+            Err(AppError::TrialReadings {
+                index: 2,
+                readings: 3
+            })
+        ));
+    }
+
+    #[test]
+    fn noisy_trials_are_weighted_less() {
+        let calibration_data = with_trials([
+            sampled_trial([1., 0., 0., 0.], 2., 0.),
+            sampled_trial([0., 1., 0., 0.], 3., 0.1),
+        ]);
+        let weights = fit_weights(&calibration_data);
+        assert_eq!(weights[0], 1.);
+        assert!(weights[1] < weights[0]);
+
+        // Without sampling statistics every trial counts the same
+        let calibration_data = with_trials(
+            READINGS.map(|readings| CalibrationTrial::from_array(readings, weight(&readings, 0.))),
+        );
+        assert!(fit_weights(&calibration_data).iter().all(|w| *w == 1.));
+    }
+
+    #[test]
+    fn a_noisy_outlier_pulls_the_fit_less() {
+        let error = |spread: f64| {
+            let mut trials: Vec<CalibrationTrial> = READINGS[..5]
+                .iter()
+                .map(|readings| sampled_trial(*readings, weight(readings, 0.), 0.))
+                .collect();
+            // Off by 10 g
+            trials.push(sampled_trial(
+                READINGS[5],
+                weight(&READINGS[5], 10.),
+                spread,
+            ));
+            let coefficients = LocalSolver::new(false).solve(&with_trials(trials)).unwrap();
+            coefficients
+                .get_coefficients()
+                .iter()
+                .zip(COEFFICIENTS)
+                .map(|(fitted, expected)| (fitted - expected).abs())
+                .sum::<f64>()
+        };
+        assert!(error(1.) < error(0.));
+    }
+}
@@ -0,0 +1,115 @@
+use crate::calibration_data::TrialSampling;
+use crate::errors::AppError;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct StabilityCriteria {
+    max_std_dev: f64,
+    max_drift: f64,
+    retry_timeout: Option<Duration>,
+}
+impl StabilityCriteria {
+    pub fn check(&self, sampling: &TrialSampling) -> Result<(), AppError> {
+        for (cell, statistics) in sampling.get_cells().iter().enumerate() {
+            if statistics.get_std_dev() > self.max_std_dev
+                || statistics.get_drift().abs() > self.max_drift
+            {
+                return Err(AppError::UnstableLoadCell {
+                    cell,
+                    std_dev: statistics.get_std_dev(),
+                    drift: statistics.get_drift(),
+                });
+            }
+        }
+        Ok(())
+    }
+    pub fn should_retry(&self, elapsed: Duration) -> bool {
+        self.retry_timeout.is_some_and(|timeout| elapsed < timeout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use node_diagnostics::data::Data;
+
+    const PERIOD: Duration = Duration::from_millis(100);
+
+    fn criteria() -> StabilityCriteria {
+        StabilityCriteria {
+            max_std_dev: 0.1,
+            max_drift: 0.5,
+            retry_timeout: Some(Duration::from_secs(5)),
+        }
+    }
+    // Cell 2 reads `noisy` on top of a steady 1.0, the other cells hold still
+    fn sampling(noisy: impl Fn(usize) -> f64) -> TrialSampling {
+        let data = std::array::from_fn(|cell| {
+            let mut data = Data::new(10);
+            for sample in 0..10 {
+                let reading = if cell == 2 { 1. + noisy(sample) } else { 1. };
+                data.push(PERIOD * sample as u32, reading);
+            }
+            data
+        });
+        TrialSampling::from_data(&data, PERIOD).unwrap()
+    }
+
+    #[test]
+    fn accepts_a_steady_sampling() {
+        assert!(criteria().check(&sampling(|_| 0.)).is_ok());
+        // Small alternating noise without a trend
+        let small_noise = |sample: usize| if sample % 2 == 0 { 0.05 } else { -0.05 };
+        assert!(criteria().check(&sampling(small_noise)).is_ok());
+        // A slow trend of 0.2/s
+        let slow_drift = |sample: usize| 0.02 * sample as f64;
+        assert!(criteria().check(&sampling(slow_drift)).is_ok());
+    }
+
+    #[test]
+    fn rejects_a_noisy_cell() {
+        let noise = |sample: usize| if sample % 2 == 0 { 0.5 } else { -0.5 };
+        let Err(AppError::UnstableLoadCell {
+            cell,
+            std_dev,
+            drift,
+        }) = criteria().check(&sampling(noise))
+        else {
+            panic!("noisy cell was accepted");
+        };
+        assert_eq!(cell, 2);
+        assert!(std_dev > 0.1);
+        assert!(drift.abs() < 0.5);
+    }
+
+    #[test]
+    fn rejects_a_drifting_cell_either_way() {
+        // 0.1 per 100 ms sample is 1/s, well within the std dev limit over a short window
+        for direction in [1., -1.] {
+            let criteria = StabilityCriteria {
+                max_std_dev: 1.,
+                ..criteria()
+            };
+            let drift = move |sample: usize| direction * 0.1 * sample as f64;
+            let Err(AppError::UnstableLoadCell { cell, drift, .. }) =
+                criteria.check(&sampling(drift))
+            else {
+                panic!("drifting cell was accepted");
+            };
+            assert_eq!(cell, 2);
+            assert!((drift - direction).abs() < 1e-9, "drift {drift}");
+        }
+    }
+
+    #[test]
+    fn retries_until_the_timeout() {
+        assert!(criteria().should_retry(Duration::from_secs(4)));
+        assert!(!criteria().should_retry(Duration::from_secs(5)));
+        let no_retry = StabilityCriteria {
+            retry_timeout: None,
+            ..criteria()
+        };
+        assert!(!no_retry.should_retry(Duration::ZERO));
+    }
+}
@@ -1,126 +1,351 @@
-use crate::calibration_data::{CalibrationData, CalibrationTrial, Coefficients};
+use crate::calibration_data::{CalibrationData, CalibrationTrial, Coefficients, TrialEntry};
+use crate::eccentricity::{CornerLoadReport, CornerLoadTest};
 use crate::errors::AppError;
-use libra::scale::ConnectedScale;
-use std::time::Duration;
-use std::{fmt, thread};
-use control_components::{controllers::clear_core};
-use control_components::components::clear_core_motor::ClearCoreMotor;
+use crate::history::{CoefficientHistory, CoefficientRecord, CoefficientSource};
+use crate::procedure::{
+    CalibrationPlan, CalibrationProcedure, Placement, PlanStep, ProcedureStatus,
+};
+use crate::session::{CalibrationSession, SessionStore};
+use crate::solver::Solver;
+use crate::stability::StabilityCriteria;
+use crate::verification::{VerificationPoint, VerificationRun};
+use std::fmt;
 
 pub struct AppData {
-    scale: Option<ConnectedScale>,
-    coefficients: Option<[f64; 4]>,
-    calibration_data: Option<CalibrationData>,
-    clear_core: Option<clear_core::Controller>,
+    // The scale itself lives with the hardware manager
+    phidget_id: Option<i32>,
+    coefficients: Option<Coefficients>,
+    session: Option<CalibrationSession>,
+    session_store: Option<SessionStore>,
+    stability_criteria: Option<StabilityCriteria>,
+    corner_test: Option<CornerLoadTest>,
+    coefficient_history: CoefficientHistory,
 }
 impl AppData {
     pub fn new() -> Self {
         Self {
-            scale: None,
+            phidget_id: None,
             coefficients: None,
-            calibration_data: None,
-            clear_core: None
+            session: None,
+            session_store: None,
+            stability_criteria: None,
+            corner_test: None,
+            coefficient_history: CoefficientHistory::default(),
         }
     }
-    pub fn get_mut_scale_ref(&mut self) -> Option<&mut ConnectedScale> {
-        self.scale.as_mut()
+    pub fn get_phidget_id(&self) -> Result<i32, AppError> {
+        self.phidget_id.ok_or(AppError::NoScale)
     }
-    fn has_connected_scale(&self) -> Result<(), AppError> {
-        if self.scale.is_some() {
-            Ok(())
-        } else {
-            Err(AppError::NoScale)
+    // Keep an in-progress session when the same scale is reconnected
+    pub fn set_connected_scale(&mut self, phidget_id: i32) {
+        if self
+            .session
+            .as_ref()
+            .is_none_or(|session| session.get_phidget_id() != phidget_id)
+        {
+            self.session.replace(CalibrationSession::new(phidget_id));
+            self.autosave_session();
         }
+        self.phidget_id.replace(phidget_id);
     }
-
-    pub fn connect_scale(&mut self) -> Result<(), AppError> {
-        if self.has_connected_scale().is_ok() {
-            println!("Already connected!");
-            return Ok(());
-        }
-        let mut scale =
-            ConnectedScale::without_id(Duration::from_secs(3)).map_err(AppError::Libra)?;
-        scale
-            .set_data_intervals(Duration::from_millis(40))
-            .map_err(AppError::Libra)?;
-        self.calibration_data
-            .replace(CalibrationData::new(scale.get_phidget_id()));
-        self.scale.replace(scale);
-        Ok(())
+    pub fn disconnect_scale(&mut self) {
+        self.phidget_id.take();
     }
-    pub fn update_coefficients(&mut self, coefficients: Coefficients) -> Result<(), AppError> {
-        let scale = self
-            .scale
-            .take()
-            .ok_or(AppError::NoScale)?
-            .update_coefficients(coefficients.get_coefficients());
-        self.scale.replace(scale);
-        self.coefficients.replace(coefficients.get_coefficients());
+    pub fn update_coefficients(
+        &mut self,
+        coefficients: Coefficients,
+        source: CoefficientSource,
+    ) -> Result<(), AppError> {
+        let phidget_id = self.get_phidget_id()?;
+        let session_id = self
+            .session
+            .as_ref()
+            .map(|session| session.get_id().to_string());
+        if let Err(e) =
+            self.coefficient_history
+                .record(coefficients.clone(), source, phidget_id, session_id)
+        {
+            log::warn!("Failed to save coefficient history: {e}");
+        }
+        if let Some(session) = self.session.as_mut() {
+            session.set_coefficients(coefficients.clone());
+            self.autosave_session();
+        }
+        self.coefficients.replace(coefficients);
 
         Ok(())
     }
+    pub fn record_connected_coefficients(&mut self, phidget_id: i32, coefficients: [f64; 4]) {
+        if let Err(e) = self
+            .coefficient_history
+            .record_connected(Coefficients::new(coefficients, None), phidget_id)
+        {
+            log::warn!("Failed to save coefficient history: {e}");
+        }
+    }
+    pub fn set_coefficient_history(&mut self, coefficient_history: CoefficientHistory) {
+        self.coefficient_history = coefficient_history;
+    }
+    pub fn get_coefficient_history(&self, phidget_id: Option<i32>) -> Vec<CoefficientRecord> {
+        self.coefficient_history.get_records(phidget_id)
+    }
+    pub fn get_rollback_coefficients(&self, index: usize) -> Result<Coefficients, AppError> {
+        let record = self.coefficient_history.get_record(index)?;
+        let phidget_id = self.get_phidget_id()?;
+        if record.get_phidget_id() != phidget_id {
+            return Err(AppError::PhidgetMismatch {
+                expected: record.get_phidget_id(),
+                actual: phidget_id,
+            });
+        }
+        Ok(record.get_coefficients().clone())
+    }
+    pub fn get_coefficients(&self) -> Option<Coefficients> {
+        self.coefficients.clone()
+    }
+    pub fn get_solver(&self) -> Solver {
+        self.session
+            .as_ref()
+            .map(CalibrationSession::get_solver)
+            .unwrap_or_default()
+    }
+    pub fn set_solver(&mut self, solver: Solver) -> Result<(), AppError> {
+        self.session
+            .as_mut()
+            .ok_or(AppError::NoSession)?
+            .set_solver(solver);
+        self.save_session()
+    }
+    pub fn get_stability_criteria(&self) -> Option<StabilityCriteria> {
+        self.stability_criteria.clone()
+    }
+    pub fn set_stability_criteria(&mut self, stability_criteria: Option<StabilityCriteria>) {
+        self.stability_criteria = stability_criteria;
+    }
     pub fn get_calibration_data(&self) -> Option<CalibrationData> {
-        self.calibration_data.clone()
+        self.session
+            .as_ref()
+            .map(|session| session.get_calibration_data().clone())
+    }
+    pub fn set_session_store(&mut self, session_store: SessionStore) {
+        self.session_store.replace(session_store);
+    }
+    pub fn get_session_store(&self) -> Result<&SessionStore, AppError> {
+        self.session_store
+            .as_ref()
+            .ok_or(AppError::Other("Session storage unavailable!".into()))
+    }
+    pub fn get_session(&self) -> Option<CalibrationSession> {
+        self.session.clone()
+    }
+    pub fn new_session(&mut self) -> Result<String, AppError> {
+        let session = CalibrationSession::new(self.get_phidget_id()?);
+        let id = session.get_id().to_string();
+        self.session.replace(session);
+        self.save_session()?;
+        Ok(id)
+    }
+    pub fn resume_session(&mut self, session: CalibrationSession) -> Result<(), AppError> {
+        if let Some(phidget_id) = self.phidget_id {
+            if phidget_id != session.get_phidget_id() {
+                return Err(AppError::PhidgetMismatch {
+                    expected: session.get_phidget_id(),
+                    actual: phidget_id,
+                });
+            }
+        }
+        self.session.replace(session);
+        Ok(())
+    }
+    pub fn close_session(&mut self, id: &str) {
+        if self
+            .session
+            .as_ref()
+            .is_some_and(|session| session.get_id() == id)
+        {
+            self.session.take();
+        }
+    }
+    pub fn set_session_notes(&mut self, notes: String) -> Result<(), AppError> {
+        self.session
+            .as_mut()
+            .ok_or(AppError::NoSession)?
+            .set_notes(notes);
+        self.save_session()
+    }
+    pub fn save_session(&mut self) -> Result<(), AppError> {
+        let session = self.session.as_mut().ok_or(AppError::NoSession)?;
+        self.session_store
+            .as_ref()
+            .ok_or(AppError::Other("Session storage unavailable!".into()))?
+            .save(session)
+    }
+    // Saving is best effort here, a full disk should not throw away a captured trial
+    fn autosave_session(&mut self) {
+        if let Err(e) = self.save_session() {
+            log::warn!("Failed to save calibration session: {e}");
+        }
     }
 
     pub fn add_calibration_trial(
         &mut self,
         calibration_trial: CalibrationTrial,
     ) -> Result<CalibrationTrial, AppError> {
-        if let Some(mut calibration_data) = self.calibration_data.take() {
-            calibration_data.add_trial(calibration_trial.clone());
-            self.calibration_data.replace(calibration_data);
+        if let Some(session) = self.session.as_mut() {
+            session.add_trial(calibration_trial.clone());
+            self.autosave_session();
             Ok(calibration_trial)
         } else {
             Err(AppError::NoScale)
         }
     }
-    pub fn get_motor(&mut self, id: usize) -> ClearCoreMotor {
-        if self.clear_core.is_none() {
-            let (controller, controller_client) = clear_core::Controller::with_client(
-                "192.168.1.12:8888",
-                &[
-                    clear_core::MotorBuilder {
-                        id: 0,
-                        scale: 800,
-                    },
-                    clear_core::MotorBuilder {
-                        id: 1,
-                        scale: 800,
-                    },
-                ],
-            );
-            tauri::async_runtime::spawn(async move {
-                if let Err(_) = controller_client.await {
-                    println!("No motor/io controller connected...");
-                }
-            });
-            thread::sleep(Duration::from_secs(5));
-            let motor = controller.get_motor(id);
-            self.clear_core.replace(controller);
-            motor
-        } else {
-            self.clear_core.clone().unwrap().get_motor(id)
-        }
+    pub fn get_calibration_trials(&self) -> Result<Vec<TrialEntry>, AppError> {
+        Ok(self
+            .session
+            .as_ref()
+            .ok_or(AppError::NoSession)?
+            .get_calibration_data()
+            .get_trial_entries())
     }
-    pub fn take_scale(&mut self) -> Result<ConnectedScale, AppError> {
-        self.scale.take().ok_or(AppError::NoScale)
+    pub fn get_calibration_trial(&self, index: usize) -> Result<CalibrationTrial, AppError> {
+        self.session
+            .as_ref()
+            .ok_or(AppError::NoSession)?
+            .get_calibration_data()
+            .get_trial(index)
+            .cloned()
     }
-    pub fn return_scale(&mut self, scale: ConnectedScale) -> Result<(), AppError> {
-        if self.scale.is_none() {
-            self.scale.replace(scale);
-            Ok(())
-        } else {
-            Err(AppError::ScaleExists)
-        }
+    pub fn remove_calibration_trial(&mut self, index: usize) -> Result<CalibrationTrial, AppError> {
+        let trial = self
+            .session
+            .as_mut()
+            .ok_or(AppError::NoSession)?
+            .get_mut_calibration_data()
+            .remove_trial(index)?;
+        self.autosave_session();
+        Ok(trial)
+    }
+    pub fn replace_calibration_trial(
+        &mut self,
+        index: usize,
+        calibration_trial: CalibrationTrial,
+    ) -> Result<CalibrationTrial, AppError> {
+        self.session
+            .as_mut()
+            .ok_or(AppError::NoSession)?
+            .get_mut_calibration_data()
+            .replace_trial(index, calibration_trial.clone())?;
+        self.autosave_session();
+        Ok(calibration_trial)
+    }
+    pub fn annotate_calibration_trial(
+        &mut self,
+        index: usize,
+        note: Option<String>,
+    ) -> Result<CalibrationTrial, AppError> {
+        let trial = self
+            .session
+            .as_mut()
+            .ok_or(AppError::NoSession)?
+            .get_mut_calibration_data()
+            .get_mut_trial(index)?;
+        trial.set_note(note);
+        let trial = trial.clone();
+        self.autosave_session();
+        Ok(trial)
+    }
+    // The procedure lives on the session, so a reopened session picks up where it left off
+    pub fn start_procedure(&mut self, plan: CalibrationPlan) -> Result<ProcedureStatus, AppError> {
+        let procedure = CalibrationProcedure::new(plan)?;
+        let status = procedure.status();
+        self.session
+            .as_mut()
+            .ok_or(AppError::NoSession)?
+            .start_procedure(procedure);
+        self.autosave_session();
+        Ok(status)
+    }
+    fn get_procedure(&self) -> Result<&CalibrationProcedure, AppError> {
+        self.session
+            .as_ref()
+            .ok_or(AppError::NoSession)?
+            .get_procedure()
+            .ok_or(AppError::NoProcedure)
+    }
+    pub fn get_procedure_status(&self) -> Result<ProcedureStatus, AppError> {
+        Ok(self.get_procedure()?.status())
+    }
+    pub fn get_procedure_step(&self, index: usize) -> Result<PlanStep, AppError> {
+        self.get_procedure()?.get_step(index).cloned()
+    }
+    pub fn record_procedure_step(
+        &mut self,
+        index: usize,
+        calibration_trial: CalibrationTrial,
+    ) -> Result<ProcedureStatus, AppError> {
+        let status = self
+            .session
+            .as_mut()
+            .ok_or(AppError::NoSession)?
+            .record_procedure_step(index, calibration_trial)?;
+        self.autosave_session();
+        Ok(status)
+    }
+    pub fn start_verification(
+        &mut self,
+        verification: VerificationRun,
+    ) -> Result<VerificationRun, AppError> {
+        self.session
+            .as_mut()
+            .ok_or(AppError::NoSession)?
+            .add_verification(verification.clone());
+        self.autosave_session();
+        Ok(verification)
+    }
+    pub fn get_verification(&self) -> Result<VerificationRun, AppError> {
+        self.session
+            .as_ref()
+            .ok_or(AppError::NoSession)?
+            .get_verification()
+            .cloned()
+            .ok_or(AppError::NoVerification)
+    }
+    pub fn record_verification(&mut self, measured: f64) -> Result<VerificationPoint, AppError> {
+        let point = self
+            .session
+            .as_mut()
+            .ok_or(AppError::NoSession)?
+            .get_mut_verification()
+            .ok_or(AppError::NoVerification)?
+            .record(measured)?;
+        self.autosave_session();
+        Ok(point)
+    }
+    pub fn start_corner_test(&mut self, corner_test: CornerLoadTest) {
+        self.corner_test.replace(corner_test);
+    }
+    pub fn capture_corner(&mut self, position: Placement, readings: [f64; 4]) -> Result<(), AppError> {
+        self.corner_test
+            .as_mut()
+            .ok_or(AppError::NoCornerTest)?
+            .capture(position, readings)
+    }
+    pub fn get_corner_report(&self) -> Result<CornerLoadReport, AppError> {
+        let coefficients = self.coefficients.as_ref().ok_or(AppError::NoCoefficients)?;
+        self.corner_test
+            .as_ref()
+            .ok_or(AppError::NoCornerTest)?
+            .report(coefficients)
     }
 }
 impl fmt::Display for AppData {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "Scale: {}, Coefficients: {:?}",
-            self.scale.is_some(),
-            self.coefficients
+            "Scale: {}, Coefficients: {:?}, Solver: {:?}",
+            self.phidget_id.is_some(),
+            self.coefficients,
+            self.get_solver()
         )
     }
 }
@@ -0,0 +1,113 @@
+// The mean and order statistics return None for an empty slice, while std_dev and slope
+// return 0 when there is no spread to measure, rather than a NaN or a panic
+
+pub fn mean(values: &[f64]) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+    Some(values.iter().sum::<f64>() / values.len() as f64)
+}
+
+// Sample standard deviation, 0 with fewer than two values
+pub fn std_dev(values: &[f64]) -> f64 {
+    let Some(mean) = mean(values).filter(|_| values.len() >= 2) else {
+        return 0.;
+    };
+    let sum_squares: f64 = values.iter().map(|value| (value - mean).powi(2)).sum();
+    (sum_squares / (values.len() - 1) as f64).sqrt()
+}
+
+pub fn median(values: &[f64]) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+    let mut sorted = values.to_vec();
+    sorted.sort_by(f64::total_cmp);
+    let middle = sorted.len() / 2;
+    if sorted.len() % 2 == 0 {
+        Some((sorted[middle - 1] + sorted[middle]) / 2.)
+    } else {
+        Some(sorted[middle])
+    }
+}
+
+pub fn median_absolute_deviation(values: &[f64]) -> Option<f64> {
+    let median = median(values)?;
+    let deviations: Vec<f64> = values.iter().map(|value| (value - median).abs()).collect();
+    self::median(&deviations)
+}
+
+pub fn min(values: &[f64]) -> Option<f64> {
+    values.iter().cloned().reduce(f64::min)
+}
+
+pub fn max(values: &[f64]) -> Option<f64> {
+    values.iter().cloned().reduce(f64::max)
+}
+
+// Least-squares slope of y over x, 0 when x has no spread
+pub fn slope(x: &[f64], y: &[f64]) -> f64 {
+    let (Some(x_mean), Some(y_mean)) = (mean(x), mean(y)) else {
+        return 0.;
+    };
+    let covariance: f64 = x
+        .iter()
+        .zip(y)
+        .map(|(x, y)| (x - x_mean) * (y - y_mean))
+        .sum();
+    let variance: f64 = x.iter().map(|x| (x - x_mean).powi(2)).sum();
+    if variance == 0. {
+        0.
+    } else {
+        covariance / variance
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_slices_have_no_statistics() {
+        assert_eq!(mean(&[]), None);
+        assert_eq!(median(&[]), None);
+        assert_eq!(median_absolute_deviation(&[]), None);
+        assert_eq!(min(&[]), None);
+        assert_eq!(max(&[]), None);
+        assert_eq!(std_dev(&[]), 0.);
+        assert_eq!(slope(&[], &[]), 0.);
+    }
+
+    #[test]
+    fn single_value() {
+        assert_eq!(mean(&[2.5]), Some(2.5));
+        assert_eq!(median(&[2.5]), Some(2.5));
+        assert_eq!(median_absolute_deviation(&[2.5]), Some(0.));
+        assert_eq!(std_dev(&[2.5]), 0.);
+        assert_eq!(slope(&[1.], &[2.5]), 0.);
+    }
+
+    #[test]
+    fn median_of_odd_and_even_lengths() {
+        assert_eq!(median(&[3., 1., 2.]), Some(2.));
+        assert_eq!(median(&[4., 1., 3., 2.]), Some(2.5));
+    }
+
+    #[test]
+    fn spread() {
+        let values = [2., 4., 4., 4., 5., 5., 7., 9.];
+        assert_eq!(mean(&values), Some(5.));
+        assert!((std_dev(&values) - (32_f64 / 7.).sqrt()).abs() < 1e-12);
+        assert_eq!(median_absolute_deviation(&values), Some(0.5));
+        assert_eq!(min(&values), Some(2.));
+        assert_eq!(max(&values), Some(9.));
+    }
+
+    #[test]
+    fn slope_of_a_line() {
+        let x = [0., 1., 2., 3.];
+        let y = [1., 3., 5., 7.];
+        assert!((slope(&x, &y) - 2.).abs() < 1e-12);
+        assert_eq!(slope(&[1., 1., 1.], &y[..3]), 0.);
+    }
+}
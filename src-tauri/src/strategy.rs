@@ -0,0 +1,216 @@
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+// Flow rates over shorter periods are mostly scale noise, so controllers hold their output
+pub const MIN_UPDATE_PERIOD: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+pub enum DispenseStrategy {
+    // Slows down in proportion to the weight left to dispense
+    #[default]
+    Proportional,
+    // Tracks a flow rate that tapers off with the weight left to dispense, starting from
+    // the proportional velocity
+    Pid {
+        kp: f64,
+        ki: f64,
+        kd: f64,
+        flow_rate: f64,
+    },
+    // Full speed until `cutoff` is left to dispense, then minimum speed
+    BangBang {
+        cutoff: f64,
+    },
+}
+impl DispenseStrategy {
+    pub fn controller(&self) -> Box<dyn DispenseController> {
+        match *self {
+            DispenseStrategy::Proportional => Box::new(ProportionalController),
+            DispenseStrategy::Pid {
+                kp,
+                ki,
+                kd,
+                flow_rate,
+            } => Box::new(PidController::new(kp, ki, kd, flow_rate)),
+            DispenseStrategy::BangBang { cutoff } => Box::new(BangBangController { cutoff }),
+        }
+    }
+}
+
+// What the dispense loop knows at each speed update
+#[derive(Debug, Clone, Copy)]
+pub struct DispenseState {
+    pub target: f64,
+    pub dispensed: f64,
+    pub flow_rate: f64,
+    pub since_update: Duration,
+    pub min_velocity: f64,
+    pub max_velocity: f64,
+}
+impl DispenseState {
+    fn remaining_fraction(&self) -> f64 {
+        (self.target - self.dispensed) / self.target
+    }
+}
+
+pub trait DispenseController: Send {
+    // Motor velocity until the next speed update, clamped by the dispense loop
+    fn velocity(&mut self, state: &DispenseState) -> f64;
+    // Called when dispensing resumes after a check fell short
+    fn reset(&mut self) {}
+}
+
+struct ProportionalController;
+impl DispenseController for ProportionalController {
+    fn velocity(&mut self, state: &DispenseState) -> f64 {
+        state.remaining_fraction() * state.max_velocity
+    }
+}
+
+struct PidController {
+    kp: f64,
+    ki: f64,
+    kd: f64,
+    flow_rate: f64,
+    integral: f64,
+    last_error: Option<f64>,
+    last_velocity: Option<f64>,
+}
+impl PidController {
+    fn new(kp: f64, ki: f64, kd: f64, flow_rate: f64) -> Self {
+        Self {
+            kp,
+            ki,
+            kd,
+            flow_rate,
+            integral: 0.,
+            last_error: None,
+            last_velocity: None,
+        }
+    }
+}
+impl DispenseController for PidController {
+    fn velocity(&mut self, state: &DispenseState) -> f64 {
+        let fraction = state.remaining_fraction().clamp(0., 1.);
+        // Full speed is taken to give the nominal flow rate, so the loop only corrects the
+        // velocity that should give the setpoint instead of starting from nothing
+        let feedforward = fraction * state.max_velocity;
+        if state.since_update < MIN_UPDATE_PERIOD {
+            return self.last_velocity.unwrap_or(feedforward);
+        }
+        let dt = state.since_update.as_secs_f64();
+        let setpoint = self.flow_rate * fraction;
+        let error = setpoint - state.flow_rate;
+        let derivative = match self.last_error {
+            Some(last_error) => (error - last_error) / dt,
+            None => 0.,
+        };
+        self.last_error.replace(error);
+        let integral = self.integral + error * dt;
+        let velocity =
+            feedforward + self.kp * error + self.ki * integral + self.kd * derivative;
+        // Stops the integral winding up while the output is pinned at a limit
+        if (state.min_velocity..=state.max_velocity).contains(&velocity) {
+            self.integral = integral;
+        }
+        self.last_velocity.replace(velocity);
+        velocity
+    }
+    fn reset(&mut self) {
+        self.last_error = None;
+        self.last_velocity = None;
+    }
+}
+
+struct BangBangController {
+    cutoff: f64,
+}
+impl DispenseController for BangBangController {
+    fn velocity(&mut self, state: &DispenseState) -> f64 {
+        if state.target - state.dispensed > self.cutoff {
+            state.max_velocity
+        } else {
+            state.min_velocity
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(dispensed: f64, flow_rate: f64, since_update: u64) -> DispenseState {
+        DispenseState {
+            target: 100.,
+            dispensed,
+            flow_rate,
+            since_update: Duration::from_millis(since_update),
+            min_velocity: 1.,
+            max_velocity: 10.,
+        }
+    }
+
+    fn pid(kp: f64, ki: f64, kd: f64) -> Box<dyn DispenseController> {
+        DispenseStrategy::Pid {
+            kp,
+            ki,
+            kd,
+            flow_rate: 20.,
+        }
+        .controller()
+    }
+
+    #[test]
+    fn proportional_slows_with_the_weight_left() {
+        let mut controller = DispenseStrategy::Proportional.controller();
+        assert_eq!(controller.velocity(&state(0., 0., 50)), 10.);
+        assert_eq!(controller.velocity(&state(75., 0., 50)), 2.5);
+    }
+
+    #[test]
+    fn bang_bang_switches_at_the_cutoff() {
+        let mut controller = DispenseStrategy::BangBang { cutoff: 10. }.controller();
+        assert_eq!(controller.velocity(&state(80., 0., 50)), 10.);
+        assert_eq!(controller.velocity(&state(95., 0., 50)), 1.);
+    }
+
+    #[test]
+    fn pid_starts_from_the_feedforward() {
+        // Nothing has flowed yet, so only the feedforward and the proportional term act
+        let mut controller = pid(0.1, 0., 0.);
+        assert_eq!(controller.velocity(&state(0., 0., 50)), 12.);
+        let mut controller = pid(0., 0., 0.);
+        assert_eq!(controller.velocity(&state(50., 10., 50)), 5.);
+    }
+
+    #[test]
+    fn pid_holds_its_output_over_short_updates() {
+        let mut controller = pid(0.1, 1., 1.);
+        let velocity = controller.velocity(&state(50., 5., 50));
+        assert!(velocity.is_finite());
+        assert_eq!(controller.velocity(&state(50., 500., 0)), velocity);
+        assert_eq!(controller.velocity(&state(50., 500., 5)), velocity);
+        // A reset controller falls back to the feedforward
+        controller.reset();
+        assert_eq!(controller.velocity(&state(50., 500., 0)), 5.);
+    }
+
+    #[test]
+    fn pid_integral_does_not_wind_up_at_a_limit() {
+        let mut controller = pid(0., 1., 0.);
+        // Far below the setpoint for a long time, the output stays pinned above the maximum
+        for _ in 0..10 {
+            assert!(controller.velocity(&state(0., 0., 1000)) > 10.);
+        }
+        // None of that error was integrated, so on the setpoint only the feedforward is left
+        assert_eq!(controller.velocity(&state(50., 10., 1000)), 5.);
+    }
+
+    #[test]
+    fn pid_derivative_restarts_after_a_reset() {
+        let mut controller = pid(0., 0., 1.);
+        controller.velocity(&state(50., 0., 100));
+        controller.reset();
+        assert_eq!(controller.velocity(&state(50., 10., 100)), 5.);
+    }
+}
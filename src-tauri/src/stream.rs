@@ -0,0 +1,70 @@
+use crate::errors::AppError;
+use crate::scale::Scale;
+use node_diagnostics::filter::Filter;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tauri::{AppHandle, Emitter};
+use tokio::time::Instant;
+
+pub const STREAM_EVENT: &str = "scale_stream";
+// Emitted with the error when a failed read stops the stream
+pub const STREAM_STOPPED_EVENT: &str = "scale_stream_stopped";
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct StreamSettings {
+    sample_period: Duration,
+    cutoff_frequency: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StreamSample {
+    time: Duration,
+    weight: f64,
+    filtered: Option<f64>,
+    cells: [f64; 4],
+}
+
+// Continuous readings pushed to the frontend, sampled by the hardware thread between jobs
+pub struct Stream {
+    settings: StreamSettings,
+    filter: Option<Filter>,
+    start: Instant,
+    next: Instant,
+}
+impl Stream {
+    pub fn new(settings: StreamSettings) -> Result<Self, AppError> {
+        if settings.sample_period.is_zero() {
+            return Err(AppError::Other(
+                "Stream sample period must be non-zero!".into(),
+            ));
+        }
+        let filter = settings.cutoff_frequency.map(|cutoff_frequency| {
+            Filter::new(1. / settings.sample_period.as_secs_f64(), cutoff_frequency)
+        });
+        let start = Instant::now();
+        Ok(Self {
+            settings,
+            filter,
+            start,
+            next: start,
+        })
+    }
+    pub fn get_deadline(&self) -> Instant {
+        self.next
+    }
+    pub fn sample(&mut self, scale: &mut dyn Scale, app: &AppHandle) -> Result<(), AppError> {
+        let cells = scale.get_raw_readings()?;
+        let weight = scale.get_weight()?;
+        let filtered = self.filter.as_mut().map(|filter| filter.apply(weight));
+        let now = Instant::now();
+        // Samples missed while a job held the hardware are skipped, not caught up on
+        self.next = (self.next + self.settings.sample_period).max(now);
+        let sample = StreamSample {
+            time: now - self.start,
+            weight,
+            filtered,
+            cells,
+        };
+        app.emit(STREAM_EVENT, sample).map_err(AppError::Tauri)
+    }
+}
@@ -0,0 +1,349 @@
+use crate::dispenser::{self, DispenseOutcome, DispenseResult, DispenseSettings};
+use crate::errors::AppError;
+use crate::hardware::HardwareManager;
+use crate::progress::ProgressReporter;
+use crate::statistics;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tauri::{AppHandle, Emitter, Manager};
+
+pub const STUDY_PROMPT_EVENT: &str = "study_prompt";
+const PROMPT_POLL_PERIOD: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+pub enum BetweenDispenses {
+    Pause(Duration),
+    // Waits for resume_study, e.g. while the hopper is refilled
+    Prompt,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct StudySettings {
+    dispenses: usize,
+    between: BetweenDispenses,
+    // Allowed error either side of the target, in the dispense weight's units
+    tolerance: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StudySummary {
+    dispenses: usize,
+    // Still short of the target after the last check
+    underweights: usize,
+    timeouts: usize,
+    mean_error: f64,
+    std_dev: f64,
+    // Negative when every dispense fell short
+    worst_overshoot: f64,
+    // Undefined without any spread in the errors
+    cpk: Option<f64>,
+    mean_duration: Duration,
+}
+impl StudySummary {
+    // Underweight and timed out dispenses count towards the statistics, cancelled ones do not
+    fn new(results: &[DispenseResult], tolerance: f64) -> Option<Self> {
+        let completed: Vec<&DispenseResult> = results
+            .iter()
+            .filter(|result| result.get_outcome() != DispenseOutcome::Cancelled)
+            .collect();
+        if completed.is_empty() {
+            return None;
+        }
+        let errors: Vec<f64> = completed.iter().map(|result| result.get_error()).collect();
+        let mean_error = statistics::mean(&errors)?;
+        let std_dev = statistics::std_dev(&errors);
+        let cpk = (std_dev > 0.)
+            .then(|| (tolerance - mean_error).min(mean_error + tolerance) / (3. * std_dev));
+        let total_duration: Duration = completed.iter().map(|result| result.get_duration()).sum();
+        let count = |outcome| {
+            completed
+                .iter()
+                .filter(|result| result.get_outcome() == outcome)
+                .count()
+        };
+        Some(Self {
+            dispenses: completed.len(),
+            underweights: count(DispenseOutcome::Underweight),
+            timeouts: count(DispenseOutcome::Timeout),
+            mean_error,
+            std_dev,
+            worst_overshoot: statistics::max(&errors)?,
+            cpk,
+            mean_duration: total_duration / completed.len() as u32,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DispenseStudy {
+    id: String,
+    started: Duration,
+    dispense_settings: DispenseSettings,
+    study_settings: StudySettings,
+    results: Vec<DispenseResult>,
+    summary: Option<StudySummary>,
+    cancelled: bool,
+    // The error that stopped the study early, the results before it are kept
+    error: Option<String>,
+}
+impl DispenseStudy {
+    // Runs each dispense as its own job, so the hardware is free while the study waits
+    // between them, and saves the study even when it was cancelled or failed
+    pub async fn run(
+        hardware: &HardwareManager,
+        app: AppHandle,
+        dispense_settings: DispenseSettings,
+        study_settings: StudySettings,
+    ) -> Result<Self, AppError> {
+        if study_settings.dispenses == 0 {
+            return Err(AppError::Other(
+                "A study needs at least one dispense!".into(),
+            ));
+        }
+        let phidget_id = hardware
+            .run_sync("dispense_study", |hardware| {
+                Ok(hardware.get_scale()?.get_phidget_id())
+            })
+            .await?;
+        let control = hardware.get_study_control().clone();
+        control.start()?;
+        let mut study = Self::new(phidget_id, dispense_settings, study_settings);
+        let result = study.conduct(hardware, &control, &app).await;
+        control.finish();
+        if let Err(e) = result {
+            log::warn!("Study {} stopped: {e}", study.id);
+            study.error.replace(e.to_string());
+        }
+        study.summary = StudySummary::new(&study.results, study.study_settings.tolerance);
+        study.save(&app)?;
+        Ok(study)
+    }
+    fn new(
+        phidget_id: i32,
+        dispense_settings: DispenseSettings,
+        study_settings: StudySettings,
+    ) -> Self {
+        let started = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        Self {
+            id: format!("{phidget_id}-{}", started.as_millis()),
+            started,
+            dispense_settings,
+            study_settings,
+            results: Vec::new(),
+            summary: None,
+            cancelled: false,
+            error: None,
+        }
+    }
+    // Stops at the first cancelled dispense or error
+    async fn conduct(
+        &mut self,
+        hardware: &HardwareManager,
+        control: &StudyControl,
+        app: &AppHandle,
+    ) -> Result<(), AppError> {
+        let dispenses = self.study_settings.dispenses;
+        for dispense in 1..=dispenses {
+            if dispense > 1 {
+                self.wait_between(dispense - 1, control, app).await;
+            }
+            if control.is_cancelled() {
+                self.cancelled = true;
+                break;
+            }
+            let settings = self.dispense_settings.clone();
+            let result = hardware
+                .run("dispense_study", move |hardware| {
+                    Box::pin(async move {
+                        let motor = hardware.get_motor(0)?;
+                        let cancel = hardware.get_cancel_token();
+                        let mut progress = hardware.get_progress_reporter();
+                        // The study reports progress by dispense, so each dispense stays quiet
+                        let (_, result) = dispenser::dispense(
+                            &motor,
+                            hardware.get_scale()?,
+                            &settings,
+                            &cancel,
+                            &mut ProgressReporter::silent(),
+                        )
+                        .await?;
+                        progress.report(dispense, dispenses, Some(result.get_dispensed()), || None);
+                        Ok(result)
+                    })
+                })
+                .await;
+            let result = match result {
+                Ok(result) => result,
+                // Cancelled before the dispense started
+                Err(AppError::Cancelled) => {
+                    self.cancelled = true;
+                    break;
+                }
+                Err(e) => return Err(e),
+            };
+            let cancelled = result.get_outcome() == DispenseOutcome::Cancelled;
+            self.results.push(result);
+            if cancelled {
+                self.cancelled = true;
+                break;
+            }
+        }
+        Ok(())
+    }
+    async fn wait_between(&self, completed: usize, control: &StudyControl, app: &AppHandle) {
+        match self.study_settings.between {
+            BetweenDispenses::Pause(pause) => {
+                let end = tokio::time::Instant::now() + pause;
+                while tokio::time::Instant::now() < end && !control.is_cancelled() {
+                    tokio::time::sleep(PROMPT_POLL_PERIOD.min(pause)).await;
+                }
+            }
+            BetweenDispenses::Prompt => {
+                control.wait_for_resume();
+                if let Err(e) = app.emit(
+                    STUDY_PROMPT_EVENT,
+                    (&self.id, completed, self.study_settings.dispenses),
+                ) {
+                    log::warn!("Failed to prompt for the next dispense: {e}");
+                }
+                while !control.is_resumed() && !control.is_cancelled() {
+                    tokio::time::sleep(PROMPT_POLL_PERIOD).await;
+                }
+            }
+        }
+    }
+    fn save(&self, app: &AppHandle) -> Result<(), AppError> {
+        let dir = app
+            .path()
+            .app_data_dir()
+            .map_err(AppError::Tauri)?
+            .join("studies");
+        fs::create_dir_all(&dir).map_err(AppError::Io)?;
+        let path: PathBuf = dir.join(format!("{}.json", self.id));
+        let contents = serde_json::to_string_pretty(self).map_err(AppError::Serde)?;
+        fs::write(path, contents).map_err(AppError::Io)
+    }
+}
+
+#[derive(Debug, Default)]
+struct StudyState {
+    running: bool,
+    resumed: bool,
+    cancelled: bool,
+}
+
+// Lets the frontend resume or cancel a study between dispenses, when it holds no job
+#[derive(Debug, Clone, Default)]
+pub struct StudyControl(Arc<Mutex<StudyState>>);
+impl StudyControl {
+    fn start(&self) -> Result<(), AppError> {
+        let mut state = self.0.lock().unwrap();
+        if state.running {
+            return Err(AppError::HardwareBusy("dispense_study".into()));
+        }
+        *state = StudyState {
+            running: true,
+            ..StudyState::default()
+        };
+        Ok(())
+    }
+    fn finish(&self) {
+        self.0.lock().unwrap().running = false;
+    }
+    pub fn resume(&self) {
+        self.0.lock().unwrap().resumed = true;
+    }
+    // False without a study to cancel
+    pub fn cancel(&self) -> bool {
+        let mut state = self.0.lock().unwrap();
+        state.cancelled = state.running;
+        state.running
+    }
+    fn wait_for_resume(&self) {
+        self.0.lock().unwrap().resumed = false;
+    }
+    fn is_resumed(&self) -> bool {
+        self.0.lock().unwrap().resumed
+    }
+    fn is_cancelled(&self) -> bool {
+        self.0.lock().unwrap().cancelled
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dispenser::testing::dispense_result;
+
+    fn result(outcome: DispenseOutcome, dispensed: f64, secs: u64) -> DispenseResult {
+        dispense_result(outcome, 50., dispensed, Duration::from_secs(secs))
+    }
+
+    #[test]
+    fn summary_of_completed_dispenses() {
+        let results = [
+            result(DispenseOutcome::Underweight, 49., 10),
+            result(DispenseOutcome::Success, 51., 20),
+            result(DispenseOutcome::Timeout, 50., 30),
+            // Left out of the statistics
+            result(DispenseOutcome::Cancelled, 10., 5),
+        ];
+        let summary = StudySummary::new(&results, 3.).unwrap();
+        assert_eq!(summary.dispenses, 3);
+        assert_eq!(summary.underweights, 1);
+        assert_eq!(summary.timeouts, 1);
+        assert_eq!(summary.mean_error, 0.);
+        assert_eq!(summary.std_dev, 1.);
+        assert_eq!(summary.worst_overshoot, 1.);
+        assert_eq!(summary.cpk, Some(1.));
+        assert_eq!(summary.mean_duration, Duration::from_secs(20));
+    }
+
+    #[test]
+    fn cpk_uses_the_nearer_limit() {
+        let results = [
+            result(DispenseOutcome::Success, 51., 10),
+            result(DispenseOutcome::Success, 53., 10),
+        ];
+        let summary = StudySummary::new(&results, 3.).unwrap();
+        let std_dev = 2f64.sqrt();
+        assert_eq!(summary.cpk, Some(1. / (3. * std_dev)));
+    }
+
+    #[test]
+    fn single_dispense_has_no_cpk() {
+        let summary = StudySummary::new(&[result(DispenseOutcome::Success, 52., 10)], 3.).unwrap();
+        assert_eq!(summary.dispenses, 1);
+        assert_eq!(summary.std_dev, 0.);
+        assert_eq!(summary.cpk, None);
+        assert_eq!(summary.worst_overshoot, 2.);
+    }
+
+    #[test]
+    fn all_cancelled_has_no_summary() {
+        let results = [
+            result(DispenseOutcome::Cancelled, 10., 5),
+            result(DispenseOutcome::Cancelled, 20., 5),
+        ];
+        assert!(StudySummary::new(&results, 3.).is_none());
+        assert!(StudySummary::new(&[], 3.).is_none());
+    }
+
+    #[test]
+    fn cancel_only_reaches_a_running_study() {
+        let control = StudyControl::default();
+        assert!(!control.cancel());
+        control.start().unwrap();
+        assert!(!control.is_cancelled());
+        assert!(matches!(control.start(), Err(AppError::HardwareBusy(_))));
+        assert!(control.cancel());
+        assert!(control.is_cancelled());
+        control.finish();
+        // The next study starts without the old cancel
+        control.start().unwrap();
+        assert!(!control.is_cancelled());
+    }
+}
@@ -0,0 +1,126 @@
+use crate::errors::AppError;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+pub enum Tolerance {
+    Grams(f64),
+    Percent(f64),
+}
+impl Tolerance {
+    fn allowed_error(&self, reference: f64) -> f64 {
+        match self {
+            Tolerance::Grams(grams) => *grams,
+            Tolerance::Percent(percent) => reference.abs() * percent / 100.,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct VerificationPoint {
+    reference: f64,
+    measured: f64,
+    error: f64,
+    allowed_error: f64,
+    passed: bool,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct VerificationRun {
+    started: Duration,
+    references: Vec<f64>,
+    tolerance: Tolerance,
+    points: Vec<VerificationPoint>,
+    next_reference: Option<f64>,
+    passed: Option<bool>,
+}
+impl VerificationRun {
+    pub fn new(references: Vec<f64>, tolerance: Tolerance) -> Result<Self, AppError> {
+        if references.is_empty() {
+            return Err(AppError::Other(
+                "Verification needs at least one reference weight!".into(),
+            ));
+        }
+        // A percentage of nothing allows no error at all, so empty-platter checks need grams
+        if matches!(tolerance, Tolerance::Percent(_)) && references.contains(&0.) {
+            return Err(AppError::Other(
+                "Percent tolerances cannot check a 0 g reference, use grams instead!".into(),
+            ));
+        }
+        Ok(Self {
+            started: SystemTime::now().duration_since(UNIX_EPOCH).unwrap(),
+            next_reference: references.first().copied(),
+            references,
+            tolerance,
+            points: Vec::new(),
+            passed: None,
+        })
+    }
+    pub fn get_next_reference(&self) -> Option<f64> {
+        self.next_reference
+    }
+    pub fn record(&mut self, measured: f64) -> Result<VerificationPoint, AppError> {
+        let reference = self.next_reference.ok_or(AppError::VerificationComplete)?;
+        let error = measured - reference;
+        let allowed_error = self.tolerance.allowed_error(reference);
+        let point = VerificationPoint {
+            reference,
+            measured,
+            error,
+            allowed_error,
+            passed: error.abs() <= allowed_error,
+        };
+        self.points.push(point.clone());
+        self.next_reference = self.references.get(self.points.len()).copied();
+        if self.next_reference.is_none() {
+            self.passed = Some(self.points.iter().all(|point| point.passed));
+        }
+        Ok(point)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_each_reference_in_order() {
+        let mut run = VerificationRun::new(vec![100., 500.], Tolerance::Grams(0.5)).unwrap();
+        assert_eq!(run.get_next_reference(), Some(100.));
+        let point = run.record(100.4).unwrap();
+        assert!(point.passed);
+        assert!((point.error - 0.4).abs() < 1e-9);
+        assert_eq!(run.passed, None);
+        assert_eq!(run.get_next_reference(), Some(500.));
+        assert!(!run.record(499.4).unwrap().passed);
+        assert_eq!(run.passed, Some(false));
+        assert!(matches!(
+            run.record(500.),
+            Err(AppError::VerificationComplete)
+        ));
+    }
+
+    #[test]
+    fn percent_tolerances_scale_with_the_reference() {
+        let mut run = VerificationRun::new(vec![100., 2000.], Tolerance::Percent(0.1)).unwrap();
+        let point = run.record(100.2).unwrap();
+        assert!((point.allowed_error - 0.1).abs() < 1e-12);
+        assert!(!point.passed);
+        let point = run.record(2001.5).unwrap();
+        assert!((point.allowed_error - 2.).abs() < 1e-12);
+        assert!(point.passed);
+    }
+
+    #[test]
+    fn zero_references_need_a_gram_tolerance() {
+        assert!(VerificationRun::new(vec![0., 500.], Tolerance::Percent(1.)).is_err());
+        let mut run = VerificationRun::new(vec![0.], Tolerance::Grams(0.2)).unwrap();
+        assert!(run.record(-0.1).unwrap().passed);
+        assert_eq!(run.passed, Some(true));
+    }
+
+    #[test]
+    fn needs_a_reference() {
+        assert!(VerificationRun::new(Vec::new(), Tolerance::Grams(1.)).is_err());
+    }
+}
@@ -1,10 +1,23 @@
-import { useState, useRef, useEffect } from "react"; // Added useRef and useEffect
+import { useState, useEffect } from "react";
 import { invoke } from "@tauri-apps/api/core";
+import { listen } from "@tauri-apps/api/event";
 import "./App.css";
 import { useNavigate } from "react-router";
 import {dropScale, sleepForDenoise} from "./utilities/utils.ts";
 import {MotorControls} from "./utilities/MotorControls.tsx";
 
+// Payload of the backend's "progress" event
+interface Progress {
+    job: number;
+    name: string;
+    attempt: number;
+    collected: number;
+    expected: number;
+    elapsed: { secs: number; nanos: number };
+    weight: number | null;
+    cells: number[] | null;
+}
+
 function App() {
     const [currentStatus, updateStatus] = useState("");
     const [samples, updateSamples] = useState(100);
@@ -14,7 +27,8 @@ function App() {
     // State and ref for the addTrial loading bar
     const [isAddingTrial, setIsAddingTrial] = useState(false);
     const [trialProgress, setTrialProgress] = useState(0);
-    const trialProgressInterval = useRef<number | null>(null);
+    const [trialReadings, setTrialReadings] = useState<number[]>([]);
+    const [trialAttempt, setTrialAttempt] = useState(1);
 
     const navigate = useNavigate();
 
@@ -35,33 +49,10 @@ function App() {
         updateStatus("Collecting data...");
         await sleepForDenoise();
 
-        const totalTime = currentSamples * samplePeriod;
-
-
         setIsAddingTrial(true);
         setTrialProgress(0);
-
-        const steps = 200; // Number of steps for the progress bar animation
-        const increment = 100 / steps;
-        const intervalDuration = totalTime > 0 ? totalTime / steps : 50; // Avoid division by zero, ensure minimum interval
-
-        // Clear any existing interval
-        if (trialProgressInterval.current) {
-            window.clearInterval(trialProgressInterval.current);
-        }
-
-        trialProgressInterval.current = window.setInterval(() => {
-            setTrialProgress(prevProgress => {
-                const newProgress = prevProgress + increment;
-                if (newProgress >= 100) {
-                    if (trialProgressInterval.current) {
-                        window.clearInterval(trialProgressInterval.current);
-                    }
-                    return 100;
-                }
-                return newProgress;
-            });
-        }, intervalDuration);
+        setTrialReadings([]);
+        setTrialAttempt(1);
 
         try {
             const result: string = await invoke("add_trial", {
@@ -73,22 +64,23 @@ function App() {
         } catch (error: any) {
             updateStatus(String(error));
         } finally {
-            if (trialProgressInterval.current) {
-                window.clearInterval(trialProgressInterval.current);
-            }
-            setTrialProgress(100); // Ensure progress hits 100
             setIsAddingTrial(false);
-            // Optionally reset progress to 0 after a short delay or immediately
-            // setTimeout(() => setTrialProgress(0), 500);
         }
     }
 
-    // Cleanup interval on component unmount
+    // Progress comes from the backend while the trial is being sampled
     useEffect(() => {
-        return () => {
-            if (trialProgressInterval.current) {
-                window.clearInterval(trialProgressInterval.current);
+        const unlisten = listen<Progress>("progress", (event) => {
+            if (event.payload.name !== "calibration_trial") {
+                return;
             }
+            const { attempt, collected, expected, cells } = event.payload;
+            setTrialAttempt(attempt);
+            setTrialProgress(expected > 0 ? (collected / expected) * 100 : 0);
+            setTrialReadings(cells ?? []);
+        });
+        return () => {
+            unlisten.then((stop) => stop());
         };
     }, []);
 
@@ -105,6 +97,18 @@ function App() {
                     <div className="loading-bar" style={{ width: `${trialProgress}%` }}></div>
                 </div>
             )}
+            {isAddingTrial && trialReadings.length > 0 && (
+                <section className="data-display">
+                    {trialAttempt > 1 && (
+                        <div className="data-item">
+                            <strong>Attempt:</strong> {trialAttempt} (retrying until the readings are stable)
+                        </div>
+                    )}
+                    <div className="data-item">
+                        <strong>Load Cells:</strong> {trialReadings.map((reading) => reading.toFixed(6)).join(", ")}
+                    </div>
+                </section>
+            )}
 
 
             <MotorControls updateStatus={updateStatus} isDisabled={false}/>
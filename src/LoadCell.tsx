@@ -1,11 +1,23 @@
-import { useState, useRef,  } from "react"; // Added useRef and useEffect
+import { useState, useRef, useEffect } from "react";
 import { invoke } from "@tauri-apps/api/core";
+import { listen } from "@tauri-apps/api/event";
 import "./App.css";
 import { useNavigate } from "react-router";
 import {dropScale, Duration, durationFromMillis, sleepForDenoise} from "./utilities/utils.ts";
 import {MotorControls} from "./utilities/MotorControls.tsx";
 import Plot, {LineData} from "./plot.tsx";
 
+// Payload of the backend's "scale_stream" event
+interface StreamSample {
+    time: Duration;
+    weight: number;
+    filtered: number | null;
+    cells: number[];
+}
+
+// Number of streamed samples kept on the plot
+const STREAM_WINDOW = 250;
+
 function App() {
     const [currentStatus, updateStatus] = useState("");
     const [samples, updateSamples] = useState(100);
@@ -15,6 +27,9 @@ function App() {
     const [isPlotting, setIsPlotting] = useState(false);
     const progressInterval = useRef<number | null>(null);
     const [plotDataSets, setPlotDataSets] = useState<LineData[]>([]);
+    const [isStreaming, setIsStreaming] = useState(false);
+    const [latestSample, setLatestSample] = useState<StreamSample | null>(null);
+    const streamSamples = useRef<StreamSample[]>([]);
 
     const navigate = useNavigate();
 
@@ -111,6 +126,68 @@ function App() {
         });
     }
 
+    async function stopCapture() {
+        try {
+            const capture: { path: string, readings: number } = await invoke("stop_raw_data_collection");
+            updateStatus(`Saved ${capture.readings} raw readings to ${capture.path}`);
+        } catch (error: any) {
+            updateStatus(String(error));
+        }
+    }
+
+    async function startStream() {
+        try {
+            streamSamples.current = [];
+            await invoke("start_stream", {
+                streamSettings: { sample_period: durationFromMillis(samplePeriod), cutoff_frequency: null }
+            });
+            setIsStreaming(true);
+            updateStatus("Streaming...");
+        } catch (error: any) {
+            updateStatus(String(error));
+        }
+    }
+
+    async function stopStream() {
+        try {
+            await invoke("stop_stream");
+            updateStatus("Stream stopped");
+        } catch (error: any) {
+            updateStatus(String(error));
+        } finally {
+            setIsStreaming(false);
+        }
+    }
+
+    useEffect(() => {
+        const unlisten = listen<StreamSample>("scale_stream", (event) => {
+            const samples = [...streamSamples.current, event.payload].slice(-STREAM_WINDOW);
+            streamSamples.current = samples;
+            setLatestSample(event.payload);
+            const xValues = samples.map((sample) => sample.time.secs + sample.time.nanos * 1e-9);
+            setPlotDataSets([0, 1, 2, 3].map((cell) => ({
+                xValues,
+                yValues: samples.map((sample) => sample.cells[cell] * 10 ** 6), // Convert to microvolts
+                label: `Load Cell ${cell}`
+            })));
+        });
+        return () => {
+            unlisten.then((stop) => stop());
+            invoke("stop_stream").catch(() => {});
+        };
+    }, []);
+
+    // The backend stops the stream by itself when a read fails
+    useEffect(() => {
+        const unlisten = listen<string>("scale_stream_stopped", (event) => {
+            setIsStreaming(false);
+            updateStatus(`Stream stopped: ${event.payload}`);
+        });
+        return () => {
+            unlisten.then((stop) => stop());
+        };
+    }, []);
+
     return (
         <main className={`app-container`}>
             <header>
@@ -128,7 +205,13 @@ function App() {
 
             <section className="controls">
                 <div className="button-grid">
-                    <button onClick={() => plotData({samples: samples, sample_period: durationFromMillis(samplePeriod)})} disabled={isPlotting}>Run Trial</button>
+                    <button onClick={() => plotData({samples: samples, sample_period: durationFromMillis(samplePeriod)})} disabled={isPlotting || isStreaming}>Run Trial</button>
+                    {isStreaming ? (
+                        <button onClick={stopStream}>Stop Stream</button>
+                    ) : (
+                        <button onClick={startStream} disabled={isPlotting}>Start Stream</button>
+                    )}
+                    <button onClick={stopCapture}>Stop Capture</button>
                 </div>
             </section>
 
@@ -152,7 +235,7 @@ function App() {
                         value={samplePeriod}
                         step={5}
                         onChange={(e) => updateSamplePeriod(parseInt(e.target.value))}
-                        disabled={isPlotting}
+                        disabled={isPlotting || isStreaming}
                     />
                 </div>
             </section>
@@ -161,6 +244,11 @@ function App() {
                 <div className="data-item">
                     <strong>Status:</strong> {currentStatus}
                 </div>
+                {isStreaming && latestSample && (
+                    <div className="data-item">
+                        <strong>Weight:</strong> {latestSample.weight.toFixed(2)}
+                    </div>
+                )}
             </section>
 
             <section className="plot-container">
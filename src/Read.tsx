@@ -1,5 +1,6 @@
 import { useState, useRef, useEffect } from "react";
 import { invoke } from "@tauri-apps/api/core";
+import { listen } from "@tauri-apps/api/event";
 import "./App.css";
 import Plot, { LineData } from './plot'; // Import LineData
 import {dropScale, Duration, durationFromMillis, sleepForDenoise} from "./utilities/utils.ts";
@@ -11,12 +12,14 @@ function App() {
         trial: TrialType;
         samples: number;
         sample_period: Duration;
-        cutoff_frequency: number | null
+        cutoff_frequency: number | null;
+        dispense_settings?: DispenseSettings;
     }
     type TrialType =
         | "Raw"
         | "Median"
-        | "Filtered";
+        | "Filtered"
+        | "Dispense";
     interface DispenseSettings {
         sample_period: Duration;
         cutoff_frequency: number;
@@ -28,6 +31,46 @@ function App() {
         timeout: Duration;
         start_buffer: Duration;
         check_samples: number;
+        strategy?: DispenseStrategy;
+    }
+    type DispenseStrategy =
+        | "Proportional"
+        | { Pid: { kp: number; ki: number; kd: number; flow_rate: number } }
+        | { BangBang: { cutoff: number } };
+    interface DispenseResult {
+        outcome: "Success" | "Underweight" | "Timeout" | "Cancelled";
+        target: number;
+        dispensed: number;
+        error: number;
+        error_percent: number;
+        duration: Duration;
+        checks: number;
+        final_position: number | null;
+    }
+    interface StudySummary {
+        dispenses: number;
+        underweights: number;
+        timeouts: number;
+        mean_error: number;
+        std_dev: number;
+        worst_overshoot: number;
+        cpk: number | null;
+        mean_duration: Duration;
+    }
+    // Payload of the backend's "progress" event
+    interface Progress {
+        job: number | null;
+        name: string;
+        collected: number;
+        expected: number;
+        weight: number | null;
+    }
+    interface DispenseStudy {
+        id: string;
+        results: DispenseResult[];
+        summary: StudySummary | null;
+        cancelled: boolean;
+        error: string | null;
     }
 
     const [currentStatus, updateStatus] = useState("");
@@ -45,13 +88,22 @@ function App() {
     const [timeout, setTimeout] = useState(30);
     const [startBuffer, setStartBuffer] = useState(1500);
     const [retract, setRetract] = useState(0.3);
+    const [strategy, setStrategy] = useState<"Proportional" | "Pid" | "BangBang">("Proportional");
+    const [pidGains, setPidGains] = useState({ kp: 0.01, ki: 0.005, kd: 0, flow_rate: 10 });
+    const [bangBangCutoff, setBangBangCutoff] = useState(10);
+    const [studyDispenses, setStudyDispenses] = useState(10);
+    const [studyTolerance, setStudyTolerance] = useState(2);
+    const [studyPause, setStudyPause] = useState(5);
+    const [studyPrompt, setStudyPrompt] = useState(false);
+    const [awaitingRefill, setAwaitingRefill] = useState(false);
 
     // Changed from xPlotValues and yPlotValues to plotDataSets
     const [plotDataSets, setPlotDataSets] = useState<LineData[]>([]);
 
     const [progress, setProgress] = useState(0);
     const [isPlotting, setIsPlotting] = useState(false);
-    const progressInterval = useRef<number | null>(null);
+    // Hardware job most recently started from this page, so Cancel targets it
+    const currentJob = useRef<number | null>(null);
 
     const navigate = useNavigate();
 
@@ -61,6 +113,20 @@ function App() {
         return sortedData[Math.floor(sortedData.length / 2)];
     }
 
+    function describeDispense(result: DispenseResult): string {
+        const duration = result.duration.secs + result.duration.nanos * 1e-9;
+        const summary = `${result.dispensed.toFixed(1)} of ${result.target} (${result.error >= 0 ? "+" : ""}${result.error.toFixed(1)}, ${result.error_percent.toFixed(1)}%) in ${duration.toFixed(1)}s, ${result.checks} checks`;
+        switch (result.outcome) {
+            case "Success":
+                return `Dispensed ${summary}`;
+            case "Underweight":
+                return `Dispense UNDERWEIGHT after the last check: ${summary}`;
+            case "Timeout":
+                return `Dispense TIMED OUT: ${summary}`;
+            case "Cancelled":
+                return `Dispense cancelled: ${summary}`;
+        }
+    }
     async function setPhidgetInterval() {
         try {
             const result: string = await invoke("set_phidget_interval", {samplePeriod: durationFromMillis(phidgetSamplePeriod)})
@@ -72,39 +138,19 @@ function App() {
     }
 
     async function plotData(dataRequest: DataRequest) {
-        updateStatus("Conducting trial...");
+        updateStatus(dataRequest.trial === "Dispense" ? "Dispensing..." : "Conducting trial...");
         await sleepForDenoise();
 
-        const totalTime = dataRequest.samples * (dataRequest.sample_period.secs + dataRequest.sample_period.nanos / 1_000_000_000) * 1000;
-
+        // The bar follows the backend's progress events: samples collected for a trial, or
+        // the share of the target dispensed so far
         setIsPlotting(true);
         setProgress(0);
 
-        const steps = 500;
-        const increment = 100 / steps;
-        const intervalDuration = totalTime > 0 ? totalTime / steps : 50; // Prevent division by zero
-
         return new Promise<{ readings: number[]; times: { secs: number; nanos: number }[] }>((resolve, reject) => {
-            progressInterval.current = window.setInterval(() => {
-                setProgress(prevProgress => {
-                    const newProgress = prevProgress + increment;
-                    if (newProgress >= 100) {
-                        if (progressInterval.current !== null) {
-                            window.clearInterval(progressInterval.current);
-                        }
-                        return 100;
-                    }
-                    return newProgress;
-                });
-            }, intervalDuration);
-
             invoke("plot", { dataRequest })
                 .then((result: unknown) => {
-                    if (progressInterval.current !== null) {
-                        window.clearInterval(progressInterval.current);
-                    }
                     if (typeof result === 'object' && result !== null && 'readings' in result && Array.isArray((result as any).readings) && 'times' in result && Array.isArray((result as any).times)) {
-                        const typedResult = result as { readings: number[]; times: { secs: number; nanos: number }[] };
+                        const typedResult = result as { readings: number[]; times: { secs: number; nanos: number }[]; dispense?: DispenseResult };
 
                         // Update to set plotDataSets
                         const newXValues = typedResult.times.map(obj => obj.secs + obj.nanos * 1e-9);
@@ -112,12 +158,12 @@ function App() {
                         const newLine: LineData = {
                             xValues: newXValues,
                             yValues: newYValues,
-                            label: `${dataRequest.trial} Data`,
+                            label: dataRequest.trial === "Dispense" ? "Dispense Attempt" : `${dataRequest.trial} Data`,
                             borderColor: "#0000FF"
                         };
                         setPlotDataSets([newLine]); // Replace current plot with the new line
 
-                        updateStatus("Data logged!");
+                        updateStatus(typedResult.dispense ? describeDispense(typedResult.dispense) : "Data logged!");
                         updateWeight(median(typedResult.readings));
                         resolve(typedResult);
                     } else {
@@ -126,9 +172,6 @@ function App() {
                     }
                 })
                 .catch(error => {
-                    if (progressInterval.current !== null) {
-                        window.clearInterval(progressInterval.current);
-                    }
                     updateStatus(String(error));
                     reject(error);
                 })
@@ -168,14 +211,19 @@ function App() {
         }
     }
 
-    async function handleDispense() {
-        let dataRequest: DataRequest = {
-            trial: "Raw", // Dispense usually uses raw data for plotting its attempt
-            samples: samples, // Or a specific number of samples for dispense monitoring
-            sample_period: {secs: 0, nanos: samplePeriod*1000000},
-            cutoff_frequency: null // Typically no filter during dispense plot
+    function describeStudy(study: DispenseStudy): string {
+        const stopped = study.error ? ` (stopped: ${study.error})` : study.cancelled ? " (cancelled)" : "";
+        if (!study.summary) {
+            return `Study ${study.id}${stopped}: no dispense finished`;
         }
-        let dispenseSettings: DispenseSettings = {
+        const summary = study.summary;
+        const duration = summary.mean_duration.secs + summary.mean_duration.nanos * 1e-9;
+        const cpk = summary.cpk === null ? "n/a" : summary.cpk.toFixed(2);
+        return `Study ${study.id}${stopped}: ${summary.dispenses} dispenses, ${summary.underweights} underweight, ${summary.timeouts} timeouts, mean error ${summary.mean_error.toFixed(2)}, std dev ${summary.std_dev.toFixed(2)}, worst overshoot ${summary.worst_overshoot.toFixed(2)}, Cpk ${cpk}, mean ${duration.toFixed(1)}s`;
+    }
+
+    function buildDispenseSettings(): DispenseSettings {
+        return {
             sample_period: {secs: 0, nanos: samplePeriod*1000000},
             cutoff_frequency: cutoffFrequency, // This might be for the control algorithm, not the plot
             check_offset: checkOffset,
@@ -186,82 +234,77 @@ function App() {
             timeout: {secs: timeout, nanos: 0},
             start_buffer: durationFromMillis(startBuffer),
             check_samples: 50,
+            strategy: strategy === "Pid"
+                ? { Pid: pidGains }
+                : strategy === "BangBang"
+                    ? { BangBang: { cutoff: bangBangCutoff } }
+                    : "Proportional",
         }
-        await dispense(dataRequest, dispenseSettings);
     }
 
-    async function dispense(dataRequest: DataRequest, dispenseSettings: DispenseSettings) {
-        updateStatus("Dispensing...");
-        await sleepForDenoise();
-
-        const totalTime = dispenseSettings.timeout.secs * 1000; // Use dispense timeout for progress
-        setIsPlotting(true); // isPlotting can be reused or renamed to isBusy/isDispensing
-        setProgress(0);
-
-        const steps = 500;
-        const increment = 100 / steps;
-        const intervalDuration = totalTime > 0 ? totalTime / steps : 50; // Prevent division by zero
-
-        return new Promise<{ readings: number[]; times: { secs: number; nanos: number }[] }>((resolve, reject) => {
-            progressInterval.current = window.setInterval(() => {
-                setProgress(prevProgress => {
-                    const newProgress = prevProgress + increment;
-                    if (newProgress >= 100) {
-                        if (progressInterval.current !== null) {
-                            window.clearInterval(progressInterval.current);
-                        }
-                        return 100;
-                    }
-                    return newProgress;
-                });
-            }, intervalDuration);
-
-            invoke("dispense", { dataRequest, dispenseSettings })
-                .then((result: unknown) => {
-                    if (progressInterval.current !== null) {
-                        window.clearInterval(progressInterval.current);
-                    }
-                    if (typeof result === 'object' && result !== null && 'readings' in result && Array.isArray((result as any).readings) && 'times' in result && Array.isArray((result as any).times)) {
-                        const typedResult = result as { readings: number[]; times: { secs: number; nanos: number }[] };
+    async function handleDispense() {
+        const dispenseSettings = buildDispenseSettings();
+        let dataRequest: DataRequest = {
+            trial: "Dispense",
+            samples: samples,
+            sample_period: dispenseSettings.sample_period,
+            cutoff_frequency: null,
+            dispense_settings: dispenseSettings
+        }
+        await plotData(dataRequest);
+    }
 
-                        // Update to set plotDataSets
-                        const newXValues = typedResult.times.map(obj => obj.secs + obj.nanos * 1e-9);
-                        const newYValues = typedResult.readings;
-                        const newLine: LineData = {
-                            xValues: newXValues,
-                            yValues: newYValues,
-                            label: "Dispense Attempt" // Example label for dispense
-                        };
-                        console.log(newLine);
-                        setPlotDataSets([newLine]); // Replace current plot with the new line
+    async function handleStudy() {
+        setIsPlotting(true);
+        updateStatus(`Running ${studyDispenses} dispenses...`);
+        try {
+            const study: DispenseStudy = await invoke("dispense_study", {
+                dispenseSettings: buildDispenseSettings(),
+                studySettings: {
+                    dispenses: studyDispenses,
+                    between: studyPrompt ? "Prompt" : { Pause: { secs: studyPause, nanos: 0 } },
+                    tolerance: studyTolerance,
+                },
+            });
+            updateStatus(describeStudy(study));
+        } catch (error) {
+            updateStatus(String(error));
+        } finally {
+            setAwaitingRefill(false);
+            setIsPlotting(false);
+        }
+    }
 
-                        updateStatus("Dispense cycle finished!");
-                        updateWeight(median(typedResult.readings)); // Update weight with final reading
-                        resolve(typedResult);
-                    } else {
-                        updateStatus("Unexpected data format from dispense!");
-                        reject("Unexpected data format from dispense");
-                    }
-                })
-                .catch(error => {
-                    if (progressInterval.current !== null) {
-                        window.clearInterval(progressInterval.current);
-                    }
-                    updateStatus(`Dispense error: ${String(error)}`);
-                    reject(error);
-                })
-                .finally(() => {
-                    setIsPlotting(false);
-                    setProgress(100); // Ensure progress bar completes
-                });
+    useEffect(() => {
+        const unlisten = listen<{ id: number }>("hardware_job", (event) => {
+            currentJob.current = event.payload.id;
         });
-    }
+        return () => {
+            unlisten.then((f) => f());
+        };
+    }, []);
 
     useEffect(() => {
+        const unlisten = listen<[string, number, number]>("study_prompt", (event) => {
+            const [, completed, dispenses] = event.payload;
+            updateStatus(`Dispensed ${completed} of ${dispenses}, refill and resume`);
+            setAwaitingRefill(true);
+        });
         return () => {
-            if (progressInterval.current !== null) {
-                window.clearInterval(progressInterval.current);
+            unlisten.then((f) => f());
+        };
+    }, []);
+
+    useEffect(() => {
+        const unlisten = listen<Progress>("progress", (event) => {
+            const { job, name, collected, expected } = event.payload;
+            if (name !== "plot" || job !== currentJob.current) {
+                return;
             }
+            setProgress(expected > 0 ? Math.min((collected / expected) * 100, 100) : 0);
+        });
+        return () => {
+            unlisten.then((f) => f());
         };
     }, []);
 
@@ -456,6 +499,44 @@ function App() {
                         disabled={isPlotting}
                         // style={{ width: '70px' }}
                     />
+                    <label htmlFor="strategy">Strategy:</label>
+                    <select
+                        id="strategy"
+                        value={strategy}
+                        onChange={(e) => setStrategy(e.target.value as "Proportional" | "Pid" | "BangBang")}
+                        disabled={isPlotting}
+                    >
+                        <option value="Proportional">Proportional</option>
+                        <option value="Pid">PID</option>
+                        <option value="BangBang">Bang-Bang</option>
+                    </select>
+                    {strategy === "BangBang" && (
+                        <>
+                            <label htmlFor="bangBangCutoff">Cutoff:</label>
+                            <input
+                                type="number"
+                                id="bangBangCutoff"
+                                value={bangBangCutoff}
+                                step={1}
+                                min={0}
+                                onChange={(e) => setBangBangCutoff(parseFloat(e.target.value))}
+                                disabled={isPlotting}
+                            />
+                        </>
+                    )}
+                    {strategy === "Pid" && (["kp", "ki", "kd", "flow_rate"] as const).map((gain) => (
+                        <span key={gain}>
+                            <label htmlFor={gain}>{gain === "flow_rate" ? "Flow Rate:" : `${gain.toUpperCase()}:`}</label>
+                            <input
+                                type="number"
+                                id={gain}
+                                value={pidGains[gain]}
+                                step={gain === "flow_rate" ? 1 : 0.001}
+                                onChange={(e) => setPidGains({ ...pidGains, [gain]: parseFloat(e.target.value) })}
+                                disabled={isPlotting}
+                            />
+                        </span>
+                    ))}
                 </div>
             </section>
             <section className="controls">
@@ -463,6 +544,60 @@ function App() {
                     <button onClick={handleDispense} disabled={isPlotting} >Dispense</button>
                 </div>
             </section>
+            <section className="controls">
+                <div className="input-group">
+                    <label htmlFor="studyDispenses">Study Dispenses:</label>
+                    <input
+                        type="number"
+                        id="studyDispenses"
+                        value={studyDispenses}
+                        step={1}
+                        min={1}
+                        onChange={(e) => setStudyDispenses(parseInt(e.target.value))}
+                        disabled={isPlotting}
+                    />
+                    <label htmlFor="studyTolerance">Tolerance (+/-):</label>
+                    <input
+                        type="number"
+                        id="studyTolerance"
+                        value={studyTolerance}
+                        step={0.5}
+                        min={0}
+                        onChange={(e) => setStudyTolerance(parseFloat(e.target.value))}
+                        disabled={isPlotting}
+                    />
+                    <label htmlFor="studyPrompt">Prompt to Refill:</label>
+                    <input
+                        type="checkbox"
+                        id="studyPrompt"
+                        checked={studyPrompt}
+                        onChange={(e) => setStudyPrompt(e.target.checked)}
+                        disabled={isPlotting}
+                    />
+                    {!studyPrompt && (
+                        <>
+                            <label htmlFor="studyPause">Pause (s):</label>
+                            <input
+                                type="number"
+                                id="studyPause"
+                                value={studyPause}
+                                step={1}
+                                min={0}
+                                onChange={(e) => setStudyPause(parseInt(e.target.value))}
+                                disabled={isPlotting}
+                            />
+                        </>
+                    )}
+                </div>
+                <div className="button-grid">
+                    <button onClick={handleStudy} disabled={isPlotting}>Run Study</button>
+                    <button onClick={async () => {
+                        setAwaitingRefill(false);
+                        await invoke("resume_study");
+                    }} disabled={!awaitingRefill}>Resume</button>
+                    <button onClick={() => invoke("cancel_job", { id: currentJob.current }).catch((error) => updateStatus(String(error)))} disabled={!isPlotting}>Cancel</button>
+                </div>
+            </section>
             <section className="controls">
                 <div className="button-grid">
 